@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+
+use object::{Object, RelocationTarget};
+
+// An archive bundles objects into a library, of which the linker only uses
+// the members that define a label the program needs. It's written to a
+// `.qa` file as text:
+//
+//     qarch 1
+//     member NAME COUNT
+//
+// Each `member` is followed by the COUNT lines of its object, as written by
+// `Object::to_text`. Members are named after the file the object was read
+// from, without its directory.
+pub const VERSION: u32 = 1;
+
+pub struct Archive {
+    pub members: Vec<(String, Object)>
+}
+
+impl Archive {
+    pub fn to_text(&self) -> String {
+        let mut output = format!("qarch {}\n", VERSION);
+        for &(ref name, ref object) in &self.members {
+            let text = object.to_text();
+            output.push_str(&format!("member {} {}\n", name, text.lines().count()));
+            output.push_str(&text);
+        }
+        output
+    }
+
+    // Reads an archive written by `to_text`. Errors are prefixed with the
+    // line number they were found on.
+    pub fn parse(text: &str) -> Result<Archive, String> {
+        let lines: Vec<_> = text.lines().collect();
+        match lines.first() {
+            Some(line) if line.trim() == format!("qarch {}", VERSION) => (),
+            Some(line) if line.starts_with("qarch ") =>
+                return Err(format!("1: unsupported archive version `{}`, expected {}", line[6..].trim(), VERSION)),
+            _ => return Err("1: not a quasm archive: missing `qarch` header".to_string())
+        }
+        let mut members = Vec::new();
+        let mut index = 1;
+        while index < lines.len() {
+            let line = lines[index].trim();
+            index += 1;
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<_> = line.split(' ').collect();
+            let (name, count) = match &fields[..] {
+                ["member", name, count] => match count.parse::<usize>() {
+                    Ok(count) => (name.to_string(), count),
+                    Err(_) => return Err(format!("{}: `{}` is not a number", index, count))
+                },
+                ["member", ..] => return Err(format!("{}: wrong number of fields for `member`", index)),
+                _ => return Err(format!("{}: expected a `member`", index))
+            };
+            if index + count > lines.len() {
+                return Err(format!("{}: expected {} more lines in `{}`", lines.len(), index + count - lines.len(), name));
+            }
+            let object = try!(Object::parse(&lines[index..index + count].join("\n"))
+                .map_err(|message| archive_error(&message, index, &name)));
+            members.push((name, object));
+            index += count;
+        }
+        Ok(Archive { members: members })
+    }
+}
+
+// An error in a member, which is prefixed with the line number in the member,
+// moved to the line number in the archive and given the member's name.
+fn archive_error(message: &str, offset: usize, name: &str) -> String {
+    match message.find(": ").and_then(|end| message[..end].parse::<usize>().ok().map(|line| (line, &message[end + 2..]))) {
+        Some((line, message)) => format!("{}: in `{}`: {}", offset + line, name, message),
+        None => format!("{}: in `{}`: {}", offset, name, message)
+    }
+}
+
+// The objects to link: each of `objects`, and then each archive member that
+// defines a label used but not defined by those already chosen, named as in
+// `lib.qa(member.qo)`. Archives are searched in order, and repeatedly, so a
+// member may use labels defined by a member of the same or an earlier
+// archive.
+pub fn select_members(objects: Vec<(String, Object)>, archives: Vec<(String, Archive)>) -> Vec<(String, Object)> {
+    let mut selected = objects;
+    let mut remaining: Vec<(String, Object)> = archives.into_iter()
+        .flat_map(|(archive_name, archive)| archive.members.into_iter()
+            .map(move |(name, object)| (format!("{}({})", archive_name, name), object)))
+        .collect();
+    loop {
+        let defined: HashSet<&str> = selected.iter()
+            .flat_map(|&(_, ref object)| object.symbols.iter().map(|&(ref name, _, _)| name.as_str()))
+            .collect();
+        let undefined: HashSet<String> = selected.iter()
+            .flat_map(|&(_, ref object)| object.relocations.iter())
+            .filter_map(|relocation| match relocation.target {
+                RelocationTarget::Symbol(ref name) if !defined.contains(name.as_str()) => Some(name.clone()),
+                _ => None
+            })
+            .collect();
+        let position = remaining.iter().position(|&(_, ref object)| {
+            object.symbols.iter().any(|&(ref name, _, _)| undefined.contains(name))
+        });
+        match position {
+            Some(position) => selected.push(remaining.remove(position)),
+            None => return selected
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use isa::{InstructionSet, Operand};
+use parser::Section;
+use Program;
+
+// How control leaves a block: by a branch being taken, by running on to the
+// next block, or by a call, which returns to the next block.
+#[derive(Clone, Copy, PartialEq)]
+enum Edge {
+    Taken,
+    Fallthrough,
+    Call
+}
+
+// A basic block: a run of instructions that's only entered at its first and
+// only left after its last.
+struct Block {
+    start: u32,
+    // The address, opcode and argument of each instruction, with the address
+    // a label argument branches to.
+    instructions: Vec<(u32, String, i64, Option<u32>)>,
+    edges: Vec<(u32, Edge)>
+}
+
+// The program's control-flow graph in Graphviz's `dot` language, with a node
+// for each basic block listing its instructions, and an edge for each way of
+// leaving it. Blocks start at labels in `.text`, at branch targets, and after
+// any instruction that branches, calls, returns or halts.
+pub fn dot(program: &Program, instruction_set: &InstructionSet) -> String {
+    let blocks = blocks(program, instruction_set);
+    let labels: BTreeMap<u32, &str> = program.labels.iter()
+        .filter(|label| label.section == Section::Text)
+        .map(|label| (label.address, label.name.as_str()))
+        .collect();
+    let mut output = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+    for block in &blocks {
+        let mut text = String::new();
+        if let Some(name) = labels.get(&block.start) {
+            text.push_str(&format!("{}:\\l", escape(name)));
+        }
+        for &(address, ref opcode, argument, target) in &block.instructions {
+            let argument = match (target, instruction_set.opcode(opcode).map(|opcode| opcode.operand)) {
+                (Some(target), _) => match labels.get(&target) {
+                    Some(name) => format!(" :{}", escape(name)),
+                    None => format!(" {:04X}", target)
+                },
+                (None, Some(Operand::None)) => String::new(),
+                (None, _) => format!(" {}", argument)
+            };
+            text.push_str(&format!("{:04X}  {}{}\\l", address, escape(opcode), argument));
+        }
+        let style = if program.entry.unwrap_or(program.origin) == block.start { ", penwidth=2" } else { "" };
+        output.push_str(&format!("    b{:04X} [label=\"{}\"{}];\n", block.start, text, style));
+    }
+    for block in &blocks {
+        for &(target, edge) in &block.edges {
+            let attributes = match edge {
+                Edge::Taken => "label=\"taken\"",
+                Edge::Fallthrough => "label=\"fallthrough\", style=dashed",
+                Edge::Call => "label=\"call\", style=dotted"
+            };
+            output.push_str(&format!("    b{:04X} -> b{:04X} [{}];\n", block.start, target, attributes));
+        }
+    }
+    output.push_str("}\n");
+    output
+}
+
+fn blocks(program: &Program, instruction_set: &InstructionSet) -> Vec<Block> {
+    let instructions: BTreeMap<u32, (String, i64, Option<u32>)> = program.words.iter()
+        .filter_map(|word| word.instruction.as_ref().map(|&(ref opcode, argument)| {
+            let target = match instruction_set.opcode(opcode).map(|opcode| opcode.operand) {
+                Some(Operand::Label) => Some((word.address as i64 + 1 + argument) as u32),
+                _ => None
+            };
+            (word.address, (opcode.clone(), argument, target))
+        }))
+        .collect();
+    let mut leaders: BTreeSet<u32> = program.labels.iter()
+        .filter(|label| label.section == Section::Text)
+        .map(|label| label.address)
+        .chain(program.entry)
+        .chain(instructions.keys().next().cloned())
+        .collect();
+    for (&address, &(ref opcode, _, target)) in &instructions {
+        leaders.extend(target);
+        if target.is_some() || is_terminator(opcode) {
+            leaders.insert(address + 1);
+        }
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for (&address, &(ref opcode, argument, target)) in &instructions {
+        let continues = blocks.last().map_or(false, |block| {
+            let &(last, _, _, _) = block.instructions.last().unwrap();
+            last + 1 == address && !leaders.contains(&address)
+        });
+        if !continues {
+            blocks.push(Block { start: address, instructions: Vec::new(), edges: Vec::new() });
+        }
+        blocks.last_mut().unwrap().instructions.push((address, opcode.clone(), argument, target));
+    }
+    for block in &mut blocks {
+        let (address, opcode, target) = match block.instructions.last() {
+            Some(&(address, ref opcode, _, target)) => (address, opcode.clone(), target),
+            None => continue
+        };
+        if let Some(target) = target.filter(|target| instructions.contains_key(target)) {
+            block.edges.push((target, if opcode == "call" { Edge::Call } else { Edge::Taken }));
+        }
+        if !is_terminator(&opcode) && instructions.contains_key(&(address + 1)) {
+            block.edges.push((address + 1, Edge::Fallthrough));
+        }
+    }
+    blocks
+}
+
+fn is_terminator(opcode: &str) -> bool {
+    ["halt", "jmp", "ret"].contains(&opcode)
+}
+
+// Escapes text for a `dot` string.
+fn escape(text: &str) -> String {
+    text.replace("\\", "\\\\").replace("\"", "\\\"")
+}
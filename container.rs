@@ -0,0 +1,193 @@
+use std::error;
+use std::fmt;
+
+use encoder::{self, Endian};
+use encoding::Profile;
+
+// Assembled programs are written after a header that lets loaders check what
+// they've been given: the magic bytes `QASM`, then the format version and
+// flags as 16-bit integers and the number of words that follow as a 32-bit
+// integer, all little-endian. If the `ENTRY` flag is set, the address to start
+// running at follows as another 32-bit integer, and if the `CHECKSUM` flag is
+// set, the CRC-32 of the words follows that. The header is always
+// little-endian, but the words after it are big-endian if the
+// `BIG_ENDIAN` flag is set, and 64-bit if the `WORD64` flag is set.
+pub const MAGIC: &'static [u8] = b"QASM";
+pub const VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 12;
+
+pub const ENTRY: u16 = 1;
+pub const BIG_ENDIAN: u16 = 2;
+pub const WORD64: u16 = 4;
+pub const CHECKSUM: u16 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub flags: u16,
+    pub word_count: u32,
+    pub entry: Option<u32>,
+    pub checksum: Option<u32>
+}
+
+#[derive(Debug)]
+pub enum ContainerError {
+    BadMagic,
+    UnsupportedVersion { version: u16 },
+    Truncated { expected: usize, found: usize },
+    ChecksumMismatch { expected: u32, found: u32 }
+}
+
+impl Header {
+    pub fn new(word_count: u32, entry: Option<u32>, endian: Endian, profile: Profile) -> Header {
+        let mut flags = 0;
+        if entry.is_some() {
+            flags |= ENTRY;
+        }
+        if endian == Endian::Big {
+            flags |= BIG_ENDIAN;
+        }
+        if profile == Profile::Word64 {
+            flags |= WORD64;
+        }
+        Header { version: VERSION, flags: flags, word_count: word_count, entry: entry, checksum: None }
+    }
+
+    pub fn endian(&self) -> Endian {
+        if self.flags & BIG_ENDIAN != 0 { Endian::Big } else { Endian::Little }
+    }
+
+    pub fn profile(&self) -> Profile {
+        if self.flags & WORD64 != 0 { Profile::Word64 } else { Profile::Word32 }
+    }
+
+    // Records the checksum of `words`, which are the bytes after the header.
+    pub fn set_checksum(&mut self, words: &[u8]) {
+        self.flags |= CHECKSUM;
+        self.checksum = Some(crc32(words));
+    }
+
+    pub fn size(&self) -> usize {
+        let mut size = HEADER_SIZE;
+        if self.flags & ENTRY != 0 {
+            size += 4;
+        }
+        if self.flags & CHECKSUM != 0 {
+            size += 4;
+        }
+        size
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.flags.to_le_bytes());
+        bytes.extend_from_slice(&self.word_count.to_le_bytes());
+        if self.flags & ENTRY != 0 {
+            bytes.extend_from_slice(&self.entry.unwrap_or(0).to_le_bytes());
+        }
+        if self.flags & CHECKSUM != 0 {
+            bytes.extend_from_slice(&self.checksum.unwrap_or(0).to_le_bytes());
+        }
+        bytes
+    }
+
+    // Checks the header at the start of `bytes`, returning it along with the
+    // words it describes. Anything after those words is ignored. The checksum
+    // isn't checked, so that a program with a bad one can still be examined;
+    // see `verify_checksum`.
+    pub fn read(bytes: &[u8]) -> Result<(Header, &[u8]), ContainerError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(ContainerError::BadMagic);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(ContainerError::Truncated { expected: HEADER_SIZE, found: bytes.len() });
+        }
+        let mut header = Header {
+            version: u16::from_le_bytes([bytes[4], bytes[5]]),
+            flags: u16::from_le_bytes([bytes[6], bytes[7]]),
+            word_count: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            entry: None,
+            checksum: None
+        };
+        if header.version != VERSION {
+            return Err(ContainerError::UnsupportedVersion { version: header.version });
+        }
+        let header_size = header.size();
+        let size = header_size + header.word_count as usize * header.profile().encoding().word_size();
+        if bytes.len() < size {
+            return Err(ContainerError::Truncated { expected: size, found: bytes.len() });
+        }
+        let mut offset = HEADER_SIZE;
+        if header.flags & ENTRY != 0 {
+            header.entry = Some(read_u32(bytes, offset));
+            offset += 4;
+        }
+        if header.flags & CHECKSUM != 0 {
+            header.checksum = Some(read_u32(bytes, offset));
+        }
+        Ok((header, &bytes[header_size..size]))
+    }
+
+    // Checks that `words`, the bytes after the header, match the recorded
+    // checksum, if there is one.
+    pub fn verify_checksum(&self, words: &[u8]) -> Result<(), ContainerError> {
+        match self.checksum {
+            Some(expected) if crc32(words) != expected =>
+                Err(ContainerError::ChecksumMismatch { expected: expected, found: crc32(words) }),
+            _ => Ok(())
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+// The CRC-32 used by zlib and PNG.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Reads a container, decoding its words in the byte order it records.
+pub fn read_words(bytes: &[u8]) -> Result<(Header, Vec<i64>), ContainerError> {
+    let (header, bytes) = try!(Header::read(bytes));
+    let words = encoder::from_bytes(bytes, header.profile().encoding().word_size(), header.endian());
+    Ok((header, words))
+}
+
+// Puts a header before the words in `bytes`, which are in `endian` order.
+pub fn wrap(bytes: &[u8], entry: Option<u32>, endian: Endian, profile: Profile, checksum: bool) -> Vec<u8> {
+    let word_count = bytes.len() / profile.encoding().word_size();
+    let mut header = Header::new(word_count as u32, entry, endian, profile);
+    if checksum {
+        header.set_checksum(bytes);
+    }
+    let mut container = header.to_bytes();
+    container.extend_from_slice(bytes);
+    container
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ContainerError::BadMagic =>
+                write!(formatter, "not a quasm program: missing `QASM` header"),
+            &ContainerError::UnsupportedVersion { version } =>
+                write!(formatter, "unsupported program format version {}, expected {}", version, VERSION),
+            &ContainerError::Truncated { expected, found } =>
+                write!(formatter, "program is truncated: expected {} bytes, found {}", expected, found),
+            &ContainerError::ChecksumMismatch { expected, found } =>
+                write!(formatter, "checksum mismatch: header records {:08X}, words have {:08X}", expected, found)
+        }
+    }
+}
+
+impl error::Error for ContainerError {}
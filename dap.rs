@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Cursor, Write};
+use std::path::Path;
+
+use debug::DebugInfo;
+use debugger::{Debugger, Event};
+use json::{self, Json};
+use vm::Stop;
+
+// A program only ever has one thread.
+const THREAD_ID: i64 = 1;
+
+// The `variablesReference` of the scope holding the operand stack.
+const STACK_REFERENCE: i64 = 1;
+
+// Serves the Debug Adapter Protocol over `input` and `output`, which are
+// usually stdin and stdout, until the client disconnects. `launch` and
+// `attach` both load the program, as the machine runs inside the adapter, by
+// giving their arguments to `load`. Besides what `load` reads, they take
+// `stopOnEntry`, and `input`, the text the program reads, since stdin carries
+// the protocol. What the program prints is sent as `output` events.
+//
+// Breakpoints are set by source line, using debug info to find the first
+// instruction at or after the line. Each frame has a scope named `stack`,
+// with the operand stack as variables, the top first. The program runs
+// until it stops before any request is read, so `pause` can't interrupt it.
+pub fn serve_dap(input: &mut dyn BufRead, output: &mut dyn Write, load: &mut dyn FnMut(&Json) -> Result<Debugger, String>) -> io::Result<()> {
+    let mut server = Server {
+        output: output,
+        sequence: 0,
+        debugger: None,
+        lines: HashMap::new(),
+        stop_on_entry: false,
+        program_input: Cursor::new(Vec::new())
+    };
+    while let Some(message) = try!(read_message(input)) {
+        let request = match json::parse(&message) {
+            Ok(request) => request,
+            Err(_) => continue
+        };
+        if !try!(server.handle(&request, load)) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+struct Server<'o> {
+    output: &'o mut dyn Write,
+    // The `seq` of the last message sent.
+    sequence: i64,
+    debugger: Option<Debugger>,
+    // The lines asked for breakpoints in each source file, which are set
+    // again once a program is loaded.
+    lines: HashMap<String, Vec<i64>>,
+    stop_on_entry: bool,
+    program_input: Cursor<Vec<u8>>
+}
+
+impl<'o> Server<'o> {
+    // Handles a request, and gives whether to carry on.
+    fn handle(&mut self, request: &Json, load: &mut dyn FnMut(&Json) -> Result<Debugger, String>) -> io::Result<bool> {
+        let command = request.get("command").as_str().unwrap_or("");
+        let arguments = request.get("arguments");
+        let loaded = self.debugger.is_some();
+        match command {
+            "initialize" => try!(self.respond(request, Json::object(vec![
+                ("supportsConfigurationDoneRequest", Json::Bool(true)),
+                ("supportsTerminateRequest", Json::Bool(true))
+            ]))),
+            "launch" | "attach" => match load(arguments) {
+                Ok(debugger) => {
+                    self.debugger = Some(debugger);
+                    self.stop_on_entry = arguments.get("stopOnEntry").as_bool().unwrap_or(false);
+                    self.program_input = Cursor::new(arguments.get("input").as_str().unwrap_or("").as_bytes().to_vec());
+                    let sources: Vec<String> = self.lines.keys().cloned().collect();
+                    for source in sources {
+                        self.set_breakpoints(&source);
+                    }
+                    try!(self.respond(request, Json::Null));
+                    // Breakpoints can only be found once there's a program.
+                    try!(self.event("initialized", Json::Null));
+                },
+                Err(message) => try!(self.fail(request, &message))
+            },
+            "setBreakpoints" => {
+                let source = arguments.get("source").get("path").as_str().unwrap_or("").to_string();
+                let lines = arguments.get("breakpoints").as_array().iter().filter_map(|breakpoint| breakpoint.get("line").as_i64()).collect();
+                self.lines.insert(source.clone(), lines);
+                let breakpoints = self.set_breakpoints(&source);
+                try!(self.respond(request, Json::object(vec![("breakpoints", Json::Array(breakpoints))])));
+            },
+            "setExceptionBreakpoints" | "setFunctionBreakpoints" =>
+                try!(self.respond(request, Json::object(vec![("breakpoints", Json::Array(Vec::new()))]))),
+            "configurationDone" if loaded => {
+                try!(self.respond(request, Json::Null));
+                if self.stop_on_entry {
+                    try!(self.stopped("entry", None));
+                } else {
+                    try!(self.run(|debugger, input, output| debugger.resume(input, output)));
+                }
+            },
+            "threads" => try!(self.respond(request, Json::object(vec![
+                ("threads", Json::Array(vec![Json::object(vec![("id", Json::Number(THREAD_ID as f64)), ("name", Json::string("main"))])]))
+            ]))),
+            "stackTrace" if loaded => {
+                let frames = self.frames();
+                let count = frames.len();
+                try!(self.respond(request, Json::object(vec![("stackFrames", Json::Array(frames)), ("totalFrames", Json::Number(count as f64))])));
+            },
+            "scopes" => try!(self.respond(request, Json::object(vec![
+                ("scopes", Json::Array(vec![Json::object(vec![
+                    ("name", Json::string("stack")),
+                    ("variablesReference", Json::Number(STACK_REFERENCE as f64)),
+                    ("expensive", Json::Bool(false))
+                ])]))
+            ]))),
+            "variables" if loaded => {
+                let variables = if arguments.get("variablesReference").as_i64() == Some(STACK_REFERENCE) {
+                    self.debugger.as_ref().unwrap().machine.stack.iter().rev().enumerate()
+                        .map(|(depth, value)| Json::object(vec![
+                            ("name", Json::String(format!("[{}]", depth))),
+                            ("value", Json::String(value.to_string())),
+                            ("variablesReference", Json::Number(0.0))
+                        ]))
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                try!(self.respond(request, Json::object(vec![("variables", Json::Array(variables))])));
+            },
+            "continue" if loaded => {
+                try!(self.respond(request, Json::object(vec![("allThreadsContinued", Json::Bool(true))])));
+                try!(self.run(|debugger, input, output| debugger.resume(input, output)));
+            },
+            "next" if loaded => {
+                try!(self.respond(request, Json::Null));
+                try!(self.run(|debugger, input, output| debugger.next(input, output)));
+            },
+            "stepIn" if loaded => {
+                try!(self.respond(request, Json::Null));
+                try!(self.run(|debugger, input, output| debugger.step(input, output)));
+            },
+            "stepOut" if loaded => {
+                try!(self.respond(request, Json::Null));
+                try!(self.run(|debugger, input, output| debugger.finish(input, output)));
+            },
+            "pause" => {
+                try!(self.respond(request, Json::Null));
+                try!(self.stopped("pause", None));
+            },
+            "disconnect" => {
+                try!(self.respond(request, Json::Null));
+                return Ok(false);
+            },
+            "terminate" => {
+                try!(self.respond(request, Json::Null));
+                try!(self.event("terminated", Json::Null));
+            },
+            "configurationDone" | "stackTrace" | "variables" | "continue" | "next" | "stepIn" | "stepOut" =>
+                try!(self.fail(request, "no program has been launched")),
+            _ => try!(self.fail(request, &format!("`{}` isn't supported", command)))
+        }
+        Ok(true)
+    }
+
+    // Sets the breakpoints asked for in `source` on the program, and gives
+    // them as the protocol does, with the line each was moved to.
+    fn set_breakpoints(&mut self, source: &str) -> Vec<Json> {
+        let lines = self.lines.get(source).cloned().unwrap_or(Vec::new());
+        let debugger = match self.debugger {
+            Some(ref mut debugger) => debugger,
+            None => return lines.iter().map(|&line| Json::object(vec![("verified", Json::Bool(false)), ("line", Json::Number(line as f64))])).collect()
+        };
+        let found: Vec<_> = lines.iter()
+            .map(|&line| debugger.debug_info.as_ref().and_then(|debug_info| line_address(debug_info, source, line)))
+            .collect();
+        let all: Vec<u32> = self.lines.iter()
+            .filter(|&(other, _)| other != source)
+            .flat_map(|(other, lines)| lines.iter().map(move |&line| (other, line)))
+            .filter_map(|(other, line)| debugger.debug_info.as_ref().and_then(|debug_info| line_address(debug_info, other, line)))
+            .map(|(address, _)| address)
+            .chain(found.iter().filter_map(|found| found.map(|(address, _)| address)))
+            .collect();
+        debugger.breakpoints = all.into_iter().collect();
+        lines.iter().zip(found)
+            .map(|(&line, found)| match found {
+                Some((_, line)) => Json::object(vec![("verified", Json::Bool(true)), ("line", Json::Number(line as f64))]),
+                None => Json::object(vec![
+                    ("verified", Json::Bool(false)),
+                    ("line", Json::Number(line as f64)),
+                    ("message", Json::string("no code at or after this line"))
+                ])
+            })
+            .collect()
+    }
+
+    // Runs the program as `action` does, sending what it printed, and then
+    // why it stopped.
+    fn run<F>(&mut self, action: F) -> io::Result<()> where F: FnOnce(&mut Debugger, &mut dyn BufRead, &mut dyn Write) -> Event {
+        let mut printed = Vec::new();
+        let event = action(self.debugger.as_mut().unwrap(), &mut self.program_input, &mut printed);
+        if !printed.is_empty() {
+            try!(self.output_event("stdout", &String::from_utf8_lossy(&printed)));
+        }
+        match event {
+            Event::Stepped => self.stopped("step", None),
+            Event::Breakpoint(_) => self.stopped("breakpoint", None),
+            Event::Trapped(trap) => {
+                let text = trap.to_string();
+                try!(self.output_event("stderr", &format!("{}\n", text)));
+                self.stopped("exception", Some(&text))
+            },
+            Event::Finished(stop) => {
+                let (message, status) = match stop {
+                    Stop::Halted(status) => (format!("halted with status {}\n", status), status),
+                    _ => {
+                        let values: Vec<_> = self.debugger.as_ref().unwrap().machine.stack.iter().map(|value| value.to_string()).collect();
+                        let how = if stop == Stop::Ended { "ended" } else { "returned" };
+                        (format!("{} with stack [{}]\n", how, values.join(", ")), 0)
+                    }
+                };
+                try!(self.output_event("console", &message));
+                try!(self.event("exited", Json::object(vec![("exitCode", Json::Number(status as f64))])));
+                self.event("terminated", Json::Null)
+            }
+        }
+    }
+
+    // The current instruction, then each call that hasn't returned, most
+    // recent first.
+    fn frames(&self) -> Vec<Json> {
+        let debugger = self.debugger.as_ref().unwrap();
+        let machine = &debugger.machine;
+        let addresses = Some(machine.pc).into_iter().chain(machine.returns.iter().rev().map(|&address| address - 1));
+        addresses.enumerate()
+            .map(|(id, address)| {
+                let label = debugger.debug_info.as_ref().and_then(|debug_info| debug_info.label(address));
+                let name = match label {
+                    Some(&(ref name, label_address)) if label_address == address => name.clone(),
+                    Some(&(ref name, label_address)) => format!("{}+{}", name, address - label_address),
+                    None => format!("{:04X}", address)
+                };
+                let mut frame = vec![
+                    ("id", Json::Number(id as f64)),
+                    ("name", Json::String(name)),
+                    ("instructionPointerReference", Json::String(format!("{:04X}", address)))
+                ];
+                match debugger.debug_info.as_ref().and_then(|debug_info| debug_info.location(address)) {
+                    Some(location) => {
+                        if let Some(ref file) = location.file {
+                            let path = Path::new(file).canonicalize().map(|path| path.to_string_lossy().into_owned()).unwrap_or(file.clone());
+                            let name = Path::new(file).file_name().map_or(file.clone(), |name| name.to_string_lossy().into_owned());
+                            frame.push(("source", Json::object(vec![("name", Json::String(name)), ("path", Json::String(path))])));
+                        }
+                        frame.push(("line", Json::Number(location.line_number as f64)));
+                        frame.push(("column", Json::Number(location.column as f64)));
+                    },
+                    None => {
+                        frame.push(("line", Json::Number(0.0)));
+                        frame.push(("column", Json::Number(0.0)));
+                    }
+                }
+                Json::object(frame)
+            })
+            .collect()
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<&str>) -> io::Result<()> {
+        let mut body = vec![
+            ("reason", Json::string(reason)),
+            ("threadId", Json::Number(THREAD_ID as f64)),
+            ("allThreadsStopped", Json::Bool(true))
+        ];
+        if let Some(text) = text {
+            body.push(("text", Json::string(text)));
+        }
+        self.event("stopped", Json::object(body))
+    }
+
+    fn output_event(&mut self, category: &str, text: &str) -> io::Result<()> {
+        self.event("output", Json::object(vec![("category", Json::string(category)), ("output", Json::string(text))]))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::string("response")),
+            ("request_seq", request.get("seq").clone()),
+            ("success", Json::Bool(true)),
+            ("command", request.get("command").clone()),
+            ("body", body)
+        ])
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::string("response")),
+            ("request_seq", request.get("seq").clone()),
+            ("success", Json::Bool(false)),
+            ("command", request.get("command").clone()),
+            ("message", Json::string(message))
+        ])
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![("type", Json::string("event")), ("event", Json::string(event)), ("body", body)])
+    }
+
+    fn send(&mut self, members: Vec<(&str, Json)>) -> io::Result<()> {
+        self.sequence += 1;
+        let mut message = vec![("seq", Json::Number(self.sequence as f64))];
+        message.extend(members);
+        let text = Json::object(message).to_string();
+        try!(write!(self.output, "Content-Length: {}\r\n\r\n{}", text.len(), text));
+        self.output.flush()
+    }
+}
+
+// The first instruction assembled from `line` of `source` or the closest
+// line after it, and that line.
+fn line_address(debug_info: &DebugInfo, source: &str, line: i64) -> Option<(u32, i64)> {
+    debug_info.locations.iter()
+        .filter(|&&(_, ref location)| location.file.as_ref().map_or(false, |file| same_file(file, source)))
+        .filter(|&&(_, ref location)| location.line_number as i64 >= line)
+        .min_by_key(|&&(address, ref location)| (location.line_number, address))
+        .map(|&(address, ref location)| (address, location.line_number as i64))
+}
+
+fn same_file(first: &str, second: &str) -> bool {
+    match (Path::new(first).canonicalize(), Path::new(second).canonicalize()) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => first == second
+    }
+}
+
+// Reads a message framed with a `Content-Length` header, or gives `None` at
+// the end of the input.
+fn read_message(input: &mut dyn BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if try!(input.read_line(&mut line)) == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        let lower = line.to_lowercase();
+        if lower.starts_with("content-length:") {
+            length = line["content-length:".len()..].trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap()];
+    try!(input.read_exact(&mut body));
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
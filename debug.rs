@@ -0,0 +1,176 @@
+use error::Location;
+use Program;
+
+// Debug info maps each address back to the source it was assembled from, so
+// the VM and debugger can report source locations. It's written to a `.qdbg`
+// file alongside the program as text, one record per line:
+//
+//     qdbg 1
+//     file INDEX PATH
+//     word ADDRESS FILE LINE COLUMN
+//     label ADDRESS NAME
+//     section ADDRESS SIZE NAME
+//     function ADDRESS SIZE NAME
+//
+// Addresses are hexadecimal and everything else decimal. `FILE` is the index
+// given by a `file` record, or `-` for source that isn't backed by a file.
+// Words, labels and functions are in address order, and sections in output
+// order.
+pub const VERSION: u32 = 1;
+
+pub struct DebugInfo {
+    // The location of each word, in address order.
+    pub locations: Vec<(u32, Location)>,
+    // Every named label, in address order.
+    pub labels: Vec<(String, u32)>,
+    // The name, address and size in words of each section that has any
+    // words, in output order.
+    pub sections: Vec<(String, u32, u32)>,
+    // The name, address and size in words of each `.func`, in address order.
+    pub functions: Vec<(String, u32, u32)>
+}
+
+impl DebugInfo {
+    // Words are given the location of the first token on their line.
+    pub fn new(program: &Program) -> DebugInfo {
+        let locations = program.words.iter()
+            .map(|word| {
+                let indent = word.source.len() - word.source.trim_start().len();
+                let location = Location { column: word.location.column + indent, ..word.location.clone() };
+                (word.address, location)
+            })
+            .collect();
+        let labels = program.labels.iter().map(|label| (label.name.clone(), label.address)).collect();
+        let sections = program.sections.iter().map(|section| (section.name.clone(), section.address, section.size)).collect();
+        let mut functions: Vec<_> = program.functions.iter().map(|function| (function.name.clone(), function.address, function.size)).collect();
+        functions.sort_by_key(|&(_, address, _)| address);
+        DebugInfo { locations: locations, labels: labels, sections: sections, functions: functions }
+    }
+
+    // The address of the first word, or zero if there are none.
+    pub fn origin(&self) -> u32 {
+        self.locations.first().map_or(0, |&(address, _)| address)
+    }
+
+    // The location of the word at `address`.
+    pub fn location(&self, address: u32) -> Option<&Location> {
+        self.locations.binary_search_by_key(&address, |&(word_address, _)| word_address)
+            .ok()
+            .map(|index| &self.locations[index].1)
+    }
+
+    // The closest label at or before `address`, for naming the function an
+    // address is in.
+    pub fn label(&self, address: u32) -> Option<&(String, u32)> {
+        self.labels.iter().take_while(|&&(_, label_address)| label_address <= address).last()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut files: Vec<&str> = Vec::new();
+        let mut output = format!("qdbg {}\n", VERSION);
+        for &(_, ref location) in &self.locations {
+            if let Some(ref file) = location.file {
+                if !files.contains(&file.as_str()) {
+                    output.push_str(&format!("file {} {}\n", files.len(), file));
+                    files.push(file);
+                }
+            }
+        }
+        for &(address, ref location) in &self.locations {
+            let file = match location.file {
+                Some(ref file) => files.iter().position(|name| name == file).unwrap().to_string(),
+                None => "-".to_string()
+            };
+            output.push_str(&format!("word {:08X} {} {} {}\n", address, file, location.line_number, location.column));
+        }
+        for &(ref name, address) in &self.labels {
+            output.push_str(&format!("label {:08X} {}\n", address, name));
+        }
+        for &(ref name, address, size) in &self.sections {
+            output.push_str(&format!("section {:08X} {} {}\n", address, size, name));
+        }
+        for &(ref name, address, size) in &self.functions {
+            output.push_str(&format!("function {:08X} {} {}\n", address, size, name));
+        }
+        output
+    }
+
+    // Reads debug info written by `to_text`. Errors are prefixed with the line
+    // number they were found on.
+    pub fn parse(text: &str) -> Result<DebugInfo, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, line)) if line.trim() == format!("qdbg {}", VERSION) => (),
+            Some((_, line)) if line.starts_with("qdbg ") =>
+                return Err(format!("1: unsupported debug info version `{}`, expected {}", line[5..].trim(), VERSION)),
+            _ => return Err("1: not quasm debug info: missing `qdbg` header".to_string())
+        }
+        let mut files = Vec::new();
+        let mut debug_info = DebugInfo { locations: Vec::new(), labels: Vec::new(), sections: Vec::new(), functions: Vec::new() };
+        for (index, line) in lines {
+            try!(debug_info.parse_line(line, &mut files).map_err(|message| format!("{}: {}", index + 1, message)));
+        }
+        Ok(debug_info)
+    }
+
+    fn parse_line(&mut self, line: &str, files: &mut Vec<String>) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let mut fields = line.splitn(2, ' ');
+        let kind = fields.next().unwrap_or("");
+        let rest = fields.next().unwrap_or("").trim();
+        match kind {
+            "file" => {
+                let mut fields = rest.splitn(2, ' ');
+                let index = try!(parse_number(fields.next().unwrap_or("")));
+                if index != files.len() {
+                    return Err(format!("expected file {}, found file {}", files.len(), index));
+                }
+                files.push(fields.next().unwrap_or("").to_string());
+            },
+            "word" => {
+                let fields: Vec<_> = rest.split_whitespace().collect();
+                if fields.len() != 4 {
+                    return Err("expected `word ADDRESS FILE LINE COLUMN`".to_string());
+                }
+                let file = if fields[1] == "-" {
+                    None
+                } else {
+                    let index = try!(parse_number(fields[1]));
+                    Some(try!(files.get(index).cloned().ok_or(format!("file {} isn't defined", index))))
+                };
+                let location = Location { file: file, line_number: try!(parse_number(fields[2])), column: try!(parse_number(fields[3])) };
+                self.locations.push((try!(parse_address(fields[0])), location));
+            },
+            "label" => {
+                let mut fields = rest.splitn(2, ' ');
+                let address = try!(parse_address(fields.next().unwrap_or("")));
+                self.labels.push((fields.next().unwrap_or("").to_string(), address));
+            },
+            "section" | "function" => {
+                let fields: Vec<_> = rest.splitn(3, ' ').collect();
+                if fields.len() != 3 {
+                    return Err(format!("expected `{} ADDRESS SIZE NAME`", kind));
+                }
+                let region = (fields[2].to_string(), try!(parse_address(fields[0])), try!(parse_number(fields[1])) as u32);
+                if kind == "section" {
+                    self.sections.push(region);
+                } else {
+                    self.functions.push(region);
+                }
+            },
+            _ => return Err(format!("`{}` is not a debug info record", kind))
+        }
+        Ok(())
+    }
+}
+
+fn parse_address(text: &str) -> Result<u32, String> {
+    u32::from_str_radix(text, 16).map_err(|_| format!("`{}` is not a hexadecimal address", text))
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    text.parse().map_err(|_| format!("`{}` is not a number", text))
+}
@@ -0,0 +1,248 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
+
+use debug::DebugInfo;
+use vm::{Machine, Stop, Trap};
+
+// How many instructions `disas` shows unless it's told.
+const DISASSEMBLY_LENGTH: u32 = 8;
+
+const HELP: &'static str = "step, s [N]            run N instructions, or one
+next, n                run one instruction, or a whole call
+continue, c            run until a breakpoint or the program finishes
+break, b :LABEL|ADDR   stop before the instruction there, or list breakpoints
+delete, d :LABEL|ADDR  remove a breakpoint
+stack                  show the stack, top last
+disas [N]              disassemble N instructions from the current one
+quit, q                stop debugging";
+
+// Why the debugger stopped running the program.
+pub enum Event {
+    // A step or `next` finished, and the program can carry on.
+    Stepped,
+    Breakpoint(u32),
+    Finished(Stop),
+    Trapped(Trap)
+}
+
+// Runs a program under control, stopping at breakpoints, and describes where
+// it is with the source that debug info points to, if it has any.
+pub struct Debugger {
+    pub machine: Machine,
+    pub debug_info: Option<DebugInfo>,
+    pub breakpoints: BTreeSet<u32>,
+    // How the program finished, after which it can't be run any further.
+    pub finished: Option<Stop>,
+    // The lines of each file debug info refers to that could be read.
+    sources: HashMap<String, Vec<String>>
+}
+
+impl Debugger {
+    pub fn new(machine: Machine, debug_info: Option<DebugInfo>) -> Debugger {
+        let mut sources = HashMap::new();
+        for &(_, ref location) in debug_info.iter().flat_map(|debug_info| &debug_info.locations) {
+            if let Some(ref file) = location.file {
+                if !sources.contains_key(file) {
+                    let mut text = String::new();
+                    if File::open(file).and_then(|mut source| source.read_to_string(&mut text)).is_ok() {
+                        sources.insert(file.clone(), text.lines().map(|line| line.to_string()).collect());
+                    }
+                }
+            }
+        }
+        Debugger { machine: machine, debug_info: debug_info, breakpoints: BTreeSet::new(), finished: None, sources: sources }
+    }
+
+    // Runs one instruction.
+    pub fn step(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Event {
+        if let Some(stop) = self.finished {
+            return Event::Finished(stop);
+        }
+        match self.machine.step(input, output) {
+            Ok(Some(stop)) => {
+                self.finished = Some(stop);
+                Event::Finished(stop)
+            },
+            Ok(None) => Event::Stepped,
+            Err(trap) => Event::Trapped(trap)
+        }
+    }
+
+    // Runs one instruction, or if it's a `call`, runs until the call returns,
+    // unless it reaches a breakpoint first.
+    pub fn next(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Event {
+        if self.opcode(self.machine.pc) != Some("call") {
+            return self.step(input, output);
+        }
+        let (returns, depth) = (self.machine.pc + 1, self.machine.returns.len());
+        loop {
+            match self.step(input, output) {
+                Event::Stepped if self.machine.pc == returns && self.machine.returns.len() == depth => return Event::Stepped,
+                Event::Stepped if self.breakpoints.contains(&self.machine.pc) => return Event::Breakpoint(self.machine.pc),
+                Event::Stepped => (),
+                event => return event
+            }
+        }
+    }
+
+    // Runs until the function the program is in returns, unless it reaches a
+    // breakpoint first. Outside any function, that's until it finishes.
+    pub fn finish(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Event {
+        let depth = self.machine.returns.len();
+        loop {
+            match self.step(input, output) {
+                Event::Stepped if depth > 0 && self.machine.returns.len() < depth => return Event::Stepped,
+                Event::Stepped if self.breakpoints.contains(&self.machine.pc) => return Event::Breakpoint(self.machine.pc),
+                Event::Stepped => (),
+                event => return event
+            }
+        }
+    }
+
+    // Runs until the program reaches a breakpoint, other than one it's
+    // stopped at, or finishes.
+    pub fn resume(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Event {
+        loop {
+            match self.step(input, output) {
+                Event::Stepped if self.breakpoints.contains(&self.machine.pc) => return Event::Breakpoint(self.machine.pc),
+                Event::Stepped => (),
+                event => return event
+            }
+        }
+    }
+
+    // The address given as `:label`, which needs debug info, or as a
+    // hexadecimal address, optionally prefixed with `0x`.
+    pub fn address(&self, text: &str) -> Result<u32, String> {
+        if text.starts_with(":") {
+            let debug_info = try!(self.debug_info.as_ref().ok_or("labels can only be used with debug info".to_string()));
+            debug_info.labels.iter()
+                .find(|&&(ref name, _)| name == &text[1..])
+                .map(|&(_, address)| address)
+                .ok_or(format!("no label named `{}`", &text[1..]))
+        } else {
+            let digits = if text.starts_with("0x") || text.starts_with("0X") { &text[2..] } else { text };
+            u32::from_str_radix(digits, 16).map_err(|_| format!("`{}` is not a label or an address", text))
+        }
+    }
+
+    // The instruction at `address`, with the line it was assembled from if
+    // there's debug info, as in `0007  jle 000C          ; prog.qs:9: jle :done`.
+    pub fn describe(&self, address: u32) -> String {
+        let instruction = format!("{:04X}  {}", address, self.machine.instruction_text(address));
+        let location = self.debug_info.as_ref().and_then(|debug_info| debug_info.location(address));
+        match location {
+            Some(location) => {
+                let file = location.file.as_ref().map_or("<input>", |file| file.as_str());
+                let source = location.file.as_ref()
+                    .and_then(|file| self.sources.get(file))
+                    .and_then(|lines| lines.get(location.line_number - 1))
+                    .map_or("", |line| line.trim());
+                format!("{:<22} ; {}:{}: {}", instruction, file, location.line_number, source)
+            },
+            None => instruction
+        }
+    }
+
+    // `count` instructions from the current one, which is marked with `=>`,
+    // with breakpoints marked with `*` and labels given on the lines before
+    // their instructions.
+    pub fn disassemble(&self, count: u32) -> String {
+        let mut output = String::new();
+        for address in self.machine.pc..self.machine.pc.saturating_add(count).min(self.machine.end) {
+            for &(ref name, _) in self.debug_info.iter().flat_map(|debug_info| &debug_info.labels).filter(|&&(_, label)| label == address) {
+                output.push_str(&format!("   :{}\n", name));
+            }
+            let marker = if address == self.machine.pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&address) { "*" } else { " " };
+            output.push_str(&format!("{}{}{}\n", marker, breakpoint, self.describe(address)));
+        }
+        output
+    }
+
+    // Runs a command, as `help` describes, and gives what it prints, or
+    // `None` if it's `quit`.
+    pub fn command(&mut self, line: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> Option<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let argument = words.get(1).cloned();
+        let report = match words.first().cloned().unwrap_or("") {
+            "step" | "s" => match argument.map_or(Ok(1), |count| count.parse::<u32>()) {
+                Ok(count) => {
+                    let mut event = Event::Stepped;
+                    for _ in 0..count {
+                        event = self.step(input, output);
+                        match event {
+                            Event::Stepped => (),
+                            _ => break
+                        }
+                    }
+                    self.report(event)
+                },
+                Err(_) => "step takes a number of instructions".to_string()
+            },
+            "next" | "n" => {
+                let event = self.next(input, output);
+                self.report(event)
+            },
+            "continue" | "c" => {
+                let event = self.resume(input, output);
+                self.report(event)
+            },
+            "break" | "b" => match argument.map(|argument| self.address(argument)) {
+                Some(Ok(address)) => {
+                    self.breakpoints.insert(address);
+                    format!("breakpoint at {}", self.describe(address))
+                },
+                Some(Err(message)) => message,
+                None if self.breakpoints.is_empty() => "no breakpoints".to_string(),
+                None => self.breakpoints.iter().map(|&address| self.describe(address)).collect::<Vec<_>>().join("\n")
+            },
+            "delete" | "d" => match argument.map(|argument| self.address(argument)) {
+                Some(Ok(address)) if self.breakpoints.remove(&address) => format!("deleted breakpoint at {:04X}", address),
+                Some(Ok(address)) => format!("no breakpoint at {:04X}", address),
+                Some(Err(message)) => message,
+                None => "delete takes a label or an address".to_string()
+            },
+            "stack" => {
+                let values: Vec<_> = self.machine.stack.iter().map(|value| value.to_string()).collect();
+                format!("[{}]", values.join(", "))
+            },
+            "disas" => match argument.map_or(Ok(DISASSEMBLY_LENGTH), |count| count.parse()) {
+                Ok(count) => self.disassemble(count).trim_end().to_string(),
+                Err(_) => "disas takes a number of instructions".to_string()
+            },
+            "help" | "h" => HELP.to_string(),
+            "quit" | "q" => return None,
+            "" => String::new(),
+            command => format!("unknown command `{}`, try `help`", command)
+        };
+        Some(report)
+    }
+
+    fn report(&self, event: Event) -> String {
+        match event {
+            Event::Stepped => self.describe(self.machine.pc),
+            Event::Breakpoint(address) => format!("breakpoint\n{}", self.describe(address)),
+            Event::Finished(Stop::Halted(status)) => format!("halted with status {}", status),
+            Event::Finished(Stop::Returned) => {
+                let values: Vec<_> = self.machine.stack.iter().map(|value| value.to_string()).collect();
+                format!("returned with stack [{}]", values.join(", "))
+            },
+            Event::Finished(Stop::Ended) => {
+                let values: Vec<_> = self.machine.stack.iter().map(|value| value.to_string()).collect();
+                format!("ended with stack [{}]", values.join(", "))
+            },
+            Event::Finished(_) => "stopped".to_string(),
+            Event::Trapped(trap) => format!("{}\n{}", trap, self.describe(trap.address))
+        }
+    }
+
+    // The name of the opcode at `address`, if it's an instruction.
+    fn opcode(&self, address: u32) -> Option<&str> {
+        self.machine.memory.get(address as usize)
+            .map(|&word| self.machine.profile.encoding().decode(word).0)
+            .and_then(|code| self.machine.instruction_set.opcode_with_code(code))
+            .map(|opcode| opcode.name.as_str())
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+use disasm::{self, Bytecode, Decoded};
+use isa::InstructionSet;
+use writer;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    // An instruction replaced by a different one at the same point.
+    Changed,
+    // A branch whose target moved along with the instruction it targets.
+    Shifted
+}
+
+// A difference between two programs. Addresses are those of the word in the
+// old and new program, and the text is what it disassembles to, where it's
+// in that program.
+pub struct Change {
+    pub kind: ChangeKind,
+    pub old_address: Option<u32>,
+    pub new_address: Option<u32>,
+    pub old: Option<String>,
+    pub new: Option<String>
+}
+
+// Compares two programs instruction by instruction. Words are aligned by
+// what they disassemble to, with branches aligned by opcode alone, so that a
+// branch is only reported as changed if it targets a different instruction.
+// Both programs are taken to start at address zero.
+pub fn diff(old: &Bytecode, new: &Bytecode, instruction_set: &InstructionSet) -> Vec<Change> {
+    let old_words = Side::new(old, instruction_set);
+    let new_words = Side::new(new, instruction_set);
+    let pairs = align(&old_words.keys, &new_words.keys);
+    let correspondence: HashMap<i64, i64> = pairs.iter()
+        .filter_map(|&(old_index, new_index)| match (old_index, new_index) {
+            (Some(old_index), Some(new_index)) => Some((old_index as i64, new_index as i64)),
+            _ => None
+        })
+        .collect();
+    let mut changes = Vec::new();
+    for (old_index, new_index) in pairs {
+        let kind = match (old_index, new_index) {
+            (Some(_), None) => ChangeKind::Removed,
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(old_index), Some(new_index)) if old_words.keys[old_index] != new_words.keys[new_index] => ChangeKind::Changed,
+            (Some(old_index), Some(new_index)) => match (&old_words.instructions[old_index], &new_words.instructions[new_index]) {
+                (&Decoded::Branch(_, old_target), &Decoded::Branch(_, new_target)) => {
+                    let old_offset = old_target - (old_index as i64 + 1);
+                    let new_offset = new_target - (new_index as i64 + 1);
+                    match correspondence.get(&old_target) {
+                        Some(&target) if target == new_target && old_offset == new_offset => continue,
+                        Some(&target) if target == new_target => ChangeKind::Shifted,
+                        None if old_offset == new_offset => continue,
+                        _ => ChangeKind::Changed
+                    }
+                },
+                _ => continue
+            },
+            (None, None) => continue
+        };
+        changes.push(Change {
+            kind: kind,
+            old_address: old_index.map(|index| index as u32),
+            new_address: new_index.map(|index| index as u32),
+            old: old_index.map(|index| old_words.text(index)),
+            new: new_index.map(|index| new_words.text(index))
+        });
+    }
+    changes
+}
+
+// One line per change, then a count of each kind.
+pub fn diff_text(changes: &[Change]) -> String {
+    let mut output = String::new();
+    for change in changes {
+        let text = match (&change.old, &change.new) {
+            (&Some(ref old), &Some(ref new)) => format!("{} => {}", old, new),
+            (&Some(ref text), &None) | (&None, &Some(ref text)) => text.clone(),
+            (&None, &None) => String::new()
+        };
+        output.push_str(&format!("{:<8} {:>4} {:>4}  {}\n",
+            kind_name(change.kind),
+            change.old_address.map_or("-".to_string(), |address| format!("{:04X}", address)),
+            change.new_address.map_or("-".to_string(), |address| format!("{:04X}", address)),
+            text));
+    }
+    let counts: Vec<_> = [ChangeKind::Added, ChangeKind::Removed, ChangeKind::Changed, ChangeKind::Shifted].iter()
+        .map(|&kind| format!("{} {}", changes.iter().filter(|change| change.kind == kind).count(), kind_name(kind)))
+        .collect();
+    output.push_str(&format!("{}\n", counts.join(", ")));
+    output
+}
+
+pub fn diff_json(changes: &[Change]) -> String {
+    let optional_string = |text: &Option<String>| text.as_ref().map_or("null".to_string(), |text| writer::json_string(text));
+    let optional_number = |number: Option<u32>| number.map_or("null".to_string(), |number| number.to_string());
+    let entries: Vec<_> = changes.iter()
+        .map(|change| format!(
+            "    {{\"kind\": \"{}\", \"old_address\": {}, \"new_address\": {}, \"old\": {}, \"new\": {}}}",
+            kind_name(change.kind),
+            optional_number(change.old_address), optional_number(change.new_address),
+            optional_string(&change.old), optional_string(&change.new)))
+        .collect();
+    let counts: Vec<_> = [ChangeKind::Added, ChangeKind::Removed, ChangeKind::Changed, ChangeKind::Shifted].iter()
+        .map(|&kind| format!("  \"{}\": {}", kind_name(kind), changes.iter().filter(|change| change.kind == kind).count()))
+        .collect();
+    format!("{{\n  \"changes\": [\n{}\n  ],\n{}\n}}\n", entries.join(",\n"), counts.join(",\n"))
+}
+
+fn kind_name(kind: ChangeKind) -> &'static str {
+    match kind {
+        ChangeKind::Added => "added",
+        ChangeKind::Removed => "removed",
+        ChangeKind::Changed => "changed",
+        ChangeKind::Shifted => "shifted"
+    }
+}
+
+// The words of one program, decoded, with the keys they're aligned by.
+struct Side<'a> {
+    words: &'a [i64],
+    instructions: Vec<Decoded>,
+    keys: Vec<String>
+}
+
+impl<'a> Side<'a> {
+    fn new(bytecode: &'a Bytecode, instruction_set: &InstructionSet) -> Side<'a> {
+        let words = &bytecode.words[..];
+        let instructions = disasm::decode_words(words, &(0..words.len() as i64), instruction_set, bytecode.profile.encoding());
+        let keys = instructions.iter().zip(words)
+            .map(|(instruction, &word)| match instruction {
+                &Decoded::Branch(ref opcode, _) => opcode.clone(),
+                instruction => disasm::instruction_text(instruction, word)
+            })
+            .collect();
+        Side { words: words, instructions: instructions, keys: keys }
+    }
+
+    fn text(&self, index: usize) -> String {
+        disasm::instruction_text(&self.instructions[index], self.words[index])
+    }
+}
+
+// Pairs up the indices of the longest common subsequence of `old` and
+// `new`, with the indices in between either side of each pair matched up
+// where they can be and left unpaired where they can't.
+fn align(old: &[String], new: &[String]) -> Vec<(Option<usize>, Option<usize>)> {
+    let prefix = old.iter().zip(new).take_while(|&(old, new)| old == new).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|&(old, new)| old == new).count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+    // lengths[i][j] is the length of the longest common subsequence of
+    // old_middle[i..] and new_middle[j..].
+    let width = new_middle.len() + 1;
+    let mut lengths = vec![0u32; (old_middle.len() + 1) * width];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            lengths[i * width + j] = if old_middle[i] == new_middle[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+    let mut pairs: Vec<_> = (0..prefix).map(|index| (Some(index), Some(index))).collect();
+    let (mut i, mut j) = (0, 0);
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    while i < old_middle.len() || j < new_middle.len() {
+        if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+            pair_up(&mut pairs, &mut removed, &mut added);
+            pairs.push((Some(prefix + i), Some(prefix + j)));
+            i += 1;
+            j += 1;
+        } else if j == new_middle.len() || (i < old_middle.len() && lengths[(i + 1) * width + j] >= lengths[i * width + j + 1]) {
+            removed.push(prefix + i);
+            i += 1;
+        } else {
+            added.push(prefix + j);
+            j += 1;
+        }
+    }
+    pair_up(&mut pairs, &mut removed, &mut added);
+    pairs.extend((0..suffix).map(|index| (Some(old.len() - suffix + index), Some(new.len() - suffix + index))));
+    pairs
+}
+
+// Matches up a run of removed and added indices in order, leaving any left
+// over unpaired.
+fn pair_up(pairs: &mut Vec<(Option<usize>, Option<usize>)>, removed: &mut Vec<usize>, added: &mut Vec<usize>) {
+    let count = removed.len().max(added.len());
+    for index in 0..count {
+        pairs.push((removed.get(index).cloned(), added.get(index).cloned()));
+    }
+    removed.clear();
+    added.clear();
+}
@@ -0,0 +1,283 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+use container::{self, ContainerError, Header};
+use encoder::{self, Endian};
+use encoding::{Encoding, Profile};
+use isa::{self, InstructionSet, Operand};
+use pseudo;
+use writer;
+
+// Bytecode read for disassembly, along with how its words are laid out.
+pub struct Bytecode {
+    pub words: Vec<i64>,
+    pub endian: Endian,
+    pub profile: Profile,
+    // The container's header, if the bytecode was in one.
+    pub header: Option<Header>,
+    // The number of bytes after the last whole word, which aren't
+    // disassembled.
+    pub trailing: usize
+}
+
+// Reads bytecode to disassemble. A container says how its words are laid
+// out; anything else is taken to be raw words in `endian` order, of the size
+// `profile` gives. Bytecode written as text with `--format base64` or
+// `--format hex` is read back as it was before it was written out, with the
+// size of the words in hex giving the profile.
+pub fn read_bytecode(bytes: &[u8], endian: Endian, profile: Profile) -> Result<Bytecode, ContainerError> {
+    match read_text(bytes) {
+        Some(Text::Base64(bytes)) => return read_bytecode(&bytes, endian, profile),
+        Some(Text::Hex(words, word_size)) => return Ok(Bytecode {
+            words: words,
+            endian: endian,
+            profile: if word_size == 8 { Profile::Word64 } else { Profile::Word32 },
+            header: None,
+            trailing: 0
+        }),
+        None => ()
+    }
+    match container::read_words(bytes) {
+        Ok((header, words)) => {
+            let size = header.size() + words.len() * header.profile().encoding().word_size();
+            Ok(Bytecode {
+                words: words,
+                endian: header.endian(),
+                profile: header.profile(),
+                trailing: bytes.len() - size,
+                header: Some(header)
+            })
+        },
+        Err(ContainerError::BadMagic) => {
+            let word_size = profile.encoding().word_size();
+            Ok(Bytecode {
+                words: encoder::from_bytes(bytes, word_size, endian),
+                endian: endian,
+                profile: profile,
+                header: None,
+                trailing: bytes.len() % word_size
+            })
+        },
+        Err(error) => Err(error)
+    }
+}
+
+// Whether `bytes` is bytecode written as text, rather than source, which
+// otherwise it would look like.
+pub fn is_text_bytecode(bytes: &[u8]) -> bool {
+    read_text(bytes).is_some()
+}
+
+enum Text {
+    // A container, which is all `--format base64` writes.
+    Base64(Vec<u8>),
+    Hex(Vec<i64>, usize)
+}
+
+fn read_text(bytes: &[u8]) -> Option<Text> {
+    let text = match ::std::str::from_utf8(bytes) {
+        Ok(text) if !text.trim().is_empty() => text,
+        _ => return None
+    };
+    // "QASM" encodes to "UUFTT", and then some.
+    if text.trim_start().starts_with("UUFTT") {
+        if let Ok(bytes) = writer::read_base64(text) {
+            return Some(Text::Base64(bytes));
+        }
+    }
+    writer::read_hex(text).ok().map(|(words, word_size)| Text::Hex(words, word_size))
+}
+
+// One line per word, with the address of the first word being `origin`:
+// the address, the word in hexadecimal, and the instruction it decodes to.
+// Words that aren't a valid instruction are shown as `.word`. Each address
+// that's the target of a label operand is given a label named after it, as in
+// `:L_0042`, which is defined on the line before it and used as the operand.
+// The target of an operand that points outside the words is given in a
+// comment instead.
+pub fn disassemble(words: &[i64], origin: u32, instruction_set: &InstructionSet, encoding: &dyn Encoding) -> String {
+    let word_digits = encoding.word_size() * 2;
+    let mask = !0u64 >> (64 - word_digits * 4);
+    let mut output = String::new();
+    for (index, (label, text)) in instruction_lines(words, origin, instruction_set, encoding).into_iter().enumerate() {
+        if let Some(label) = label {
+            output.push_str(&format!(":{}\n", label));
+        }
+        output.push_str(&format!("{:04X}  {:03$X}  {}\n", origin as usize + index, words[index] as u64 & mask, text, word_digits));
+    }
+    output
+}
+
+// The text each word disassembles to, along with the label synthesized for
+// it if it's the target of a label operand.
+pub fn instruction_lines(words: &[i64], origin: u32, instruction_set: &InstructionSet, encoding: &dyn Encoding) -> Vec<(Option<String>, String)> {
+    let addresses = origin as i64..origin as i64 + words.len() as i64;
+    let instructions = decode_words(words, &addresses, instruction_set, encoding);
+    let targets = branch_targets(&instructions, &addresses);
+    addresses.clone().zip(words).zip(instructions)
+        .map(|((address, &word), instruction)| {
+            let text = match instruction {
+                Decoded::Branch(ref opcode, target) if !targets.contains(&target) =>
+                    format!("{} {:<8} ; {:04X}", opcode, target - (address + 1), target),
+                ref instruction => instruction_text(instruction, word)
+            };
+            (if targets.contains(&address) { Some(label_name(address)) } else { None }, text)
+        })
+        .collect()
+}
+
+// Disassembles to source that assembles back to exactly the same bytes, when
+// given the options listed in the comment at its start. Words are placed
+// from `origin`, and the entry address of a container is given with
+// `.start`. Anything that wouldn't assemble back to the same word, such as
+// a branch outside the words, is written as `.word`.
+pub fn disassemble_source(bytecode: &Bytecode, origin: u32, instruction_set: &InstructionSet) -> Result<String, String> {
+    if bytecode.trailing > 0 {
+        return Err(format!("{} bytes after the last whole word can't be disassembled", bytecode.trailing));
+    }
+    let words = &bytecode.words;
+    let addresses = origin as i64..origin as i64 + words.len() as i64;
+    let instructions = decode_words(words, &addresses, instruction_set, bytecode.profile.encoding());
+    let mut targets = branch_targets(&instructions, &addresses);
+    let entry = bytecode.header.as_ref().and_then(|header| header.entry).map(|entry| entry as i64);
+    if let Some(entry) = entry {
+        if !addresses.contains(&entry) {
+            return Err(format!("the entry address {:04X} is outside the words", entry));
+        }
+        targets.insert(entry);
+    }
+    let mut output = format!("; assemble with:{}{}{}\n",
+        match bytecode.header {
+            Some(ref header) if header.checksum.is_none() => " --no-checksum",
+            Some(_) => "",
+            None => " --raw"
+        },
+        if bytecode.endian == Endian::Big { " --endian big" } else { "" },
+        if bytecode.profile == Profile::Word64 { " --profile word64" } else { "" });
+    if origin != 0 {
+        output.push_str(&format!(".org {}\n", origin));
+    }
+    if let Some(entry) = entry {
+        output.push_str(&format!(".start :{}\n", label_name(entry)));
+    }
+    for ((address, &word), instruction) in addresses.clone().zip(words).zip(instructions) {
+        if targets.contains(&address) {
+            output.push_str(&format!(":{}\n", label_name(address)));
+        }
+        match instruction {
+            Decoded::Instruction(ref opcode, _) | Decoded::Branch(ref opcode, _) if pseudo::is_reserved(opcode) =>
+                output.push_str(&format!(".word {}", word)),
+            Decoded::Data => output.push_str(&format!(".word {}", word)),
+            Decoded::Instruction(ref opcode, None) => output.push_str(opcode),
+            Decoded::Instruction(ref opcode, Some(argument)) => output.push_str(&format!("{} {}", opcode, argument)),
+            Decoded::Branch(ref opcode, target) if targets.contains(&target) =>
+                output.push_str(&format!("{} :{}", opcode, label_name(target))),
+            Decoded::Branch(ref opcode, target) =>
+                output.push_str(&format!(".word {} ; {} {:04X}", word, opcode, target))
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+// What a word decodes to: data, an instruction with any argument, or an
+// instruction with a label operand and the address it targets.
+pub enum Decoded {
+    Data,
+    Instruction(String, Option<i64>),
+    Branch(String, i64)
+}
+
+pub fn decode_words(words: &[i64], addresses: &Range<i64>, instruction_set: &InstructionSet, encoding: &dyn Encoding) -> Vec<Decoded> {
+    addresses.clone().zip(words)
+        .map(|(address, &word)| decode(word, address, instruction_set, encoding))
+        .collect()
+}
+
+// The addresses within `addresses` that label operands target.
+fn branch_targets(instructions: &[Decoded], addresses: &Range<i64>) -> BTreeSet<i64> {
+    instructions.iter()
+        .filter_map(|instruction| match instruction {
+            &Decoded::Branch(_, target) if addresses.contains(&target) => Some(target),
+            _ => None
+        })
+        .collect()
+}
+
+pub fn label_name(address: i64) -> String {
+    format!("L_{:04X}", address)
+}
+
+// How `word` is written when it decodes to `instruction`, with any branch
+// target given as a label.
+pub fn instruction_text(instruction: &Decoded, word: i64) -> String {
+    match instruction {
+        &Decoded::Data => format!(".word {}", word),
+        &Decoded::Instruction(ref opcode, None) => opcode.clone(),
+        &Decoded::Instruction(ref opcode, Some(argument)) => format!("{} {}", opcode, argument),
+        &Decoded::Branch(ref opcode, target) => format!("{} :{}", opcode, label_name(target))
+    }
+}
+
+fn decode(word: i64, address: i64, instruction_set: &InstructionSet, encoding: &dyn Encoding) -> Decoded {
+    let (code, argument) = encoding.decode(word);
+    let opcode = match instruction_set.opcode_with_code(code) {
+        Some(opcode) => opcode,
+        None => return Decoded::Data
+    };
+    match opcode.operand {
+        Operand::None if argument == 0 => Decoded::Instruction(opcode.name.clone(), None),
+        Operand::Immediate => Decoded::Instruction(opcode.name.clone(), Some(argument)),
+        Operand::Depth if argument >= 1 && argument <= isa::MAX_DEPTH => Decoded::Instruction(opcode.name.clone(), Some(argument)),
+        Operand::Label => Decoded::Branch(opcode.name.clone(), address + 1 + argument),
+        _ => Decoded::Data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use options::Options;
+
+    // Every opcode, with arguments at the edges of what its operand can be,
+    // and a branch beyond the words, which has to be written as `.word`.
+    fn sample_words(instruction_set: &InstructionSet, encoding: &dyn Encoding) -> Vec<i64> {
+        let (minimum, maximum) = encoding.argument_range();
+        let mut words = Vec::new();
+        for opcode in instruction_set.opcodes() {
+            let arguments = match opcode.operand {
+                Operand::None => vec![0],
+                Operand::Immediate => vec![minimum, -1, 0, 1, maximum],
+                Operand::Depth => vec![1, 2, isa::MAX_DEPTH],
+                Operand::Label => vec![-1, 0, 1, maximum]
+            };
+            for argument in arguments {
+                words.push(encoding.encode(opcode.code as i64, argument));
+            }
+        }
+        // Somewhere for the last branches to go.
+        words.extend(&[0, 0]);
+        words
+    }
+
+    #[test]
+    fn round_trip_gives_the_same_bytes() {
+        let instruction_set = InstructionSet::default();
+        for &profile in &[Profile::Word32, Profile::Word64] {
+            for &endian in &[Endian::Little, Endian::Big] {
+                let encoding = profile.encoding();
+                let bytes = encoder::to_bytes(sample_words(&instruction_set, encoding), encoding.word_size(), endian);
+                let bytecode = read_bytecode(&bytes, endian, profile).ok().unwrap();
+                let source = disassemble_source(&bytecode, 0, &instruction_set).unwrap();
+                let mut options = Options::default();
+                options.profile = profile;
+                options.endian = endian;
+                let program = match ::assemble_with_options(&source, &options) {
+                    Ok(program) => program,
+                    Err(error) => panic!("{}\n{}", error, source)
+                };
+                assert!(program.bytes == bytes, "{} {} doesn't round trip:\n{}", profile.name(), endian.name(), source);
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use resolver::{Instruction, Word};
+use encoding::{self, Encoding};
+use error::{Diagnostic, DiagnosticKind};
+use extension::OpcodeExtension;
+use isa::InstructionSet;
+use suggestions;
+
+pub fn encode_word<'a>(word: Word<'a>, instruction_set: &InstructionSet, extensions: &[Box<dyn OpcodeExtension>], encoding: &dyn Encoding, diagnostics: &mut Vec<Diagnostic>) -> i64 {
+    match word {
+        Word::Instruction(instruction) => encode_instruction(instruction, instruction_set, extensions, encoding, diagnostics),
+        Word::Data(value) => value
+    }
+}
+
+fn encode_instruction<'a>(instruction: Instruction<'a>, instruction_set: &InstructionSet, extensions: &[Box<dyn OpcodeExtension>], encoding: &dyn Encoding, diagnostics: &mut Vec<Diagnostic>) -> i64 {
+    let opcode = match instruction_set.opcode(&instruction.opcode) {
+        Some(opcode) => opcode,
+        None => {
+            diagnostics.push(Diagnostic::new(
+                instruction.location,
+                DiagnosticKind::UnknownOpcode {
+                    opcode: instruction.opcode.to_string(),
+                    suggestion: suggestions::closest(instruction.opcode, instruction_set.opcodes().iter().map(|opcode| opcode.name.as_str()))
+                        .map(|name| name.to_string())
+                }));
+            return 0;
+        }
+    };
+    extensions.iter()
+        .filter_map(|extension| extension.encode(opcode, instruction.arg, encoding))
+        .next()
+        .unwrap_or(encoding.encode(opcode.code as i64, instruction.arg))
+}
+
+// The order words are written in, which doesn't depend on the machine
+// assembling them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Endian {
+    Little,
+    Big
+}
+
+impl Default for Endian {
+    fn default() -> Endian {
+        Endian::Little
+    }
+}
+
+impl Endian {
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Endian::Little => "little",
+            &Endian::Big => "big"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Endian> {
+        match name {
+            "little" => Some(Endian::Little),
+            "big" => Some(Endian::Big),
+            _ => None
+        }
+    }
+}
+
+// Writes the low `word_size` bytes of each word.
+pub fn to_bytes(bytecodes: Vec<i64>, word_size: usize, endian: Endian) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(bytecodes.len() * word_size);
+    for bytecode in bytecodes {
+        match endian {
+            Endian::Little => bytes.extend_from_slice(&bytecode.to_le_bytes()[..word_size]),
+            Endian::Big => bytes.extend_from_slice(&bytecode.to_be_bytes()[8 - word_size..])
+        }
+    }
+    bytes
+}
+
+// The inverse of `to_bytes`, sign-extending each word. Any bytes after the
+// last whole word are ignored.
+pub fn from_bytes(bytes: &[u8], word_size: usize, endian: Endian) -> Vec<i64> {
+    bytes.chunks(word_size)
+        .filter(|chunk| chunk.len() == word_size)
+        .map(|chunk| {
+            let word = match endian {
+                Endian::Little => chunk.iter().rev().fold(0, |word, &byte| (word << 8) | byte as i64),
+                Endian::Big => chunk.iter().fold(0, |word, &byte| (word << 8) | byte as i64)
+            };
+            encoding::sign_extend(word, word_size as u32 * 8)
+        })
+        .collect()
+}
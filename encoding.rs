@@ -0,0 +1,99 @@
+// How instructions are laid out in a word: the opcode in the low
+// `argument_shift` bits, and a signed argument in the `argument_bits` above
+// them.
+pub trait Encoding {
+    // The size of a word in bytes.
+    fn word_size(&self) -> usize;
+    fn argument_shift(&self) -> u32;
+    fn argument_bits(&self) -> u32;
+
+    fn word_bits(&self) -> u32 {
+        self.word_size() as u32 * 8
+    }
+
+    // The smallest and largest values a word of data can hold.
+    fn word_range(&self) -> (i64, i64) {
+        signed_range(self.word_bits())
+    }
+
+    // The smallest and largest values an argument can hold.
+    fn argument_range(&self) -> (i64, i64) {
+        signed_range(self.argument_bits())
+    }
+
+    fn encode(&self, code: i64, argument: i64) -> i64 {
+        code.wrapping_add(argument << self.argument_shift())
+    }
+
+    // Splits a word into its opcode and argument.
+    fn decode(&self, word: i64) -> (i64, i64) {
+        let code = word & ((1 << self.argument_shift()) - 1);
+        let argument = sign_extend(word >> self.argument_shift(), self.argument_bits());
+        (code, argument)
+    }
+}
+
+// 32-bit words with a 16-bit argument.
+pub struct Word32;
+
+// 64-bit words with a 48-bit argument.
+pub struct Word64;
+
+impl Encoding for Word32 {
+    fn word_size(&self) -> usize { 4 }
+    fn argument_shift(&self) -> u32 { 16 }
+    fn argument_bits(&self) -> u32 { 16 }
+}
+
+impl Encoding for Word64 {
+    fn word_size(&self) -> usize { 8 }
+    fn argument_shift(&self) -> u32 { 16 }
+    fn argument_bits(&self) -> u32 { 48 }
+}
+
+// The encodings that can be selected with `--profile`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Profile {
+    Word32,
+    Word64
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::Word32
+    }
+}
+
+impl Profile {
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Profile::Word32 => "word32",
+            &Profile::Word64 => "word64"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Profile> {
+        match name {
+            "word32" => Some(Profile::Word32),
+            "word64" => Some(Profile::Word64),
+            _ => None
+        }
+    }
+
+    pub fn encoding(&self) -> &'static dyn Encoding {
+        match self {
+            &Profile::Word32 => &Word32,
+            &Profile::Word64 => &Word64
+        }
+    }
+}
+
+fn signed_range(bits: u32) -> (i64, i64) {
+    let maximum = ((1u64 << (bits - 1)) - 1) as i64;
+    (-maximum - 1, maximum)
+}
+
+pub fn sign_extend(value: i64, bits: u32) -> i64 {
+    let unused = 64 - bits;
+    (value << unused) >> unused
+}
@@ -0,0 +1,404 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use parser;
+use writer;
+
+#[derive(Debug)]
+pub enum QuasmError {
+    Invalid(Vec<Diagnostic>),
+    Io(io::Error),
+    // An extension's opcodes clash with the instruction set.
+    Extension(String),
+    // The program can't be written in the requested format.
+    Output(String),
+    // Objects can't be linked, for each of these reasons.
+    Link(Vec<String>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: Option<String>,
+    pub line_number: usize,
+    pub column: usize
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub location: Location,
+    pub kind: DiagnosticKind,
+    // Whether a warning has been made an error, as by `--deny-warnings`.
+    pub denied: bool
+}
+
+#[derive(Debug)]
+pub enum DiagnosticKind {
+    UnknownOpcode { opcode: String, suggestion: Option<String> },
+    UndefinedLabel { label: String, suggestion: Option<String> },
+    DuplicateLabel { label: String, previous: Location },
+    BadArgument { argument: String },
+    ImmediateOutOfRange { literal: String },
+    UnexpectedToken { token: String },
+    UnexpectedEndOfArgument,
+    UndefinedSymbol { symbol: String, suggestion: Option<String> },
+    DuplicateSymbol { symbol: String, previous: Location },
+    PredefinedSymbol { symbol: String },
+    CircularDefinition { symbol: String },
+    BadSymbolName { name: String },
+    UnknownDirective { directive: String, suggestion: Option<String> },
+    ExtensionFailed { directive: String, message: String },
+    MissingOperand { directive: String },
+    ExpectedString { argument: String },
+    BadEscape { escape: String },
+    NotConstant { symbol: String },
+    NotConstantExpression { expression: String },
+    NegativeCount { directive: String, count: i64 },
+    BadAlignment { alignment: i64 },
+    OriginBehind { origin: i64, address: i64 },
+    UnterminatedDirective { directive: String },
+    UnmatchedDirective { directive: String },
+    DuplicateMacro { name: String, previous: Location },
+    DuplicateDirective { directive: String, previous: Location },
+    WrongMacroArguments { name: String, expected: usize, found: usize },
+    MacroRecursion { name: String },
+    IncludeFailed { path: String, reason: String },
+    IncludeCycle { path: String },
+    DivisionByZero,
+    Overflow,
+    InvalidAddressArithmetic,
+    ValueOutOfRange { value: i64, minimum: i64, maximum: i64 },
+    OffsetOutOfRange { offset: i64, minimum: i64, maximum: i64 },
+    FallsOffEnd { strict: bool },
+    UnexpectedArgument { opcode: String },
+    MissingArgument { opcode: String },
+    ExpectedLabel { opcode: String },
+    Unrelocatable { expression: String },
+    AbsoluteAddress { expression: String },
+    Unreachable { after: String },
+    UnusedLabel { label: String },
+    StackUnderflow { opcode: String, needs: i64, depth: i64 },
+    InconsistentStackDepth { depth: i64, previous: i64 },
+    BadStackEffect { argument: String },
+    StackEffectMismatch { depth: i64, pushes: i64 },
+    UnattachedDirective { directive: String },
+    MagicNumber { value: i64 },
+    DeepSwap { opcode: String, depth: i64 },
+    LongFunction { name: String, instructions: usize, limit: usize },
+    // A rule broken, as found by `quasm lint`, and whether it's denied.
+    Lint { rule: String, message: String, denied: bool }
+}
+
+impl Diagnostic {
+    pub fn new(location: Location, kind: DiagnosticKind) -> Diagnostic {
+        Diagnostic { location: location, kind: kind, denied: false }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        !self.denied && self.is_warning_kind()
+    }
+
+    fn message(&self) -> String {
+        if self.denied {
+            format!("{} (warnings are denied)", self.kind)
+        } else {
+            self.kind.to_string()
+        }
+    }
+
+    // Renders the diagnostic as rustc does, with its severity, its location,
+    // and the line it's on with the token it's about underlined, if `source`
+    // gives that line, followed by a blank line. Colors are ANSI escape codes.
+    //
+    //     error: unknown opcode `foo`
+    //      --> prog.qs:2:5
+    //       |
+    //     2 |     foo
+    //       |     ^^^
+    pub fn render(&self, source: Option<&str>, color: bool) -> String {
+        let paint = |code: &str, text: &str| if color { format!("\x1b[{}m{}\x1b[0m", code, text) } else { text.to_string() };
+        let (severity, severity_color) = if self.is_warning() { ("warning", "1;33") } else { ("error", "1;31") };
+        let mut output = format!("{}{}\n", paint(severity_color, severity), paint("1", &format!(": {}", self.message())));
+        let gutter = " ".repeat(self.location.line_number.to_string().len());
+        let place = match self.location.file {
+            Some(ref file) => format!("{}:{}:{}", file, self.location.line_number, self.location.column),
+            None => format!("<input>:{}:{}", self.location.line_number, self.location.column)
+        };
+        output.push_str(&format!("{}{} {}\n", gutter, paint("1;34", "-->"), place));
+        if let Some(source) = source {
+            let source = source.trim_end();
+            output.push_str(&format!("{} {}\n", gutter, paint("1;34", "|")));
+            output.push_str(&format!("{} {}\n", paint("1;34", &format!("{} |", self.location.line_number)), source));
+            let start = self.location.column - 1;
+            if start < source.len() && source.is_char_boundary(start) {
+                // Tabs are kept so that the caret lines up however wide they are.
+                let indent: String = source[..start].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                let width = parser::tokenize(&source[start..]).first().map_or(1, |token| token.chars().count().max(1));
+                output.push_str(&format!("{} {} {}{}\n", gutter, paint("1;34", "|"), indent, paint(severity_color, &"^".repeat(width))));
+            }
+        }
+        output.push('\n');
+        output
+    }
+
+    // The diagnostic as a JSON object on one line, with what `render` gives
+    // apart from the source line. A diagnostic is only ever about one line,
+    // which is both the first and the last of its range.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"severity\": {}, \"code\": {}, \"message\": {}, \"file\": {}, \"line_start\": {}, \"line_end\": {}, \"column\": {}}}",
+            writer::json_string(if self.is_warning() { "warning" } else { "error" }),
+            writer::json_string(self.kind.code()),
+            writer::json_string(&self.message()),
+            self.location.file.as_ref().map_or("null".to_string(), |file| writer::json_string(file)),
+            self.location.line_number,
+            self.location.line_number,
+            self.location.column)
+    }
+
+    fn is_warning_kind(&self) -> bool {
+        match self.kind {
+            DiagnosticKind::FallsOffEnd { strict } => !strict,
+            DiagnosticKind::Unreachable {..} | DiagnosticKind::UnusedLabel {..} => true,
+            DiagnosticKind::StackUnderflow {..} | DiagnosticKind::InconsistentStackDepth {..} => true,
+            DiagnosticKind::StackEffectMismatch {..} => true,
+            DiagnosticKind::MagicNumber {..} | DiagnosticKind::DeepSwap {..} | DiagnosticKind::LongFunction {..} => true,
+            DiagnosticKind::Lint { denied, .. } => !denied,
+            _ => false
+        }
+    }
+}
+
+impl DiagnosticKind {
+    // A name for the kind of diagnostic, such as `unknown-opcode`, which
+    // stays the same however the message is worded. A broken lint rule is
+    // named after the rule.
+    pub fn code(&self) -> &str {
+        match self {
+            &DiagnosticKind::UnknownOpcode {..} => "unknown-opcode",
+            &DiagnosticKind::UndefinedLabel {..} => "undefined-label",
+            &DiagnosticKind::DuplicateLabel {..} => "duplicate-label",
+            &DiagnosticKind::BadArgument {..} => "bad-argument",
+            &DiagnosticKind::ImmediateOutOfRange {..} => "immediate-out-of-range",
+            &DiagnosticKind::UnexpectedToken {..} => "unexpected-token",
+            &DiagnosticKind::UnexpectedEndOfArgument => "unexpected-end-of-argument",
+            &DiagnosticKind::UndefinedSymbol {..} => "undefined-symbol",
+            &DiagnosticKind::DuplicateSymbol {..} => "duplicate-symbol",
+            &DiagnosticKind::PredefinedSymbol {..} => "predefined-symbol",
+            &DiagnosticKind::CircularDefinition {..} => "circular-definition",
+            &DiagnosticKind::BadSymbolName {..} => "bad-symbol-name",
+            &DiagnosticKind::UnknownDirective {..} => "unknown-directive",
+            &DiagnosticKind::ExtensionFailed {..} => "extension-failed",
+            &DiagnosticKind::MissingOperand {..} => "missing-operand",
+            &DiagnosticKind::ExpectedString {..} => "expected-string",
+            &DiagnosticKind::BadEscape {..} => "bad-escape",
+            &DiagnosticKind::NotConstant {..} => "not-constant",
+            &DiagnosticKind::NotConstantExpression {..} => "not-constant-expression",
+            &DiagnosticKind::NegativeCount {..} => "negative-count",
+            &DiagnosticKind::BadAlignment {..} => "bad-alignment",
+            &DiagnosticKind::OriginBehind {..} => "origin-behind",
+            &DiagnosticKind::UnterminatedDirective {..} => "unterminated-directive",
+            &DiagnosticKind::UnmatchedDirective {..} => "unmatched-directive",
+            &DiagnosticKind::DuplicateMacro {..} => "duplicate-macro",
+            &DiagnosticKind::DuplicateDirective {..} => "duplicate-directive",
+            &DiagnosticKind::WrongMacroArguments {..} => "wrong-macro-arguments",
+            &DiagnosticKind::MacroRecursion {..} => "macro-recursion",
+            &DiagnosticKind::IncludeFailed {..} => "include-failed",
+            &DiagnosticKind::IncludeCycle {..} => "include-cycle",
+            &DiagnosticKind::DivisionByZero => "division-by-zero",
+            &DiagnosticKind::Overflow => "overflow",
+            &DiagnosticKind::InvalidAddressArithmetic => "invalid-address-arithmetic",
+            &DiagnosticKind::ValueOutOfRange {..} => "value-out-of-range",
+            &DiagnosticKind::OffsetOutOfRange {..} => "offset-out-of-range",
+            &DiagnosticKind::FallsOffEnd {..} => "falls-off-end",
+            &DiagnosticKind::UnexpectedArgument {..} => "unexpected-argument",
+            &DiagnosticKind::MissingArgument {..} => "missing-argument",
+            &DiagnosticKind::ExpectedLabel {..} => "expected-label",
+            &DiagnosticKind::Unrelocatable {..} => "unrelocatable",
+            &DiagnosticKind::AbsoluteAddress {..} => "absolute-address",
+            &DiagnosticKind::Unreachable {..} => "unreachable",
+            &DiagnosticKind::UnusedLabel {..} => "unused-label",
+            &DiagnosticKind::StackUnderflow {..} => "stack-underflow",
+            &DiagnosticKind::InconsistentStackDepth {..} => "inconsistent-stack-depth",
+            &DiagnosticKind::BadStackEffect {..} => "bad-stack-effect",
+            &DiagnosticKind::StackEffectMismatch {..} => "stack-effect-mismatch",
+            &DiagnosticKind::UnattachedDirective {..} => "unattached-directive",
+            &DiagnosticKind::MagicNumber {..} => "magic-number",
+            &DiagnosticKind::DeepSwap {..} => "deep-swap",
+            &DiagnosticKind::LongFunction {..} => "long-function",
+            &DiagnosticKind::Lint { ref rule, .. } => rule.as_str()
+        }
+    }
+}
+
+impl fmt::Display for QuasmError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &QuasmError::Invalid(ref diagnostics) => {
+                for diagnostic in diagnostics {
+                    try!(writeln!(formatter, "{}", diagnostic));
+                }
+                let errors = diagnostics.iter().filter(|diagnostic| !diagnostic.is_warning()).count();
+                write!(formatter, "{} error(s)", errors)
+            },
+            &QuasmError::Io(ref error) =>
+                write!(formatter, "{}", error),
+            &QuasmError::Extension(ref message) =>
+                write!(formatter, "{}", message),
+            &QuasmError::Output(ref message) =>
+                write!(formatter, "{}", message),
+            &QuasmError::Link(ref messages) => {
+                for message in messages {
+                    try!(writeln!(formatter, "{}", message));
+                }
+                write!(formatter, "{} error(s)", messages.len())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(formatter, "{}:{}:{}", file, self.line_number, self.column),
+            None => write!(formatter, "line {}, column {}", self.line_number, self.column)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_warning() {
+            write!(formatter, "{}: warning: {}", self.location, self.message())
+        } else {
+            write!(formatter, "{}: {}", self.location, self.message())
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DiagnosticKind::UnknownOpcode { ref opcode, suggestion: Some(ref suggestion) } =>
+                write!(formatter, "unknown opcode `{}`, did you mean `{}`?", opcode, suggestion),
+            &DiagnosticKind::UnknownOpcode { ref opcode, suggestion: None } =>
+                write!(formatter, "unknown opcode `{}`", opcode),
+            &DiagnosticKind::UndefinedLabel { ref label, suggestion: Some(ref suggestion) } =>
+                write!(formatter, "undefined label `:{}`, did you mean `:{}`?", label, suggestion),
+            &DiagnosticKind::UndefinedLabel { ref label, suggestion: None } =>
+                write!(formatter, "undefined label `:{}`", label),
+            &DiagnosticKind::DuplicateLabel { ref label, ref previous } =>
+                write!(formatter, "label `:{}` is already defined at {}", label, previous),
+            &DiagnosticKind::BadArgument { ref argument } =>
+                write!(formatter, "bad argument `{}`, expected an integer literal or a label", argument),
+            &DiagnosticKind::ImmediateOutOfRange { ref literal } =>
+                write!(formatter, "literal `{}` is out of range, as it doesn't fit in 64 bits", literal),
+            &DiagnosticKind::UnexpectedToken { ref token } =>
+                write!(formatter, "unexpected `{}` in argument", token),
+            &DiagnosticKind::UnexpectedEndOfArgument =>
+                write!(formatter, "unexpected end of argument"),
+            &DiagnosticKind::UndefinedSymbol { ref symbol, suggestion: Some(ref suggestion) } =>
+                write!(formatter, "undefined symbol `{}`, did you mean `{}`?", symbol, suggestion),
+            &DiagnosticKind::UndefinedSymbol { ref symbol, suggestion: None } =>
+                write!(formatter, "undefined symbol `{}`", symbol),
+            &DiagnosticKind::DuplicateSymbol { ref symbol, ref previous } =>
+                write!(formatter, "symbol `{}` is already defined at {}", symbol, previous),
+            &DiagnosticKind::PredefinedSymbol { ref symbol } =>
+                write!(formatter, "symbol `{}` is already defined on the command line", symbol),
+            &DiagnosticKind::CircularDefinition { ref symbol } =>
+                write!(formatter, "symbol `{}` is defined in terms of itself", symbol),
+            &DiagnosticKind::BadSymbolName { ref name } =>
+                write!(formatter, "`{}` is not a valid symbol name", name),
+            &DiagnosticKind::UnknownDirective { ref directive, suggestion: Some(ref suggestion) } =>
+                write!(formatter, "unknown directive `{}`, did you mean `{}`?", directive, suggestion),
+            &DiagnosticKind::UnknownDirective { ref directive, suggestion: None } =>
+                write!(formatter, "unknown directive `{}`", directive),
+            &DiagnosticKind::ExtensionFailed { ref directive, ref message } =>
+                write!(formatter, "`{}`: {}", directive, message),
+            &DiagnosticKind::MissingOperand { ref directive } =>
+                write!(formatter, "missing operand for `{}`", directive),
+            &DiagnosticKind::ExpectedString { ref argument } =>
+                write!(formatter, "expected a quoted string, found `{}`", argument),
+            &DiagnosticKind::BadEscape { ref escape } =>
+                write!(formatter, "invalid escape sequence `{}`", escape),
+            &DiagnosticKind::NotConstant { ref symbol } =>
+                write!(formatter, "`{}` does not have a constant value at this point", symbol),
+            &DiagnosticKind::NotConstantExpression { ref expression } =>
+                write!(formatter, "`{}` is not a constant expression", expression),
+            &DiagnosticKind::NegativeCount { ref directive, count } =>
+                write!(formatter, "`{}` count must not be negative, found {}", directive, count),
+            &DiagnosticKind::BadAlignment { alignment } =>
+                write!(formatter, "alignment must be positive, found {}", alignment),
+            &DiagnosticKind::OriginBehind { origin, address } =>
+                write!(formatter, "origin {} is before the current address {}", origin, address),
+            &DiagnosticKind::UnterminatedDirective { ref directive } =>
+                write!(formatter, "`{}` is never closed", directive),
+            &DiagnosticKind::UnmatchedDirective { ref directive } =>
+                write!(formatter, "`{}` without a matching opening directive", directive),
+            &DiagnosticKind::DuplicateDirective { ref directive, ref previous } =>
+                write!(formatter, "`{}` is already given at {}", directive, previous),
+            &DiagnosticKind::DuplicateMacro { ref name, ref previous } =>
+                write!(formatter, "macro `{}` is already defined at {}", name, previous),
+            &DiagnosticKind::WrongMacroArguments { ref name, expected, found } =>
+                write!(formatter, "macro `{}` takes {} argument(s) but {} were given", name, expected, found),
+            &DiagnosticKind::MacroRecursion { ref name } =>
+                write!(formatter, "expansion of `{}` is nested too deeply", name),
+            &DiagnosticKind::IncludeFailed { ref path, ref reason } =>
+                write!(formatter, "cannot include `{}`: {}", path, reason),
+            &DiagnosticKind::IncludeCycle { ref path } =>
+                write!(formatter, "`{}` includes itself", path),
+            &DiagnosticKind::DivisionByZero =>
+                write!(formatter, "division by zero in argument"),
+            &DiagnosticKind::Overflow =>
+                write!(formatter, "arithmetic overflow in argument"),
+            &DiagnosticKind::InvalidAddressArithmetic =>
+                write!(formatter, "label addresses can only be offset by an integer or subtracted from each other"),
+            &DiagnosticKind::ValueOutOfRange { value, minimum, maximum } =>
+                write!(formatter, "argument evaluates to {}, which is out of range, expected {} to {}", value, minimum, maximum),
+            &DiagnosticKind::OffsetOutOfRange { offset, minimum, maximum } =>
+                write!(formatter, "label is {} words away, which is out of range of a relative offset ({} to {})", offset, minimum, maximum),
+            &DiagnosticKind::FallsOffEnd {..} =>
+                write!(formatter, "execution can run off the end of the program here, expected `halt` or a jump"),
+            &DiagnosticKind::UnexpectedArgument { ref opcode } =>
+                write!(formatter, "`{}` doesn't take an argument", opcode),
+            &DiagnosticKind::MissingArgument { ref opcode } =>
+                write!(formatter, "`{}` requires an argument", opcode),
+            &DiagnosticKind::ExpectedLabel { ref opcode } =>
+                write!(formatter, "`{}` requires a label argument, such as `:loop`", opcode),
+            &DiagnosticKind::Unrelocatable { ref expression } =>
+                write!(formatter, "`{}` can't be relocated when linked, expected a label plus or minus a constant, or the distance between labels in the same section", expression),
+            &DiagnosticKind::AbsoluteAddress { ref expression } =>
+                write!(formatter, "`{}` depends on the absolute address of a label, which isn't allowed with `--pic` since the program may be loaded anywhere", expression),
+            &DiagnosticKind::Unreachable { ref after } =>
+                write!(formatter, "unreachable code: this follows a `{}` with no label in between, so it can never run", after),
+            &DiagnosticKind::UnusedLabel { ref label } =>
+                write!(formatter, "label `{}` is never used", label),
+            &DiagnosticKind::StackUnderflow { ref opcode, needs, depth } =>
+                write!(formatter, "stack underflow: `{}` needs {} value(s) on the stack, but there can be only {} here", opcode, needs, depth),
+            &DiagnosticKind::InconsistentStackDepth { depth, previous } =>
+                write!(formatter, "the stack depth here is {} along one path but {} along another", depth, previous),
+            &DiagnosticKind::BadStackEffect { ref argument } =>
+                write!(formatter, "bad stack effect `{}`, expected how many values are taken and left, such as `-2 +1`", argument),
+            &DiagnosticKind::StackEffectMismatch { depth, pushes } =>
+                write!(formatter, "this returns leaving {} value(s) on the stack in place of its arguments, but its stack effect says {}", depth, pushes),
+            &DiagnosticKind::UnattachedDirective { ref directive } =>
+                write!(formatter, "`{}` must be in a `.func` or before a label", directive),
+            &DiagnosticKind::MagicNumber { value } =>
+                write!(formatter, "magic number {}, consider naming it with `.equ`", value),
+            &DiagnosticKind::DeepSwap { ref opcode, depth } =>
+                write!(formatter, "`{} {}` reaches deep into the stack, which is hard to follow", opcode, depth),
+            &DiagnosticKind::LongFunction { ref name, instructions, limit } =>
+                write!(formatter, "function `{}` has {} instructions, more than {}", name, instructions, limit),
+            &DiagnosticKind::Lint { ref rule, ref message, .. } =>
+                write!(formatter, "{} [{}]", message, rule)
+        }
+    }
+}
+
+impl error::Error for QuasmError {}
+
+impl From<io::Error> for QuasmError {
+    fn from(error: io::Error) -> QuasmError {
+        QuasmError::Io(error)
+    }
+}
@@ -0,0 +1,386 @@
+use literals::{self, LiteralError};
+
+pub enum Expression<'a> {
+    Integer(i64),
+    Label(&'a str),
+    // `@name`, the label's absolute address rather than an offset to it.
+    AbsoluteLabel(&'a str),
+    Symbol(&'a str),
+    Negate(&'a str, Box<Expression<'a>>),
+    Not(&'a str, Box<Expression<'a>>),
+    Binary(Operator, &'a str, Box<Expression<'a>>, Box<Expression<'a>>)
+}
+
+#[derive(Clone, Copy)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or
+}
+
+// Label addresses are tracked separately from plain integers so that an
+// argument such as `:table+2` still resolves to a PC-relative offset, while
+// the distance `:end-:start` between two labels is an ordinary integer.
+#[derive(Clone, Copy)]
+pub enum Value {
+    Integer(i64),
+    Address(i64)
+}
+
+pub struct SyntaxError<'a> {
+    pub token: &'a str,
+    pub kind: SyntaxErrorKind
+}
+
+pub enum SyntaxErrorKind {
+    MalformedLiteral,
+    LiteralOutOfRange,
+    UnexpectedToken,
+    UnexpectedEnd
+}
+
+pub struct EvaluationError<'a> {
+    pub token: &'a str,
+    pub kind: EvaluationErrorKind
+}
+
+pub enum EvaluationErrorKind {
+    UndefinedLabel,
+    UndefinedSymbol,
+    DivisionByZero,
+    Overflow,
+    InvalidAddressArithmetic,
+    CircularDefinition,
+    // The symbol is defined, but its value isn't available yet.
+    NotConstant,
+    // The symbol's own definition is invalid, and has already been reported.
+    InvalidSymbol
+}
+
+pub trait Environment {
+    fn label(&self, name: &str) -> Result<Value, EvaluationErrorKind>;
+    fn symbol(&self, name: &str) -> Result<Value, EvaluationErrorKind>;
+}
+
+pub fn parse<'a>(text: &'a str) -> Result<Expression<'a>, SyntaxError<'a>> {
+    let tokens = try!(tokenize(text));
+    let mut parser = Parser { text: text, tokens: tokens, position: 0 };
+    let expression = try!(parser.parse_binary(0));
+    match parser.tokens.get(parser.position) {
+        Some(&token) => Err(SyntaxError { token: token, kind: SyntaxErrorKind::UnexpectedToken }),
+        None => Ok(expression)
+    }
+}
+
+pub fn evaluate<'a, E: Environment>(expression: &Expression<'a>, environment: &E) -> Result<Value, EvaluationError<'a>> {
+    match expression {
+        &Expression::Integer(value) => Ok(Value::Integer(value)),
+        &Expression::Label(name) => environment.label(name)
+            .map_err(|kind| EvaluationError { token: name, kind: kind }),
+        &Expression::AbsoluteLabel(name) => match environment.label(name) {
+            Ok(Value::Address(address)) => Ok(Value::Integer(address)),
+            Ok(value) => Ok(value),
+            Err(kind) => Err(EvaluationError { token: name, kind: kind })
+        },
+        &Expression::Symbol(name) => environment.symbol(name)
+            .map_err(|kind| EvaluationError { token: name, kind: kind }),
+        &Expression::Negate(token, ref operand) => match try!(evaluate(operand, environment)) {
+            Value::Integer(value) => value.checked_neg()
+                .map(Value::Integer)
+                .ok_or(EvaluationError { token: token, kind: EvaluationErrorKind::Overflow }),
+            Value::Address(_) =>
+                Err(EvaluationError { token: token, kind: EvaluationErrorKind::InvalidAddressArithmetic })
+        },
+        &Expression::Not(token, ref operand) => match try!(evaluate(operand, environment)) {
+            Value::Integer(value) => Ok(Value::Integer((value == 0) as i64)),
+            Value::Address(_) =>
+                Err(EvaluationError { token: token, kind: EvaluationErrorKind::InvalidAddressArithmetic })
+        },
+        &Expression::Binary(operator, token, ref left, ref right) => {
+            let left_value = try!(evaluate(left, environment));
+            // `&&` and `||` short-circuit, so that `.if DIVISOR && 10 / DIVISOR`
+            // doesn't fail when DIVISOR is zero.
+            match (operator, left_value) {
+                (Operator::And, Value::Integer(0)) => return Ok(Value::Integer(0)),
+                (Operator::Or, Value::Integer(value)) if value != 0 => return Ok(Value::Integer(1)),
+                _ => ()
+            }
+            let right_value = try!(evaluate(right, environment));
+            apply(operator, left_value, right_value)
+                .map_err(|kind| EvaluationError { token: token, kind: kind })
+        }
+    }
+}
+
+// Every label an expression uses, in the order they're written.
+pub fn labels<'a>(expression: &Expression<'a>) -> Vec<&'a str> {
+    match expression {
+        &Expression::Label(name) | &Expression::AbsoluteLabel(name) => vec![name],
+        &Expression::Integer(_) | &Expression::Symbol(_) => Vec::new(),
+        &Expression::Negate(_, ref operand) | &Expression::Not(_, ref operand) => labels(operand),
+        &Expression::Binary(_, _, ref left, ref right) => {
+            let mut names = labels(left);
+            names.extend(labels(right));
+            names
+        }
+    }
+}
+
+// How an expression's value changes when the labels it uses move: a list of
+// terms, each added to the value the number of times it's paired with, which
+// is negative if it's subtracted. Terms that cancel out are left out, so the
+// distance between two labels that move together has none. `leaf` gives the
+// terms of each label and symbol. Returns `None` if a label is used in any
+// other way, such as being multiplied or compared, so that the value doesn't
+// move with it.
+pub fn label_terms<'a, T, F>(expression: &Expression<'a>, leaf: &F) -> Option<Vec<(T, i64)>>
+    where T: PartialEq, F: Fn(&Expression<'a>) -> Option<Vec<(T, i64)>>
+{
+    match expression {
+        &Expression::Label(_) | &Expression::AbsoluteLabel(_) | &Expression::Symbol(_) => leaf(expression),
+        &Expression::Integer(_) => Some(Vec::new()),
+        &Expression::Negate(_, ref operand) =>
+            label_terms(operand, leaf).map(|terms| terms.into_iter().map(|(term, count)| (term, -count)).collect()),
+        &Expression::Not(_, ref operand) => label_terms(operand, leaf).filter(|terms| terms.is_empty()),
+        &Expression::Binary(operator, _, ref left, ref right) => {
+            let (left, right) = match (label_terms(left, leaf), label_terms(right, leaf)) {
+                (Some(left), Some(right)) => (left, right),
+                _ => return None
+            };
+            match operator {
+                Operator::Add => Some(combine_terms(left, right, 1)),
+                Operator::Subtract => Some(combine_terms(left, right, -1)),
+                _ if left.is_empty() && right.is_empty() => Some(Vec::new()),
+                _ => None
+            }
+        }
+    }
+}
+
+// Adds each of the right terms the given number of times to the left ones,
+// leaving out any that cancel out.
+fn combine_terms<T: PartialEq>(left: Vec<(T, i64)>, right: Vec<(T, i64)>, sign: i64) -> Vec<(T, i64)> {
+    let mut terms = left;
+    for (term, count) in right {
+        match terms.iter_mut().find(|&&mut (ref existing, _)| *existing == term) {
+            Some(&mut (_, ref mut total)) => *total += sign * count,
+            None => terms.push((term, sign * count))
+        }
+    }
+    terms.retain(|&(_, count)| count != 0);
+    terms
+}
+
+fn apply(operator: Operator, left: Value, right: Value) -> Result<Value, EvaluationErrorKind> {
+    match (operator, left, right) {
+        (Operator::Add, Value::Address(address), Value::Integer(offset)) |
+        (Operator::Add, Value::Integer(offset), Value::Address(address)) |
+        (Operator::Subtract, Value::Address(address), Value::Integer(offset)) => {
+            let offset = match operator { Operator::Subtract => offset.checked_neg(), _ => Some(offset) };
+            offset.and_then(|offset| address.checked_add(offset))
+                .map(Value::Address)
+                .ok_or(EvaluationErrorKind::Overflow)
+        },
+        (Operator::Subtract, Value::Address(left), Value::Address(right)) =>
+            left.checked_sub(right).map(Value::Integer).ok_or(EvaluationErrorKind::Overflow),
+        (_, Value::Address(left), Value::Address(right)) if is_comparison(operator) =>
+            Ok(Value::Integer(compare(operator, left, right) as i64)),
+        (_, Value::Integer(left), Value::Integer(right)) => {
+            let result = match operator {
+                Operator::Add => left.checked_add(right),
+                Operator::Subtract => left.checked_sub(right),
+                Operator::Multiply => left.checked_mul(right),
+                Operator::Divide | Operator::Remainder if right == 0 =>
+                    return Err(EvaluationErrorKind::DivisionByZero),
+                Operator::Divide => left.checked_div(right),
+                Operator::Remainder => left.checked_rem(right),
+                Operator::And => Some((left != 0 && right != 0) as i64),
+                Operator::Or => Some((left != 0 || right != 0) as i64),
+                _ => Some(compare(operator, left, right) as i64)
+            };
+            result.map(Value::Integer).ok_or(EvaluationErrorKind::Overflow)
+        },
+        _ => Err(EvaluationErrorKind::InvalidAddressArithmetic)
+    }
+}
+
+fn is_comparison(operator: Operator) -> bool {
+    match operator {
+        Operator::Equal | Operator::NotEqual | Operator::Less |
+        Operator::LessOrEqual | Operator::Greater | Operator::GreaterOrEqual => true,
+        _ => false
+    }
+}
+
+fn compare(operator: Operator, left: i64, right: i64) -> bool {
+    match operator {
+        Operator::Equal => left == right,
+        Operator::NotEqual => left != right,
+        Operator::Less => left < right,
+        Operator::LessOrEqual => left <= right,
+        Operator::Greater => left > right,
+        _ => left >= right
+    }
+}
+
+// Binary operators and their precedence, from loosest to tightest binding.
+fn binary_operator(token: &str) -> Option<(Operator, u8)> {
+    match token {
+        "||" => Some((Operator::Or, 1)),
+        "&&" => Some((Operator::And, 2)),
+        "==" => Some((Operator::Equal, 3)),
+        "!=" => Some((Operator::NotEqual, 3)),
+        "<" => Some((Operator::Less, 4)),
+        "<=" => Some((Operator::LessOrEqual, 4)),
+        ">" => Some((Operator::Greater, 4)),
+        ">=" => Some((Operator::GreaterOrEqual, 4)),
+        "+" => Some((Operator::Add, 5)),
+        "-" => Some((Operator::Subtract, 5)),
+        "*" => Some((Operator::Multiply, 6)),
+        "/" => Some((Operator::Divide, 6)),
+        "%" => Some((Operator::Remainder, 6)),
+        _ => None
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<&'a str>,
+    position: usize
+}
+
+impl<'a> Parser<'a> {
+    fn parse_binary(&mut self, minimum_precedence: u8) -> Result<Expression<'a>, SyntaxError<'a>> {
+        let mut left = try!(self.parse_unary());
+        loop {
+            let (operator, precedence, token) = match self.peek().and_then(|token| binary_operator(token).map(|operator| (operator, token))) {
+                Some(((operator, precedence), token)) if precedence > minimum_precedence => (operator, precedence, token),
+                _ => return Ok(left)
+            };
+            self.position += 1;
+            let right = try!(self.parse_binary(precedence));
+            left = Expression::Binary(operator, token, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression<'a>, SyntaxError<'a>> {
+        match self.peek() {
+            Some(token @ "-") => {
+                self.position += 1;
+                let operand = try!(self.parse_unary());
+                Ok(Expression::Negate(token, Box::new(operand)))
+            },
+            Some(token @ "!") => {
+                self.position += 1;
+                let operand = try!(self.parse_unary());
+                Ok(Expression::Not(token, Box::new(operand)))
+            },
+            Some("+") => {
+                self.position += 1;
+                self.parse_unary()
+            },
+            _ => self.parse_atom()
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Expression<'a>, SyntaxError<'a>> {
+        let token = match self.tokens.get(self.position) {
+            Some(&token) => token,
+            None => return Err(SyntaxError { token: &self.text[self.text.len()..], kind: SyntaxErrorKind::UnexpectedEnd })
+        };
+        self.position += 1;
+        if token == "(" {
+            let expression = try!(self.parse_binary(0));
+            match self.peek() {
+                Some(")") => {
+                    self.position += 1;
+                    Ok(expression)
+                },
+                Some(_) => Err(SyntaxError { token: self.tokens[self.position], kind: SyntaxErrorKind::UnexpectedToken }),
+                None => Err(SyntaxError { token: &self.text[self.text.len()..], kind: SyntaxErrorKind::UnexpectedEnd })
+            }
+        } else if token.starts_with(":") && token.len() > 1 {
+            Ok(Expression::Label(&token[1..]))
+        } else if token.starts_with("@") && token.len() > 1 {
+            Ok(Expression::AbsoluteLabel(&token[1..]))
+        } else if token.starts_with(|c: char| c.is_digit(10) || c == '\'') {
+            literals::parse_integer(token)
+                .map(Expression::Integer)
+                .map_err(|error| SyntaxError {
+                    token: token,
+                    kind: match error {
+                        LiteralError::Malformed => SyntaxErrorKind::MalformedLiteral,
+                        LiteralError::OutOfRange => SyntaxErrorKind::LiteralOutOfRange
+                    }
+                })
+        } else if token.starts_with(is_name_character) {
+            Ok(Expression::Symbol(token))
+        } else {
+            Err(SyntaxError { token: token, kind: SyntaxErrorKind::UnexpectedToken })
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|&token| token)
+    }
+}
+
+fn tokenize<'a>(text: &'a str) -> Result<Vec<&'a str>, SyntaxError<'a>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        if c == '\'' {
+            let mut escaped = false;
+            let mut closed = false;
+            while let Some((index, c)) = chars.next() {
+                end = index + c.len_utf8();
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '\'' {
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                return Err(SyntaxError { token: &text[start..end], kind: SyntaxErrorKind::MalformedLiteral });
+            }
+        } else if "=!<>&|".contains(c) {
+            if let Some(&(index, next)) = chars.peek() {
+                if binary_operator(&text[start..index + next.len_utf8()]).is_some() {
+                    end = index + next.len_utf8();
+                    chars.next();
+                }
+            }
+        } else if c == ':' || c == '@' || is_name_character(c) {
+            while let Some(&(index, c)) = chars.peek() {
+                if !is_name_character(c) {
+                    break;
+                }
+                end = index + c.len_utf8();
+                chars.next();
+            }
+        }
+        tokens.push(&text[start..end]);
+    }
+    Ok(tokens)
+}
+
+pub fn is_name_character(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
@@ -0,0 +1,27 @@
+use encoding::Encoding;
+use isa::Opcode;
+
+// Lets library users add host-specific instructions and directives without
+// changing quasm. Extensions are consulted in the order they're registered
+// in `Options::extensions`, before the built-in behaviour.
+pub trait OpcodeExtension {
+    // Opcodes to add to the instruction set. Their names mustn't clash with
+    // an existing opcode or alias.
+    fn opcodes(&self) -> Vec<Opcode> {
+        Vec::new()
+    }
+
+    // Encodes an instruction, or returns `None` to use the default encoding
+    // of `encoding`.
+    fn encode(&self, _opcode: &Opcode, _argument: i64, _encoding: &dyn Encoding) -> Option<i64> {
+        None
+    }
+
+    // Handles a directive, such as `.vector`, that isn't built in. `operands`
+    // is the text following the directive, which is empty if there's none.
+    // Returns the words to emit, or `None` if the directive isn't one of this
+    // extension's.
+    fn directive(&self, _directive: &str, _operands: &str) -> Option<Result<Vec<i64>, String>> {
+        None
+    }
+}
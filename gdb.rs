@@ -0,0 +1,317 @@
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use debugger::{Debugger, Event};
+use vm::{Stop, Trap, TrapKind};
+
+// How many instructions a continue runs between looking for an interrupt.
+const INTERRUPT_INTERVAL: u32 = 1024;
+
+// The registers, in the order of `g` packets: the program counter, as a byte
+// address, the depth of the operand stack, the number of calls that haven't
+// returned, and the value at the top of the stack, or 0 if it's empty.
+const TARGET_XML: &'static str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\"><feature name=\"org.quasm.core\">\
+<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/>\
+<reg name=\"depth\" bitsize=\"32\" type=\"uint32\"/>\
+<reg name=\"calls\" bitsize=\"32\" type=\"uint32\"/>\
+<reg name=\"top\" bitsize=\"64\" type=\"int64\"/>\
+</feature></target>";
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+// Serves the gdb remote serial protocol on `stream` until gdb detaches or
+// kills the program, and gives whether the program should run on, which it
+// shouldn't once it's killed. The program reads `input` and prints to
+// `output` as it would without gdb.
+//
+// Memory is given to gdb a byte at a time, with each word taking as many
+// bytes as it's encoded in, little-endian, so the byte address of a word is
+// its address times its size. Breakpoints and the program counter are byte
+// addresses too. The registers are as `TARGET_XML` describes. Monitor
+// commands are as for `quasm debug`, so that `monitor stack` shows the whole
+// stack. A continue can be interrupted, as the stream is looked at for gdb's
+// interrupt every so often.
+pub fn serve_gdb(stream: &mut TcpStream, debugger: &mut Debugger, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<bool> {
+    let word_size = debugger.machine.profile.encoding().word_size() as u64;
+    let mut stub = Stub { stream: stream, debugger: debugger, input: input, output: output, word_size: word_size, acks: true };
+    while let Some(packet) = try!(stub.read_packet()) {
+        if packet == "k" || packet.starts_with("vKill") {
+            if packet.starts_with("vKill") {
+                try!(stub.send("OK"));
+            }
+            return Ok(false);
+        }
+        let detach = packet.starts_with("D");
+        let reply = try!(stub.handle(&packet));
+        try!(stub.send(&reply));
+        // Acknowledgements stop after the reply agreeing to it.
+        if packet == "QStartNoAckMode" {
+            stub.acks = false;
+        }
+        if detach {
+            break;
+        }
+    }
+    Ok(true)
+}
+
+struct Stub<'a> {
+    stream: &'a mut TcpStream,
+    debugger: &'a mut Debugger,
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+    // The number of bytes each word takes.
+    word_size: u64,
+    // Whether packets are acknowledged, until gdb asks for them not to be.
+    acks: bool
+}
+
+impl<'a> Stub<'a> {
+    // The reply to a packet, which is empty for any that aren't supported.
+    fn handle(&mut self, packet: &str) -> io::Result<String> {
+        let reply = match packet.chars().next().unwrap_or(' ') {
+            '?' => self.stop_reply(None),
+            'g' => {
+                let machine = &self.debugger.machine;
+                let mut registers = String::new();
+                registers.push_str(&hex_bytes(machine.pc as u64 * self.word_size, 4));
+                registers.push_str(&hex_bytes(machine.stack.len() as u64, 4));
+                registers.push_str(&hex_bytes(machine.returns.len() as u64, 4));
+                registers.push_str(&hex_bytes(machine.stack.last().cloned().unwrap_or(0) as u64, 8));
+                registers
+            },
+            'p' => {
+                let machine = &self.debugger.machine;
+                match u32::from_str_radix(&packet[1..], 16) {
+                    Ok(0) => hex_bytes(machine.pc as u64 * self.word_size, 4),
+                    Ok(1) => hex_bytes(machine.stack.len() as u64, 4),
+                    Ok(2) => hex_bytes(machine.returns.len() as u64, 4),
+                    Ok(3) => hex_bytes(machine.stack.last().cloned().unwrap_or(0) as u64, 8),
+                    _ => "E01".to_string()
+                }
+            },
+            'm' => match parse_pair(&packet[1..]) {
+                Some((address, length)) => self.read_memory(address, length),
+                None => "E01".to_string()
+            },
+            'c' | 's' => {
+                if let Ok(address) = u64::from_str_radix(&packet[1..], 16) {
+                    self.debugger.machine.pc = (address / self.word_size) as u32;
+                }
+                try!(self.resume(packet.starts_with("s")))
+            },
+            'Z' | 'z' if packet[1..].starts_with("0,") || packet[1..].starts_with("1,") => match parse_pair(&packet[3..]) {
+                Some((address, _)) => {
+                    let address = (address / self.word_size) as u32;
+                    if packet.starts_with("Z") {
+                        self.debugger.breakpoints.insert(address);
+                    } else {
+                        self.debugger.breakpoints.remove(&address);
+                    }
+                    "OK".to_string()
+                },
+                None => "E01".to_string()
+            },
+            'H' | 'T' | 'D' => "OK".to_string(),
+            _ if packet.starts_with("qSupported") => "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string(),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => match parse_pair(&packet["qXfer:features:read:target.xml:".len()..]) {
+                Some((offset, length)) => {
+                    let start = (offset as usize).min(TARGET_XML.len());
+                    let end = (start + length as usize).min(TARGET_XML.len());
+                    format!("{}{}", if end == TARGET_XML.len() { "l" } else { "m" }, &TARGET_XML[start..end])
+                },
+                None => "E01".to_string()
+            },
+            _ if packet == "QStartNoAckMode" => "OK".to_string(),
+            _ if packet.starts_with("qRcmd,") => {
+                let command = String::from_utf8_lossy(&from_hex(&packet["qRcmd,".len()..])).into_owned();
+                let report = self.debugger.command(&command, self.input, self.output).unwrap_or(String::new());
+                try!(self.output.flush());
+                if !report.is_empty() {
+                    try!(self.send(&format!("O{}", to_hex(format!("{}\n", report).as_bytes()))));
+                }
+                "OK".to_string()
+            },
+            _ if packet == "vCont?" => "vCont;c;C;s;S".to_string(),
+            _ if packet.starts_with("vCont;") => {
+                let action = packet["vCont;".len()..].chars().next().unwrap_or('c');
+                try!(self.resume(action == 's' || action == 'S'))
+            },
+            _ if packet == "qAttached" => "1".to_string(),
+            _ if packet == "qC" => "QC1".to_string(),
+            _ if packet == "qfThreadInfo" => "m1".to_string(),
+            _ if packet == "qsThreadInfo" => "l".to_string(),
+            _ => String::new()
+        };
+        Ok(reply)
+    }
+
+    // Runs one instruction or until a breakpoint, and gives the stop reply.
+    fn resume(&mut self, step: bool) -> io::Result<String> {
+        let mut count = 0;
+        let event = loop {
+            match self.debugger.step(self.input, self.output) {
+                Event::Stepped if step => break Event::Stepped,
+                Event::Stepped if self.debugger.breakpoints.contains(&self.debugger.machine.pc) => break Event::Breakpoint(self.debugger.machine.pc),
+                Event::Stepped => (),
+                event => break event
+            }
+            count += 1;
+            if count % INTERRUPT_INTERVAL == 0 && try!(self.interrupted()) {
+                try!(self.output.flush());
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        };
+        try!(self.output.flush());
+        Ok(match event {
+            Event::Trapped(trap) => {
+                try!(self.send(&format!("O{}", to_hex(format!("{}\n", trap).as_bytes()))));
+                self.stop_reply(Some(&trap))
+            },
+            _ => self.stop_reply(None)
+        })
+    }
+
+    // Why the program last stopped: with a signal for a trap, with its
+    // status once it's finished, or otherwise as a `SIGTRAP`.
+    fn stop_reply(&self, trap: Option<&Trap>) -> String {
+        match self.debugger.finished {
+            Some(Stop::Halted(status)) => format!("W{:02x}", status as u8),
+            Some(_) => "W00".to_string(),
+            None => format!("S{:02x}", trap.map_or(SIGTRAP, |trap| signal(&trap.kind)))
+        }
+    }
+
+    // The bytes of memory from the byte address `address`, stopping at the
+    // end of memory.
+    fn read_memory(&self, address: u64, length: u64) -> String {
+        let memory = &self.debugger.machine.memory;
+        let mut bytes = Vec::new();
+        for byte in address..address.saturating_add(length) {
+            match memory.get((byte / self.word_size) as usize) {
+                Some(&word) => bytes.push((word as u64 >> (byte % self.word_size * 8)) as u8),
+                None => break
+            }
+        }
+        if bytes.is_empty() && length > 0 { "E01".to_string() } else { to_hex(&bytes) }
+    }
+
+    // Whether gdb has sent an interrupt, which is looked for without waiting.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        try!(self.stream.set_nonblocking(true));
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        try!(self.stream.set_nonblocking(false));
+        match result {
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error)
+        }
+    }
+
+    // Reads the next packet, acknowledging it, or gives `None` once gdb has
+    // gone. An interrupt while the program is stopped is answered with where
+    // it's stopped.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let start = match try!(self.read_byte()) {
+                Some(byte) => byte,
+                None => return Ok(None)
+            };
+            if start == 0x03 {
+                let reply = self.stop_reply(None);
+                try!(self.send(&reply));
+                continue;
+            }
+            if start != b'$' {
+                continue;
+            }
+            let mut data = Vec::new();
+            loop {
+                match try!(self.read_byte()) {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None)
+                }
+            }
+            let mut checksum = [0; 2];
+            try!(self.stream.read_exact(&mut checksum));
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok();
+            let actual = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+            if self.acks {
+                try!(self.stream.write_all(if expected == Some(actual) { b"+" } else { b"-" }));
+            }
+            if expected == Some(actual) || !self.acks {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(ref error) if error.kind() == ErrorKind::ConnectionReset => Ok(None),
+            Err(error) => Err(error)
+        }
+    }
+
+    // Sends a packet, escaping the characters that frame packets. Any
+    // acknowledgement is skipped over when the next packet is read.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::new();
+        for &byte in data.as_bytes() {
+            if byte == b'$' || byte == b'#' || byte == b'}' || byte == b'*' {
+                escaped.push(b'}');
+                escaped.push(byte ^ 0x20);
+            } else {
+                escaped.push(byte);
+            }
+        }
+        let checksum = escaped.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let mut packet = vec![b'$'];
+        packet.extend(escaped);
+        packet.extend(format!("#{:02x}", checksum).into_bytes());
+        try!(self.stream.write_all(&packet));
+        self.stream.flush()
+    }
+}
+
+fn signal(kind: &TrapKind) -> u8 {
+    match kind {
+        &TrapKind::InvalidInstruction { .. } | &TrapKind::UnsupportedOpcode { .. } => SIGILL,
+        &TrapKind::OutOfProgram | &TrapKind::BadAddress { .. } => SIGSEGV,
+        &TrapKind::DivisionByZero => SIGFPE,
+        _ => SIGABRT
+    }
+}
+
+// Parses `ADDRESS,LENGTH` in hexadecimal, as `m` and `Z` packets have them.
+fn parse_pair(text: &str) -> Option<(u64, u64)> {
+    let mut parts = text.splitn(2, ',');
+    let first = parts.next().and_then(|part| u64::from_str_radix(part, 16).ok());
+    let second = parts.next().and_then(|part| u64::from_str_radix(part.split(';').next().unwrap(), 16).ok());
+    first.and_then(|first| second.map(|second| (first, second)))
+}
+
+// The lowest `size` bytes of `value` in hexadecimal, little-endian.
+fn hex_bytes(value: u64, size: usize) -> String {
+    (0..size).map(|index| format!("{:02x}", (value >> (index * 8)) as u8)).collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Vec<u8> {
+    (0..text.len() / 2).filter_map(|index| text.get(index * 2..index * 2 + 2)).filter_map(|pair| u8::from_str_radix(pair, 16).ok()).collect()
+}
@@ -0,0 +1,98 @@
+use container::{self, ContainerError, Header};
+use debug::DebugInfo;
+use disasm;
+use encoder::Endian;
+use encoding::Profile;
+use isa::InstructionSet;
+
+// Describes bytecode in one place: its container header, the sections and
+// symbols from its debug info if there is any, and each word as bytes
+// alongside the instruction it disassembles to. Bytecode that isn't in a
+// container is read as for `disasm::read_bytecode`. The container doesn't
+// record where the words are placed, so addresses start from the first one
+// in the debug info, or from zero without it.
+pub fn inspect(bytes: &[u8], debug_info: Option<&DebugInfo>, instruction_set: &InstructionSet, endian: Endian, profile: Profile) -> Result<String, ContainerError> {
+    let bytecode = try!(disasm::read_bytecode(bytes, endian, profile));
+    let mut output = String::new();
+    let body = match bytecode.header {
+        Some(ref header) => {
+            output.push_str(&describe_header(header));
+            &bytes[header.size()..]
+        },
+        None => {
+            output.push_str("Header: none, read as raw bytecode\n");
+            bytes
+        }
+    };
+    let origin = debug_info.map_or(0, |debug_info| debug_info.origin());
+    output.push_str(&format!("Word size: {} bits, {}-endian\n", bytecode.profile.encoding().word_bits(), match bytecode.endian {
+        Endian::Little => "little",
+        Endian::Big => "big"
+    }));
+    if bytecode.trailing > 0 {
+        output.push_str(&format!("Trailing bytes: {}\n", bytecode.trailing));
+    }
+
+    output.push_str("\nSections:\n");
+    match debug_info {
+        Some(debug_info) => for &(ref name, address, size) in &debug_info.sections {
+            output.push_str(&format!("  {:<8} 0x{:08X} {:>8} words\n", name, address, size));
+        },
+        None => output.push_str("  not recorded without debug info\n")
+    }
+
+    output.push_str("\nSymbols:\n");
+    match debug_info {
+        Some(debug_info) => for &(ref name, address) in &debug_info.labels {
+            output.push_str(&format!("  0x{:08X} {}\n", address, name));
+        },
+        None => output.push_str("  not recorded without debug info\n")
+    }
+
+    output.push_str("\nContents:\n");
+    let encoding = bytecode.profile.encoding();
+    let lines = disasm::instruction_lines(&bytecode.words, origin, instruction_set, encoding);
+    for (index, (chunk, (label, text))) in body.chunks(encoding.word_size()).zip(lines).enumerate() {
+        let address = origin + index as u32;
+        if let Some(debug_info) = debug_info {
+            for &(ref name, _) in debug_info.labels.iter().filter(|&&(_, label_address)| label_address == address) {
+                output.push_str(&format!("{}:\n", name));
+            }
+        }
+        if let Some(label) = label {
+            output.push_str(&format!(":{}\n", label));
+        }
+        let hex: Vec<_> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+        output.push_str(&format!("  {:04X}  {:<23}  {}\n", address, hex.join(" "), text));
+    }
+    Ok(output)
+}
+
+fn describe_header(header: &Header) -> String {
+    let mut flags = Vec::new();
+    if header.flags & container::ENTRY != 0 {
+        flags.push("entry");
+    }
+    if header.flags & container::BIG_ENDIAN != 0 {
+        flags.push("big-endian");
+    }
+    if header.flags & container::WORD64 != 0 {
+        flags.push("word64");
+    }
+    if header.flags & container::CHECKSUM != 0 {
+        flags.push("checksum");
+    }
+    let mut output = String::from("Header:\n");
+    output.push_str(&format!("  Magic:   {}\n", String::from_utf8_lossy(container::MAGIC)));
+    output.push_str(&format!("  Version: {}\n", header.version));
+    output.push_str(&format!("  Flags:   0x{:04X} ({})\n", header.flags, if flags.is_empty() { "none".to_string() } else { flags.join(", ") }));
+    output.push_str(&format!("  Words:   {}\n", header.word_count));
+    match header.entry {
+        Some(entry) => output.push_str(&format!("  Entry:   0x{:04X}\n", entry)),
+        None => output.push_str("  Entry:   none\n")
+    }
+    if let Some(checksum) = header.checksum {
+        output.push_str(&format!("  CRC-32:  {:08X}\n", checksum));
+    }
+    output
+}
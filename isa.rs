@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// What an opcode's argument must be: nothing, any value, a stack depth from 1
+// to `MAX_DEPTH`, or a label, which is encoded as an offset relative to the
+// next instruction. A number is taken to be such an offset already.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Operand {
+    None,
+    Immediate,
+    Depth,
+    Label
+}
+
+pub const MAX_DEPTH: i64 = 255;
+
+// A number of stack values, which is `fixed`, plus the instruction's argument
+// if `argument` is set.
+#[derive(Clone, Copy, PartialEq)]
+pub struct StackCount {
+    pub fixed: u32,
+    pub argument: bool
+}
+
+#[derive(Clone)]
+pub struct Opcode {
+    pub name: String,
+    pub code: i32,
+    pub operand: Operand,
+    pub pops: StackCount,
+    pub pushes: StackCount
+}
+
+#[derive(Clone)]
+pub struct InstructionSet {
+    opcodes: Vec<Opcode>,
+    aliases: Vec<(String, String)>
+}
+
+const BUILT_IN: &'static str = include_str!("isa.csv");
+
+impl Default for InstructionSet {
+    fn default() -> InstructionSet {
+        InstructionSet::parse(BUILT_IN).expect("built-in instruction set is invalid")
+    }
+}
+
+impl InstructionSet {
+    pub fn read(path: &Path) -> Result<InstructionSet, String> {
+        let mut text = String::new();
+        try!(File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|error| format!("{}: {}", path.display(), error)));
+        InstructionSet::parse(&text).map_err(|message| format!("{}:{}", path.display(), message))
+    }
+
+    // Parses a spec in the format described at the top of isa.csv. Errors
+    // are prefixed with the line number they were found on.
+    pub fn parse(text: &str) -> Result<InstructionSet, String> {
+        let mut instruction_set = InstructionSet { opcodes: Vec::new(), aliases: Vec::new() };
+        for (index, line) in text.lines().enumerate() {
+            try!(instruction_set.parse_line(line).map_err(|message| format!("{}: {}", index + 1, message)));
+        }
+        for &(ref alias, ref opcode) in &instruction_set.aliases {
+            if instruction_set.opcode(opcode).is_none() {
+                return Err(format!("alias `{}` refers to unknown opcode `{}`", alias, opcode));
+            }
+        }
+        Ok(instruction_set)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let line = match line.find('#') {
+            Some(index) => &line[..index],
+            None => line
+        };
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        let fields: Vec<_> = line.split(',').map(|field| field.trim()).collect();
+        let name = fields[0].to_lowercase();
+        if name == "alias" {
+            if fields.len() != 3 {
+                return Err("expected `alias, name, opcode`".to_string());
+            }
+            let alias = try!(parse_name(fields[1]));
+            let opcode = try!(parse_name(fields[2]));
+            try!(self.check_unused(&alias));
+            self.aliases.push((alias, opcode));
+            return Ok(());
+        }
+        if fields.len() != 5 {
+            return Err("expected `name, code, operand, pops, pushes`".to_string());
+        }
+        let name = try!(parse_name(&name));
+        try!(self.check_unused(&name));
+        let code = try!(fields[1].parse::<i32>().ok()
+            .filter(|code| *code >= 0 && *code <= 0xffff)
+            .ok_or(format!("`{}` is not an opcode number from 0 to 65535", fields[1])));
+        let operand = match fields[2].to_lowercase().as_str() {
+            "none" => Operand::None,
+            "immediate" => Operand::Immediate,
+            "depth" => Operand::Depth,
+            "label" => Operand::Label,
+            _ => return Err(format!("`{}` is not an operand kind", fields[2]))
+        };
+        self.opcodes.push(Opcode {
+            name: name,
+            code: code,
+            operand: operand,
+            pops: try!(parse_stack_count(fields[3])),
+            pushes: try!(parse_stack_count(fields[4]))
+        });
+        Ok(())
+    }
+
+    fn check_unused(&self, name: &str) -> Result<(), String> {
+        if self.opcode(name).is_some() || self.aliases.iter().any(|&(ref alias, _)| alias == name) {
+            Err(format!("`{}` is defined more than once", name))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn add(&mut self, opcode: Opcode) -> Result<(), String> {
+        try!(self.check_unused(&opcode.name));
+        self.opcodes.push(opcode);
+        Ok(())
+    }
+
+    pub fn opcode(&self, name: &str) -> Option<&Opcode> {
+        self.opcodes.iter().find(|opcode| opcode.name == name)
+    }
+
+    // The first opcode encoded as `code`.
+    pub fn opcode_with_code(&self, code: i64) -> Option<&Opcode> {
+        self.opcodes.iter().find(|opcode| opcode.code as i64 == code)
+    }
+
+    pub fn opcodes(&self) -> &[Opcode] {
+        &self.opcodes
+    }
+
+    // The name of the opcode `name` refers to, ignoring case and resolving
+    // aliases.
+    pub fn canonical_opcode<'a>(&'a self, name: &str) -> Option<&'a str> {
+        let name = name.to_lowercase();
+        self.aliases.iter()
+            .find(|&&(ref alias, _)| *alias == name)
+            .map(|&(_, ref opcode)| opcode.as_str())
+            .or_else(|| self.opcode(&name).map(|opcode| opcode.name.as_str()))
+    }
+}
+
+fn parse_name(name: &str) -> Result<String, String> {
+    if !name.is_empty() && name.chars().all(|character| character.is_ascii_alphanumeric() || character == '_') {
+        Ok(name.to_lowercase())
+    } else {
+        Err(format!("`{}` is not a valid opcode name", name))
+    }
+}
+
+fn parse_stack_count(text: &str) -> Result<StackCount, String> {
+    let error = || format!("`{}` is not a stack count", text);
+    let (argument, fixed) = if text == "arg" {
+        (true, "0")
+    } else if text.starts_with("arg+") {
+        (true, text[4..].trim())
+    } else {
+        (false, text)
+    };
+    let fixed = try!(fixed.parse::<u32>().map_err(|_| error()));
+    Ok(StackCount { fixed: fixed, argument: argument })
+}
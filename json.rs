@@ -0,0 +1,266 @@
+use std::fmt;
+
+use writer;
+
+// A JSON value, as read by `parse` or written by its `Display`. Object
+// members are kept in the order they're written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    pub fn object(members: Vec<(&str, Json)>) -> Json {
+        Json::Object(members.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+    }
+
+    pub fn string(text: &str) -> Json {
+        Json::String(text.to_string())
+    }
+
+    // The member `name` of an object, or `Null` for anything else or a member
+    // that isn't there, so that lookups can be chained.
+    pub fn get(&self, name: &str) -> &Json {
+        match self {
+            &Json::Object(ref members) => members.iter()
+                .find(|&&(ref member, _)| member == name)
+                .map_or(&Json::Null, |&(_, ref value)| value),
+            _ => &Json::Null
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            &Json::String(ref text) => Some(text),
+            _ => None
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            &Json::Number(number) if number.fract() == 0.0 => Some(number as i64),
+            _ => None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            &Json::Bool(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            &Json::Array(ref values) => values,
+            _ => &[]
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Json::Null => write!(formatter, "null"),
+            &Json::Bool(value) => write!(formatter, "{}", value),
+            &Json::Number(number) if number.fract() == 0.0 && number.abs() < 1e15 => write!(formatter, "{}", number as i64),
+            &Json::Number(number) => write!(formatter, "{}", number),
+            &Json::String(ref text) => write!(formatter, "{}", writer::json_string(text)),
+            &Json::Array(ref values) => {
+                try!(write!(formatter, "["));
+                for (index, value) in values.iter().enumerate() {
+                    try!(write!(formatter, "{}{}", if index > 0 { "," } else { "" }, value));
+                }
+                write!(formatter, "]")
+            },
+            &Json::Object(ref members) => {
+                try!(write!(formatter, "{{"));
+                for (index, &(ref name, ref value)) in members.iter().enumerate() {
+                    try!(write!(formatter, "{}{}:{}", if index > 0 { "," } else { "" }, writer::json_string(name), value));
+                }
+                write!(formatter, "}}")
+            }
+        }
+    }
+}
+
+// Parses a whole JSON text. Errors give the byte offset they were found at.
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut parser = Parser { text: text, position: 0 };
+    let value = try!(parser.value());
+    parser.whitespace();
+    if parser.position < text.len() {
+        return Err(parser.error("expected the end of the text"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Json::String),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('n') => self.keyword("null", Json::Null),
+            Some(c) if c == '-' || c.is_digit(10) => self.number(),
+            _ => Err(self.error("expected a value"))
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.position += 1;
+        let mut members = Vec::new();
+        self.whitespace();
+        if self.eat('}') {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected a member name"));
+            }
+            let name = try!(self.string());
+            self.whitespace();
+            if !self.eat(':') {
+                return Err(self.error("expected `:`"));
+            }
+            members.push((name, try!(self.value())));
+            self.whitespace();
+            if self.eat('}') {
+                return Ok(Json::Object(members));
+            }
+            if !self.eat(',') {
+                return Err(self.error("expected `,` or `}`"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.position += 1;
+        let mut values = Vec::new();
+        self.whitespace();
+        if self.eat(']') {
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(try!(self.value()));
+            self.whitespace();
+            if self.eat(']') {
+                return Ok(Json::Array(values));
+            }
+            if !self.eat(',') {
+                return Err(self.error("expected `,` or `]`"));
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut text = String::new();
+        loop {
+            let c = try!(self.next().ok_or(self.error("unterminated string")));
+            match c {
+                '"' => return Ok(text),
+                '\\' => {
+                    let escape = try!(self.next().ok_or(self.error("unterminated string")));
+                    text.push(match escape {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => try!(self.unicode_escape()),
+                        _ => return Err(self.error("unknown escape"))
+                    });
+                },
+                c => text.push(c)
+            }
+        }
+    }
+
+    // The character after `\u`, which may be a pair of surrogates.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let first = try!(self.hex4());
+        let code = if first >= 0xD800 && first < 0xDC00 && self.text[self.position..].starts_with("\\u") {
+            self.position += 2;
+            let second = try!(self.hex4());
+            0x10000 + ((first - 0xD800) << 10) + (second.wrapping_sub(0xDC00) & 0x3FF)
+        } else {
+            first
+        };
+        Ok(::std::char::from_u32(code).unwrap_or('\u{FFFD}'))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = try!(self.text.get(self.position..self.position + 4).ok_or(self.error("expected four hexadecimal digits")));
+        let value = try!(u32::from_str_radix(digits, 16).map_err(|_| self.error("expected four hexadecimal digits")));
+        self.position += 4;
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if c.is_digit(10) || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E' {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+        self.text[start..self.position].parse().map(Json::Number).map_err(|_| format!("{}: bad number", start))
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, String> {
+        if self.text[self.position..].starts_with(keyword) {
+            self.position += keyword.len();
+            Ok(value)
+        } else {
+            Err(self.error("expected a value"))
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while self.peek().map_or(false, |c| c == ' ' || c == '\t' || c == '\n' || c == '\r') {
+            self.position += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.position += c.map_or(0, |c| c.len_utf8());
+        c
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{}: {}", self.position, message)
+    }
+}
@@ -0,0 +1,296 @@
+mod error;
+mod literals;
+mod expression;
+mod parser;
+mod symbols;
+mod resolver;
+mod encoder;
+mod isa;
+mod extension;
+mod container;
+mod encoding;
+mod writer;
+mod debug;
+mod disasm;
+mod inspect;
+mod size;
+mod verify;
+mod diff;
+mod object;
+mod archive;
+mod nm;
+mod suggestions;
+mod options;
+mod sources;
+mod preprocessor;
+mod pseudo;
+mod optimizer;
+mod lint;
+mod stack;
+mod cfg;
+mod tags;
+mod vm;
+mod debugger;
+mod tui;
+mod json;
+mod dap;
+mod gdb;
+
+use std::borrow::Cow;
+use std::ops::Range;
+use std::path::Path;
+
+use preprocessor::LogicalLine;
+use resolver::Word;
+use sources::{SourceFile, Sources};
+
+pub use error::{QuasmError, Diagnostic, DiagnosticKind, Location};
+pub use options::Options;
+pub use parser::Section;
+pub use isa::{InstructionSet, Opcode, Operand, StackCount};
+pub use extension::OpcodeExtension;
+pub use container::{Header, ContainerError, read_words};
+pub use encoder::Endian;
+pub use encoding::{Encoding, Profile};
+pub use writer::{Format, OutputOptions, read_base64, read_hex};
+pub use debug::DebugInfo;
+pub use disasm::{Bytecode, disassemble, disassemble_source, is_text_bytecode, read_bytecode};
+pub use inspect::inspect;
+pub use size::size;
+pub use verify::{Violation, verify};
+pub use diff::{Change, ChangeKind, diff, diff_json, diff_text};
+pub use object::{Object, ObjectSection, Relocation, RelocationKind, RelocationTarget, check_position_independent, link};
+pub use archive::{Archive, select_members};
+pub use nm::nm;
+pub use optimizer::{OptimizationLevel, Pass};
+pub use lint::{Level, Lints, Rule};
+pub use tags::{ctags, etags};
+pub use vm::{MEMORY_WORDS, HostFunction, Machine, Stop, Trap, TrapKind};
+pub use debugger::{Debugger, Event};
+pub use tui::Tui;
+pub use json::Json;
+pub use dap::serve_dap;
+pub use gdb::serve_gdb;
+
+pub struct Program {
+    pub bytes: Vec<u8>,
+    // What each word was assembled from, in output order.
+    pub words: Vec<WordInfo>,
+    // Every line after preprocessing, in output order.
+    pub lines: Vec<LineInfo>,
+    // The address given by `.start`, if any.
+    pub entry: Option<u32>,
+    // The address of the first word.
+    pub origin: u32,
+    // The address and number of words of each run of zeros filled in by a
+    // `.org`.
+    pub gaps: Vec<(u32, u32)>,
+    // Every named label, in address order.
+    pub labels: Vec<Label>,
+    // Each section that has any words, in output order.
+    pub sections: Vec<Region>,
+    // Each `.func`, in address order within each section.
+    pub functions: Vec<Region>,
+    // Each word that depends on where a label ends up, if the program was
+    // assembled as an object.
+    pub relocations: Vec<Relocation>,
+    pub endian: Endian,
+    pub profile: Profile,
+    pub warnings: Vec<Diagnostic>
+}
+
+pub struct WordInfo {
+    pub address: u32,
+    pub encoded: i64,
+    // The opcode and argument, if the word is an instruction.
+    pub instruction: Option<(String, i64)>,
+    // The start of the line the word was assembled from, and its text after
+    // any macros were expanded.
+    pub location: Location,
+    pub source: String
+}
+
+// Local labels are named after the label they're scoped to, as in
+// `loop.done`.
+pub struct Label {
+    pub name: String,
+    pub address: u32,
+    pub section: Section,
+    // Whether an object exports the label, as given by `.global`.
+    pub global: bool
+}
+
+// A section or function, with the address and number of words it spans. A
+// section's name is its directive, as in `.text`.
+pub struct Region {
+    pub name: String,
+    pub section: Section,
+    pub address: u32,
+    pub size: u32
+}
+
+// A line after any macros were expanded, with the address it's placed at and
+// the indices of the words it assembled to.
+pub struct LineInfo {
+    pub address: u32,
+    pub location: Location,
+    pub source: String,
+    pub words: Range<usize>
+}
+
+impl Program {
+    // The program as it's written to a file.
+    pub fn output(&self, options: &OutputOptions) -> Result<Vec<u8>, QuasmError> {
+        writer::write(self, options)
+    }
+
+    // Each label with its address and section.
+    pub fn symbols(&self) -> String {
+        writer::symbols(self)
+    }
+
+    // Each section and function with its address, size and labels.
+    pub fn map(&self) -> String {
+        writer::map(self)
+    }
+
+    // The source location of each word, and the labels, for the VM and
+    // debugger.
+    pub fn debug_info(&self) -> DebugInfo {
+        DebugInfo::new(self)
+    }
+
+    // The program as an object to be linked with others, which is only
+    // complete if it was assembled with `Options::object`.
+    pub fn object(&self) -> Object {
+        Object::new(self)
+    }
+
+    // Each line with its address and the words it assembled to.
+    pub fn listing(&self) -> String {
+        writer::listing(self)
+    }
+
+    // The control-flow graph of the instructions, for Graphviz.
+    pub fn cfg(&self, instruction_set: &InstructionSet) -> String {
+        cfg::dot(self, instruction_set)
+    }
+
+    // Counts of the instructions and labels, and the program's size.
+    pub fn stats(&self, instruction_set: &InstructionSet) -> String {
+        writer::stats(self, instruction_set)
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Program, QuasmError> {
+    assemble_with_options(source, &Options::default())
+}
+
+// Assembles source that isn't backed by a file. Any `.include` paths are
+// relative to the working directory.
+pub fn assemble_with_options(source: &str, options: &Options) -> Result<Program, QuasmError> {
+    assemble_source_file(SourceFile::from_string(source.to_string()), options)
+}
+
+// Assembles the file at `path`. Any `.include` paths are relative to the
+// directory of the file containing the directive.
+pub fn assemble_file(path: &Path, options: &Options) -> Result<Program, QuasmError> {
+    let source_file = try!(SourceFile::read(path));
+    assemble_source_file(source_file, options)
+}
+
+fn assemble_source_file(source_file: SourceFile, options: &Options) -> Result<Program, QuasmError> {
+    let mut instruction_set = options.instruction_set.clone();
+    for extension in &options.extensions {
+        for opcode in extension.opcodes() {
+            try!(instruction_set.add(opcode).map_err(QuasmError::Extension));
+        }
+    }
+    let mut diagnostics = Vec::new();
+    let (sources, line_refs) = Sources::load(source_file, &mut diagnostics);
+    let logical_lines = line_refs.iter()
+        .map(|line| LogicalLine { file: sources.name(line), line_number: line.line_number, text: Cow::Borrowed(sources.text(line)) })
+        .collect();
+    let logical_lines = preprocessor::preprocess(logical_lines, options, &mut diagnostics);
+    let lines = logical_lines.iter()
+        .map(|line| parser::parse_line(line.file, line.line_number, &line.text, &options.extensions, &mut diagnostics))
+        .collect();
+    let lines = pseudo::expand(lines, &instruction_set, &mut diagnostics);
+    if options.warn_unreachable {
+        lint::check_unreachable(&lines, &mut diagnostics);
+    }
+    if options.warn_unused_labels {
+        lint::check_unused_labels(&lines, &mut diagnostics);
+    }
+    stack::check_stack(&lines, &instruction_set, options.warn_stack, &mut diagnostics);
+    if let Some(ref lints) = options.lints {
+        lint::lint(&lines, &instruction_set, lints, &mut diagnostics);
+    }
+    let lines = optimizer::optimize(lines, &instruction_set, options);
+    let resolved = resolver::resolve(lines, &instruction_set, options, &mut diagnostics);
+    let instructions: Vec<_> = resolved.words.iter()
+        .map(|word| match word {
+            &Word::Instruction(ref instruction) => Some((instruction.opcode.to_string(), instruction.arg)),
+            &Word::Data(_) => None
+        })
+        .collect();
+    let bytecodes: Vec<_> = resolved.words.into_iter()
+        .map(|word| encoder::encode_word(word, &instruction_set, &options.extensions, options.profile.encoding(), &mut diagnostics))
+        .collect();
+    let origin = resolved.origin;
+    let words = bytecodes.iter().zip(instructions).zip(resolved.sources).enumerate()
+        .map(|(index, ((&encoded, instruction), (location, source)))| WordInfo {
+            address: origin as u32 + index as u32,
+            encoded: encoded,
+            instruction: instruction,
+            location: location,
+            source: source.to_string()
+        })
+        .collect();
+    diagnostics.sort_by_key(|diagnostic| {
+        let file = sources.files.iter().position(|file| file.name == diagnostic.location.file);
+        (file, diagnostic.location.line_number, diagnostic.location.column)
+    });
+    if options.deny_warnings {
+        for diagnostic in &mut diagnostics {
+            diagnostic.denied = diagnostic.is_warning();
+        }
+    }
+    if diagnostics.iter().all(|diagnostic| diagnostic.is_warning()) {
+        Ok(Program {
+            bytes: encoder::to_bytes(bytecodes, options.profile.encoding().word_size(), options.endian),
+            words: words,
+            lines: resolved.lines.into_iter()
+                .map(|(location, source, address, words)| LineInfo {
+                    address: address as u32,
+                    location: location,
+                    source: source.to_string(),
+                    words: words
+                })
+                .collect(),
+            entry: resolved.entry.map(|entry| entry as u32),
+            origin: resolved.origin as u32,
+            gaps: resolved.gaps.into_iter().map(|(address, size)| (address as u32, size as u32)).collect(),
+            labels: resolved.labels.into_iter()
+                .map(|(name, address, section, global)| Label { name: name, address: address as u32, section: section, global: global })
+                .collect(),
+            sections: resolved.sections.into_iter()
+                .map(|(section, address, size)| Region {
+                    name: format!(".{}", section.name()),
+                    section: section,
+                    address: address as u32,
+                    size: size as u32
+                })
+                .collect(),
+            functions: resolved.functions.into_iter()
+                .map(|(name, section, address, size)| Region { name: name, section: section, address: address as u32, size: size as u32 })
+                .collect(),
+            relocations: resolved.relocations,
+            endian: options.endian,
+            profile: options.profile,
+            warnings: diagnostics
+        })
+    } else {
+        Err(QuasmError::Invalid(diagnostics))
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::{HashMap, HashSet};
+
+use error::{Diagnostic, DiagnosticKind, Location};
+use expression;
+use isa::{InstructionSet, Operand};
+use optimizer::{self, Targets};
+use parser::{self, Argument, Line, Section, SourceLine};
+use resolver;
+use symbols;
+
+// The most instructions a `.func` can have before `long-function` warns.
+const LONG_FUNCTION: usize = 64;
+
+// The deepest a `swap` can reach before `deep-swap` warns.
+const DEEP_SWAP: i64 = 2;
+
+// A rule checked by `quasm lint`, which can be allowed or denied by name.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Rule {
+    // A label nothing refers to.
+    UnusedLabel,
+    // A number other than 0, 1 or -1 written as the value of a `const`,
+    // `loadi` or `storei` rather than named with `.equ`.
+    MagicNumber,
+    // A `swap` deeper than the top few values, which is hard to follow.
+    DeepSwap,
+    // A `.func` with more instructions than is easy to follow.
+    LongFunction,
+    // Execution running off the end of the program.
+    MissingHalt
+}
+
+// What `quasm lint` does when a rule is broken.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny
+}
+
+// The level of each rule, which is `Level::Warn` unless it's been set.
+#[derive(Clone, Default)]
+pub struct Lints {
+    levels: HashMap<Rule, Level>
+}
+
+// Warns at the start of each run of instructions in `.text` that follows a
+// `halt`, `jmp` or `ret` with no label in between, and so can never run. This
+// looks at the source as written, so it warns even if the instructions are
+// then optimized away.
+pub fn check_unreachable<'a>(lines: &[SourceLine<'a>], diagnostics: &mut Vec<Diagnostic>) {
+    let sections = resolver::line_sections(lines);
+    // The terminator the current instruction follows, and whether it's
+    // already been warned about.
+    let mut after: Option<&str> = None;
+    let mut warned = false;
+    for (line, _) in lines.iter().zip(sections).filter(|&(_, section)| section == Section::Text) {
+        if line.label.is_some() {
+            after = None;
+            warned = false;
+        }
+        if let Line::Instruction { opcode, token, .. } = line.line {
+            match after {
+                Some(terminator) if !warned => {
+                    diagnostics.push(Diagnostic::new(
+                        line.location(token),
+                        DiagnosticKind::Unreachable { after: terminator.to_string() }));
+                    warned = true;
+                },
+                Some(_) => (),
+                None => if optimizer::is_terminator(line) {
+                    after = Some(opcode);
+                }
+            }
+        }
+    }
+}
+
+// Warns about each named label that nothing refers to. Labels exported with
+// `.global` are used by whatever the program is linked with, so they're never
+// warned about.
+pub fn check_unused_labels<'a>(lines: &[SourceLine<'a>], diagnostics: &mut Vec<Diagnostic>) {
+    let scopes = resolver::label_scopes(lines);
+    let targets = Targets::new(lines, &scopes);
+    let mut used = HashSet::new();
+    for (index, line) in lines.iter().enumerate() {
+        let names = match line.line {
+            Line::Global(name) => vec![name],
+            _ => optimizer::line_expressions(line).into_iter().flat_map(|expression| expression::labels(expression)).collect()
+        };
+        used.extend(names.into_iter().filter_map(|name| targets.find(name, &scopes[index])));
+    }
+    for (index, line) in lines.iter().enumerate() {
+        if let Some(label) = line.label {
+            if !symbols::is_anonymous_label(label) && !used.contains(&index) {
+                diagnostics.push(Diagnostic::new(line.location(label), DiagnosticKind::UnusedLabel { label: label.to_string() }));
+            }
+        }
+    }
+}
+
+impl Rule {
+    pub fn all() -> &'static [Rule] {
+        &[Rule::UnusedLabel, Rule::MagicNumber, Rule::DeepSwap, Rule::LongFunction, Rule::MissingHalt]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Rule::UnusedLabel => "unused-label",
+            &Rule::MagicNumber => "magic-number",
+            &Rule::DeepSwap => "deep-swap",
+            &Rule::LongFunction => "long-function",
+            &Rule::MissingHalt => "missing-halt"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Rule> {
+        Rule::all().iter().cloned().find(|rule| rule.name() == name)
+    }
+}
+
+impl Lints {
+    pub fn set(&mut self, rule: Rule, level: Level) {
+        self.levels.insert(rule, level);
+    }
+
+    pub fn level(&self, rule: Rule) -> Level {
+        self.levels.get(&rule).cloned().unwrap_or(Level::Warn)
+    }
+}
+
+// Checks each rule that isn't allowed, reporting it as a warning or, if it's
+// denied, an error. A comment such as `; lint:allow(magic-number)` allows the
+// rules it names on its line, or on the next line with anything else on it
+// if it's on a line of its own.
+pub fn lint<'a>(lines: &[SourceLine<'a>], instruction_set: &InstructionSet, lints: &Lints, diagnostics: &mut Vec<Diagnostic>) {
+    let mut found = Vec::new();
+    let mut unused_labels = Vec::new();
+    check_unused_labels(lines, &mut unused_labels);
+    found.extend(unused_labels.into_iter().map(|diagnostic| (Rule::UnusedLabel, diagnostic)));
+    let mut endings = Vec::new();
+    resolver::check_ending(lines, instruction_set, false, &mut endings);
+    found.extend(endings.into_iter().map(|diagnostic| (Rule::MissingHalt, diagnostic)));
+    check_instructions(lines, instruction_set, &mut found);
+    check_function_lengths(lines, &mut found);
+
+    let allowed = allowed_rules(lines);
+    for (rule, diagnostic) in found {
+        let level = lints.level(rule);
+        let allowed_here = allowed.get(&(diagnostic.location.file.clone(), diagnostic.location.line_number))
+            .map_or(false, |rules| rules.contains(&rule));
+        if level == Level::Allow || allowed_here {
+            continue;
+        }
+        diagnostics.push(Diagnostic::new(diagnostic.location, DiagnosticKind::Lint {
+            rule: rule.name().to_string(),
+            message: diagnostic.kind.to_string(),
+            denied: level == Level::Deny
+        }));
+    }
+}
+
+fn check_instructions<'a>(lines: &[SourceLine<'a>], instruction_set: &InstructionSet, found: &mut Vec<(Rule, Diagnostic)>) {
+    for line in lines {
+        let (opcode, arg) = match line.line {
+            Line::Instruction { opcode, ref arg, .. } => (opcode, arg),
+            _ => continue
+        };
+        let (expression, text) = match arg {
+            &Argument::Expression { ref expression, text } => (expression, text),
+            _ => continue
+        };
+        let value = match optimizer::literal(expression) {
+            // Character literals say what they are.
+            Some(value) if !text.starts_with("'") => value,
+            _ => continue
+        };
+        let operand = instruction_set.opcode(opcode).map(|opcode| opcode.operand);
+        if ["const", "loadi", "storei"].contains(&opcode) && (value < -1 || value > 1) {
+            found.push((Rule::MagicNumber, Diagnostic::new(line.location(text), DiagnosticKind::MagicNumber { value: value })));
+        } else if operand == Some(Operand::Depth) && value > DEEP_SWAP {
+            found.push((Rule::DeepSwap, Diagnostic::new(line.location(text), DiagnosticKind::DeepSwap { opcode: opcode.to_string(), depth: value })));
+        }
+    }
+}
+
+fn check_function_lengths<'a>(lines: &[SourceLine<'a>], found: &mut Vec<(Rule, Diagnostic)>) {
+    let mut open: Option<(&SourceLine<'a>, &str, usize)> = None;
+    for line in lines {
+        match line.line {
+            Line::Function { name, .. } => open = Some((line, name, 0)),
+            Line::Instruction {..} => if let Some((_, _, ref mut count)) = open {
+                *count += 1;
+            },
+            Line::EndFunction {..} => if let Some((function_line, name, count)) = open.take() {
+                if count > LONG_FUNCTION {
+                    found.push((Rule::LongFunction, Diagnostic::new(
+                        function_line.location(name),
+                        DiagnosticKind::LongFunction { name: name.to_string(), instructions: count, limit: LONG_FUNCTION })));
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+// The rules allowed by `lint:allow` comments on each line, by file and line
+// number.
+fn allowed_rules<'a>(lines: &[SourceLine<'a>]) -> HashMap<(Option<String>, usize), Vec<Rule>> {
+    let mut allowed: HashMap<(Option<String>, usize), Vec<Rule>> = HashMap::new();
+    let mut pending: Vec<Rule> = Vec::new();
+    for line in lines {
+        let location: Location = line.location(line.text);
+        let key = (location.file, location.line_number);
+        let rules = parser::line_comment(line.text).map_or(Vec::new(), allow_comment);
+        let has_code = !parser::tokenize(line.text).is_empty();
+        if has_code {
+            let entry = allowed.entry(key).or_insert(Vec::new());
+            entry.extend(pending.drain(..));
+            entry.extend(rules);
+        } else {
+            pending.extend(rules);
+        }
+    }
+    allowed
+}
+
+// The rules named by a `lint:allow(rule, ...)` comment.
+fn allow_comment(comment: &str) -> Vec<Rule> {
+    if !comment.starts_with("lint:allow(") || !comment.ends_with(")") {
+        return Vec::new();
+    }
+    comment["lint:allow(".len()..comment.len() - 1].split(',')
+        .filter_map(|name| Rule::from_name(name.trim()))
+        .collect()
+}
@@ -0,0 +1,104 @@
+pub enum LiteralError {
+    Malformed,
+    OutOfRange
+}
+
+// Literals are plain integers, range checked once the argument they appear
+// in has been evaluated, and only out of range here if they don't fit in 64
+// bits. A hexadecimal, binary or octal literal on its own as an immediate is
+// a bit pattern, as `is_bit_pattern` describes.
+pub fn parse_integer(literal: &str) -> Result<i64, LiteralError> {
+    if literal.starts_with("'") {
+        return parse_character(literal);
+    }
+    if !literal.starts_with(|c: char| c.is_digit(10)) {
+        return Err(LiteralError::Malformed);
+    }
+    let (radix, digits) = split_radix(literal);
+    // Underscores are digit separators, as in `1_000` or `0xFF_FF`, and are
+    // only allowed between two digits.
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return Err(LiteralError::Malformed);
+    }
+    let digits: String = digits.chars().filter(|&c| c != '_').collect();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(LiteralError::Malformed);
+    }
+    i64::from_str_radix(&digits, radix).map_err(|_| LiteralError::OutOfRange)
+}
+
+// Whether `text` is just a hexadecimal, binary or octal literal, which as an
+// immediate is taken to be the argument's bits, so that `const 0xFFFF` is
+// `const -1` with 16-bit arguments.
+pub fn is_bit_pattern(text: &str) -> bool {
+    let text = text.trim();
+    split_radix(text).0 != 10 && parse_integer(text).is_ok()
+}
+
+fn split_radix(literal: &str) -> (u32, &str) {
+    let prefix = literal.get(..2).map(|prefix| prefix.to_lowercase());
+    match prefix.as_ref().map(|prefix| prefix.as_str()) {
+        Some("0x") => (16, &literal[2..]),
+        Some("0b") => (2, &literal[2..]),
+        Some("0o") => (8, &literal[2..]),
+        _ => (10, literal)
+    }
+}
+
+fn parse_character(literal: &str) -> Result<i64, LiteralError> {
+    if literal.len() >= 4 && literal.starts_with("'\\") && literal.ends_with("'") {
+        return match unescape(&literal[1..literal.len() - 1]) {
+            Ok(ref bytes) if bytes.len() == 1 => Ok(bytes[0] as i64),
+            _ => Err(LiteralError::Malformed)
+        };
+    }
+    let mut chars = literal.chars();
+    match (chars.next(), chars.next(), chars.next(), chars.next()) {
+        (Some('\''), Some(c), Some('\''), None) if c != '\'' => Ok(c as i64),
+        _ => Err(LiteralError::Malformed)
+    }
+}
+
+// Decodes the escapes `\n`, `\t`, `\r`, `\0`, `\\`, `\'`, `\"` and `\xNN`,
+// encoding everything else as UTF-8. A malformed escape is given as its byte
+// range within `text`.
+pub fn unescape(text: &str) -> Result<Vec<u8>, (usize, usize)> {
+    let mut bytes = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some((_, 'n')) => Some(b'\n'),
+            Some((_, 't')) => Some(b'\t'),
+            Some((_, 'r')) => Some(b'\r'),
+            Some((_, '0')) => Some(0),
+            Some((_, '\\')) => Some(b'\\'),
+            Some((_, '\'')) => Some(b'\''),
+            Some((_, '"')) => Some(b'"'),
+            Some((_, 'x')) => {
+                let mut digits = String::new();
+                while digits.len() < 2 {
+                    match chars.peek() {
+                        Some(&(_, digit)) if digit.is_digit(16) => digits.push(digit),
+                        _ => break
+                    }
+                    chars.next();
+                }
+                if digits.len() == 2 { u8::from_str_radix(&digits, 16).ok() } else { None }
+            },
+            _ => None
+        };
+        match byte {
+            Some(byte) => bytes.push(byte),
+            None => {
+                let end = chars.peek().map(|&(index, _)| index).unwrap_or(text.len());
+                return Err((start, end));
+            }
+        }
+    }
+    Ok(bytes)
+}
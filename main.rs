@@ -1,183 +1,1158 @@
+extern crate quasm;
+
 use std::env;
 use std::fs::File;
 use std::io;
+use std::process;
 use std::io::BufRead;
+use std::io::Read;
 use std::io::Write;
-use std::io::BufReader;
+use std::io::IsTerminal;
 use std::io::BufWriter;
-use std::collections::HashMap;
-use std::ops::RangeFrom;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use quasm::{Archive, DebugInfo, Debugger, Diagnostic, Endian, Format, InstructionSet, Json, Level, Lints, Machine, Object, OptimizationLevel, Options, OutputOptions, Pass, Profile, QuasmError, Rule, Stop, Tui};
+
+const USAGE: &'static str = "usage: quasm [--color auto|always|never] [--message-format human|json] [-D NAME=VALUE]... [--warn-fall-through] [--strict] [--deny-warnings] [--warn-unreachable] [--warn-unused-labels] [--warn-stack] [--object] [--pic] [-O0|-O1|-O2|-Os] [--opt=[no-]PASS]... [--no-opt] [--isa-spec PATH] [--format FORMAT] [--base-address ADDRESS] [--address-bits 16|24|32] [--address-markers] [--array-name NAME] [--label-constants] [--no-checksum] [--listing PATH] [--symbols PATH] [--map PATH] [--debug-info PATH] [--stats] [--raw] [--endian little|big] [--profile word32|word64] SOURCE DESTINATION
+       quasm disasm [--round-trip] [--isa-spec PATH] [--base-address ADDRESS] [--endian little|big] [--profile word32|word64] INPUT
+       quasm inspect [--debug-info PATH] [--isa-spec PATH] [--endian little|big] [--profile word32|word64] INPUT
+       quasm verify [--debug-info PATH] [--isa-spec PATH] [--endian little|big] [--profile word32|word64] INPUT
+       quasm size [--debug-info PATH] [--endian little|big] [--profile word32|word64] INPUT
+       quasm debug [--tui] [--debug-info PATH] [--memory-size WORDS] [--isa-spec PATH] [--base-address ADDRESS] [--endian little|big] [--profile word32|word64] INPUT|SOURCE
+       quasm run [--gdb [HOST]:PORT] [--memory-size WORDS] [--max-steps N] [--timeout SECONDS] [--trace] [--trace-file PATH] [--trace-depth N] [--isa-spec PATH] [--base-address ADDRESS] [--endian little|big] [--profile word32|word64] INPUT|SOURCE
+       quasm diff [--json] [--isa-spec PATH] [--endian little|big] [--profile word32|word64] OLD NEW
+       quasm link [--pic] [--format FORMAT] [--no-checksum] [--symbols PATH] [--map PATH] -o DESTINATION OBJECT|ARCHIVE...
+       quasm ar ARCHIVE OBJECT...
+       quasm nm OBJECT|ARCHIVE...
+       quasm tags [-e] [-o DESTINATION] SOURCE...
+       quasm dap
+       quasm check [--color auto|always|never] [--message-format human|json] [-D NAME=VALUE]... [--warn-fall-through] [--strict] [--deny-warnings] [--warn-unused-labels] [--warn-stack] [--object] [--pic] [--isa-spec PATH] [--profile word32|word64] SOURCE
+       quasm lint [--color auto|always|never] [--message-format human|json] [--allow RULE]... [--deny RULE]... [--deny-warnings] [-D NAME=VALUE]... [--isa-spec PATH] [--profile word32|word64] SOURCE
+       quasm cfg [--color auto|always|never] [--message-format human|json] [-D NAME=VALUE]... [-O0|-O1|-O2|-Os] [--opt=[no-]PASS]... [--isa-spec PATH] [--profile word32|word64] SOURCE -o DESTINATION";
+
+// The exit status of a program that `run` stops before it finishes, which is
+// the one `timeout` uses.
+const EXIT_STOPPED: i32 = 124;
+
+struct Arguments {
+    source_path: String,
+    // Empty for `check`, which doesn't write anything.
+    destination_path: String,
+    options: Options,
+    output: OutputOptions,
+    listing_path: Option<String>,
+    symbols_path: Option<String>,
+    map_path: Option<String>,
+    debug_info_path: Option<String>,
+    // Print counts of what the program is made of.
+    stats: bool,
+    messages: MessageFormat
+}
+
+// How diagnostics are reported: rendered for people on stderr, in color if
+// asked for with `--color`, or as JSON on stdout with one object a line.
+#[derive(Clone, Copy)]
+enum MessageFormat {
+    Human { color: bool },
+    Json
+}
+
+// The subcommands that read bytecode rather than assembling it.
+#[derive(Clone, Copy, PartialEq)]
+enum Tool {
+    Disassemble,
+    Inspect,
+    Verify,
+    Diff,
+    Size,
+    Run,
+    Debug
+}
+
+struct ToolArguments {
+    input_path: String,
+    // The program `input_path` is compared with, for `diff`.
+    new_path: Option<String>,
+    instruction_set: InstructionSet,
+    origin: u32,
+    endian: Endian,
+    profile: Profile,
+    round_trip: bool,
+    debug_info_path: Option<String>,
+    json: bool,
+    // Where `run` traces each step, if it does: stderr, unless given a path.
+    trace: Option<Option<String>>,
+    trace_depth: usize,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    memory_size: u32,
+    // Debug in a full-screen view rather than on the command line.
+    tui: bool,
+    // Where `run` waits for gdb to connect, if it does.
+    gdb: Option<String>
+}
+
+struct LinkArguments {
+    object_paths: Vec<String>,
+    destination_path: String,
+    output: OutputOptions,
+    symbols_path: Option<String>,
+    map_path: Option<String>,
+    pic: bool
+}
 
 fn main() -> () {
-    println!("Hello, world!");
+    let arguments: Vec<_> = env::args().skip(1).collect();
+    let tool = match arguments.first().map(|argument| argument.as_str()) {
+        Some("disasm") => Some(Tool::Disassemble),
+        Some("inspect") => Some(Tool::Inspect),
+        Some("verify") => Some(Tool::Verify),
+        Some("diff") => Some(Tool::Diff),
+        Some("size") => Some(Tool::Size),
+        Some("run") => Some(Tool::Run),
+        Some("debug") => Some(Tool::Debug),
+        _ => None
+    };
+    if let Some(tool) = tool {
+        tool_main(tool, &arguments[1..]);
+        return;
+    }
+    match arguments.first().map(|argument| argument.as_str()) {
+        Some("link") => {
+            link_main(&arguments[1..]);
+            return;
+        },
+        Some("ar") => {
+            archive_main(&arguments[1..]);
+            return;
+        },
+        Some("nm") => {
+            nm_main(&arguments[1..]);
+            return;
+        },
+        Some("tags") => {
+            tags_main(&arguments[1..]);
+            return;
+        },
+        Some("cfg") => {
+            cfg_main(&arguments[1..]);
+            return;
+        },
+        Some("check") => {
+            check_main(&arguments[1..]);
+            return;
+        },
+        Some("lint") => {
+            lint_main(&arguments[1..]);
+            return;
+        },
+        Some("dap") => {
+            dap_main(&arguments[1..]);
+            return;
+        },
+        _ => ()
+    }
 
-    let arguments: Vec<_> = env::args().collect();
-    let (source_path, destination_path) = match &arguments[..] {
-        [_, ref source_path, ref destination_path, ..] => (source_path, destination_path),
-        _ => panic!("Not enough arguments")
+    let arguments = match parse_arguments(&arguments, true) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            writeln!(io::stderr(), "{}\n{}", message, USAGE).unwrap();
+            process::exit(2)
+        }
     };
+    // With JSON messages, stdout only has diagnostics, a line each.
+    let human = match arguments.messages {
+        MessageFormat::Human { .. } => true,
+        MessageFormat::Json => false
+    };
+
+    if human {
+        println!("Hello, world!");
+    }
 
-    match compile(source_path, destination_path) {
-        Ok(_) => println!("Finished"),
-        Err(e) => println!("{}", e)
+    match compile(&arguments) {
+        Ok(_) if human => println!("Finished"),
+        Ok(_) => (),
+        Err(e) => {
+            report_error(&e, arguments.messages);
+            process::exit(1)
+        }
     }
 }
 
-enum Line<'a> {
-    Instruction { opcode: &'a str, arg: Argument<'a> },
-    Label { name: &'a str }
+fn tool_main(tool: Tool, arguments: &[String]) {
+    let arguments = match parse_tool_arguments(tool, arguments) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            writeln!(io::stderr(), "{}\n{}", message, USAGE).unwrap();
+            process::exit(2)
+        }
+    };
+
+    let output = match tool {
+        Tool::Disassemble => disassemble(&arguments),
+        Tool::Inspect => inspect(&arguments),
+        Tool::Verify => verify(&arguments),
+        Tool::Diff => diff(&arguments),
+        Tool::Size => size(&arguments),
+        Tool::Run => return run(&arguments),
+        Tool::Debug => return debug(&arguments)
+    };
+    match output {
+        Ok(output) => print!("{}", output),
+        Err(message) => {
+            writeln!(io::stderr(), "{}", message).unwrap();
+            process::exit(1)
+        }
+    }
 }
 
-enum Argument<'a> {
-    Integer(i16),
-    Label(&'a str),
-    None
+fn parse_tool_arguments(tool: Tool, arguments: &[String]) -> Result<ToolArguments, String> {
+    let mut paths = Vec::new();
+    let mut instruction_set = InstructionSet::default();
+    let mut origin = 0;
+    let mut endian = Endian::default();
+    let mut profile = Profile::default();
+    let mut round_trip = false;
+    let mut debug_info_path = None;
+    let mut json = false;
+    let mut trace = None;
+    let mut trace_depth = 4;
+    let mut max_steps = None;
+    let mut timeout = None;
+    let mut memory_size = quasm::MEMORY_WORDS;
+    let mut tui = false;
+    let mut gdb = None;
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        if argument == "--isa-spec" {
+            let path = try!(arguments.next().ok_or("--isa-spec requires a path".to_string()));
+            instruction_set = try!(InstructionSet::read(Path::new(path)));
+        } else if argument == "--base-address" && (tool == Tool::Disassemble || tool == Tool::Run || tool == Tool::Debug) {
+            origin = try!(arguments.next()
+                .and_then(|address| parse_address(address))
+                .ok_or("--base-address requires an address".to_string()));
+        } else if argument == "--endian" {
+            endian = try!(parse_endian(arguments.next()));
+        } else if argument == "--profile" {
+            profile = try!(parse_profile(arguments.next()));
+        } else if argument == "--round-trip" && tool == Tool::Disassemble {
+            round_trip = true;
+        } else if argument == "--json" && tool == Tool::Diff {
+            json = true;
+        } else if argument == "--trace" && tool == Tool::Run {
+            trace = Some(trace.unwrap_or(None));
+        } else if argument == "--trace-file" && tool == Tool::Run {
+            let path = try!(arguments.next().ok_or("--trace-file requires a path".to_string()));
+            trace = Some(Some(path.clone()));
+        } else if argument == "--trace-depth" && tool == Tool::Run {
+            trace_depth = try!(arguments.next()
+                .and_then(|depth| depth.parse().ok())
+                .ok_or("--trace-depth requires a number of values".to_string()));
+        } else if argument == "--memory-size" && (tool == Tool::Run || tool == Tool::Debug) {
+            memory_size = try!(arguments.next()
+                .and_then(|size| parse_address(size))
+                .ok_or("--memory-size requires a number of words".to_string()));
+        } else if argument == "--tui" && tool == Tool::Debug {
+            tui = true;
+        } else if argument == "--gdb" && tool == Tool::Run {
+            gdb = Some(try!(arguments.next().ok_or("--gdb requires an address such as `:1234`".to_string())).clone());
+        } else if argument == "--max-steps" && tool == Tool::Run {
+            max_steps = Some(try!(arguments.next()
+                .and_then(|steps| steps.parse().ok())
+                .ok_or("--max-steps requires a number of steps".to_string())));
+        } else if argument == "--timeout" && tool == Tool::Run {
+            let seconds: f64 = try!(arguments.next()
+                .and_then(|seconds| seconds.parse().ok())
+                .filter(|&seconds: &f64| seconds >= 0.0 && seconds.is_finite())
+                .ok_or("--timeout requires a number of seconds".to_string()));
+            timeout = Some(Duration::from_secs_f64(seconds));
+        } else if argument == "--debug-info" && (tool == Tool::Inspect || tool == Tool::Verify || tool == Tool::Size || tool == Tool::Debug) {
+            let path = try!(arguments.next().ok_or("--debug-info requires a path".to_string()));
+            debug_info_path = Some(path.clone());
+        } else if argument.starts_with("-") {
+            return Err(format!("unknown option `{}`", argument));
+        } else {
+            paths.push(argument.clone());
+        }
+    }
+    // Addresses are given as arguments, so there's no more memory than an
+    // argument can reach.
+    let addressable = 1u64 << profile.encoding().argument_bits();
+    if memory_size as u64 > addressable {
+        return Err(format!("--memory-size can be at most {} words with the {} profile", addressable, profile.name()));
+    }
+    let new_path = if tool == Tool::Diff {
+        if paths.len() != 2 {
+            return Err("expected an old and a new input path".to_string());
+        }
+        paths.pop()
+    } else {
+        if paths.len() != 1 {
+            return Err("expected an input path".to_string());
+        }
+        None
+    };
+    Ok(ToolArguments {
+        input_path: paths.pop().unwrap(),
+        new_path: new_path,
+        instruction_set: instruction_set,
+        origin: origin,
+        endian: endian,
+        profile: profile,
+        round_trip: round_trip,
+        debug_info_path: debug_info_path,
+        json: json,
+        trace: trace,
+        trace_depth: trace_depth,
+        max_steps: max_steps,
+        timeout: timeout,
+        memory_size: memory_size,
+        tui: tui,
+        gdb: gdb
+    })
 }
 
-struct Instruction<'a> {
-    opcode: &'a str,
-    arg: i16
+fn link_main(arguments: &[String]) {
+    let arguments = match parse_link_arguments(arguments) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            writeln!(io::stderr(), "{}\n{}", message, USAGE).unwrap();
+            process::exit(2)
+        }
+    };
+
+    if let Err(error) = link(&arguments) {
+        writeln!(io::stderr(), "{}", error).unwrap();
+        process::exit(1)
+    }
 }
 
-fn compile(source_path: &String, destination_path: &String) -> io::Result<()> {
-    let raw_lines = try!(read_lines(source_path));
-    let lines = raw_lines.iter().map(parse_line);
-    let instructions = resolve(lines.collect());
-    let bytecodes = instructions.into_iter().map(encode_instruction);
-    write_lines(destination_path, bytecodes)
+fn archive_main(arguments: &[String]) {
+    let (archive_path, object_paths) = match arguments.split_first() {
+        Some((archive_path, object_paths)) if !object_paths.is_empty() && !arguments.iter().any(|argument| argument.starts_with("-")) =>
+            (archive_path, object_paths),
+        _ => {
+            writeln!(io::stderr(), "expected an archive path and at least one object path\n{}", USAGE).unwrap();
+            process::exit(2)
+        }
+    };
+
+    if let Err(message) = archive(archive_path, object_paths) {
+        writeln!(io::stderr(), "{}", message).unwrap();
+        process::exit(1)
+    }
 }
 
-fn parse_line<'a>(line: &'a String) -> Line<'a> {
-    if line.starts_with(":") {
-        Line::Label { name: line }
-    } else {
-        let mut parts = line.split(" ");
-        let opcode = parts.next().unwrap();
-        // TODO: reject args for noarg opcodes
-        let arg = parse_arg(parts.next());
-        Line::Instruction { opcode: opcode, arg: arg }
+fn nm_main(arguments: &[String]) {
+    if arguments.is_empty() || arguments.iter().any(|argument| argument.starts_with("-")) {
+        writeln!(io::stderr(), "expected at least one object or archive path\n{}", USAGE).unwrap();
+        process::exit(2)
+    }
+
+    match nm(arguments) {
+        Ok(output) => print!("{}", output),
+        Err(message) => {
+            writeln!(io::stderr(), "{}", message).unwrap();
+            process::exit(1)
+        }
     }
 }
 
-fn parse_arg(part: Option<&str>) -> Argument {
-    // TODO: don't drop parse errors on the floor
-    part.and_then(
-        |s|
-        if s.starts_with(":") {
-            Some(Argument::Label(s))
+// Each archive member, and each object when there's more than one, is listed
+// under its name, as nm does.
+fn nm(paths: &[String]) -> Result<String, String> {
+    let mut output = String::new();
+    for path in paths {
+        let text = String::from_utf8_lossy(&try!(read_file(Path::new(path)))).into_owned();
+        if text.starts_with("qarch ") {
+            let archive = try!(Archive::parse(&text).map_err(|message| format!("{}:{}", path, message)));
+            for &(ref name, ref object) in &archive.members {
+                output.push_str(&format!("\n{}:\n{}", name, quasm::nm(object)));
+            }
         } else {
-            s.parse::<i16>().ok().map(Argument::Integer)
-        })
-        .unwrap_or(Argument::None)
+            let object = try!(Object::parse(&text).map_err(|message| format!("{}:{}", path, message)));
+            if paths.len() > 1 {
+                output.push_str(&format!("\n{}:\n", path));
+            }
+            output.push_str(&quasm::nm(&object));
+        }
+    }
+    Ok(output)
 }
 
-fn resolve<'a>(lines: Vec<Line<'a>>) -> Vec<Instruction<'a>> {
-    let lines_with_addresses = lines_with_addresses(lines);
-    let label_addresses = find_labels(&lines_with_addresses);
-    lines_with_addresses.into_iter().filter_map(|line| resolve_line(&label_addresses, line)).collect()
+// Writes a ctags file, or an etags file with `-e`, to `tags` or `TAGS` unless
+// given another path with `-o`.
+fn tags_main(arguments: &[String]) {
+    let mut etags = false;
+    let mut destination_path = None;
+    let mut source_paths = Vec::new();
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        if argument == "-e" {
+            etags = true;
+        } else if argument == "-o" {
+            destination_path = arguments.next().cloned();
+            if destination_path.is_none() {
+                writeln!(io::stderr(), "-o requires a path\n{}", USAGE).unwrap();
+                process::exit(2)
+            }
+        } else if argument.starts_with("-") {
+            writeln!(io::stderr(), "unknown option `{}`\n{}", argument, USAGE).unwrap();
+            process::exit(2)
+        } else {
+            source_paths.push(argument.clone());
+        }
+    }
+    if source_paths.is_empty() {
+        writeln!(io::stderr(), "expected at least one source path\n{}", USAGE).unwrap();
+        process::exit(2)
+    }
+    let destination_path = destination_path.unwrap_or((if etags { "TAGS" } else { "tags" }).to_string());
+
+    if let Err(message) = tags(&source_paths, &destination_path, etags) {
+        writeln!(io::stderr(), "{}", message).unwrap();
+        process::exit(1)
+    }
 }
 
-fn resolve_line<'a>(label_addresses: &HashMap<String, i16>, (line, address) : (Line<'a>, i16)) -> Option<Instruction<'a>> {
-    match line {
-        Line::Instruction { opcode: opcode, arg: arg } =>
-            Option::Some(Instruction { opcode: opcode, arg: resolve_arg(label_addresses, address, &arg) }),
-        _ =>
-            Option::None
+// Assembles without writing anything, for the diagnostics, which include
+// warnings about unreachable code.
+fn check_main(arguments: &[String]) {
+    let mut arguments = match parse_arguments(arguments, false) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            writeln!(io::stderr(), "{}\n{}", message, USAGE).unwrap();
+            process::exit(2)
+        }
+    };
+    arguments.options.warn_unreachable = true;
+
+    match quasm::assemble_file(Path::new(&arguments.source_path), &arguments.options) {
+        Ok(program) => report_warnings(&program.warnings, arguments.messages),
+        Err(error) => {
+            report_error(&error, arguments.messages);
+            process::exit(1)
+        }
     }
 }
 
-fn resolve_arg<'a>(label_addresses: &HashMap<String, i16>, address: i16, argument: &Argument<'a>) -> i16 {
-    match argument {
-        &Argument::Integer(value) => value,
-        &Argument::Label(name) => label_addresses[name.to_string()] - (address + 1),
-        &Argument::None => 0
+// Checks the lint rules as well as assembling, and fails if any denied rule
+// is broken. The rest of the arguments are as for `check`.
+fn lint_main(arguments: &[String]) {
+    let arguments = parse_lint_arguments(arguments);
+    let arguments = match arguments {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            writeln!(io::stderr(), "{}\n{}", message, USAGE).unwrap();
+            process::exit(2)
+        }
+    };
+
+    match quasm::assemble_file(Path::new(&arguments.source_path), &arguments.options) {
+        Ok(program) => report_warnings(&program.warnings, arguments.messages),
+        Err(error) => {
+            report_error(&error, arguments.messages);
+            process::exit(1)
+        }
     }
 }
 
-fn encode_instruction<'a>(instruction: Instruction<'a>) -> i32 {
-    let bytecode = encode_opcode(&instruction.opcode);
-    let arg = instruction.arg as i32;
-    bytecode + (arg << 16)
+fn parse_lint_arguments(arguments: &[String]) -> Result<Arguments, String> {
+    let mut lints = Lints::default();
+    let mut rest = Vec::new();
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        let level = match argument.as_str() {
+            "--allow" => Level::Allow,
+            "--deny" => Level::Deny,
+            _ => {
+                rest.push(argument.clone());
+                continue;
+            }
+        };
+        let names: Vec<_> = Rule::all().iter().map(|rule| format!("`{}`", rule.name())).collect();
+        let rule = try!(arguments.next()
+            .and_then(|name| Rule::from_name(name))
+            .ok_or(format!("{} requires a rule, one of {}", argument, names.join(", "))));
+        lints.set(rule, level);
+    }
+    let mut arguments = try!(parse_arguments(&rest, false));
+    arguments.options.lints = Some(lints);
+    Ok(arguments)
 }
 
-fn encode_opcode(name: &str) -> i32 {
-    match name {
-        "const" => 0, // value << 16
-        "pop" => 1,
-        "dup" => 2,
-        "swap" => 3, // + (depth << 16)
-        "cmp" => 4,
-        "add" => 5,
-        "mul" => 6,
-        "jmp" => 7,
-        "jle" => 8,
-        _ => panic!("Unrecognised opcode")
+// Takes the same options as assembling, with the destination given by `-o`.
+fn cfg_main(arguments: &[String]) {
+    let arguments = match arguments.iter().position(|argument| argument == "-o") {
+        Some(position) if position + 1 < arguments.len() => {
+            let mut rest = arguments.to_vec();
+            let destination = rest.remove(position + 1);
+            rest.remove(position);
+            rest.push(destination);
+            parse_arguments(&rest, true)
+        },
+        _ => Err("expected a destination path, given with -o".to_string())
+    };
+    let arguments = match arguments {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            writeln!(io::stderr(), "{}\n{}", message, USAGE).unwrap();
+            process::exit(2)
+        }
+    };
+
+    if let Err(error) = cfg(&arguments) {
+        report_error(&error, arguments.messages);
+        process::exit(1)
     }
 }
 
-fn lines_with_addresses<'a>(lines: Vec<Line<'a>>) -> Vec<(Line<'a>, i16)> {
-    let mut address = 0;
-    let mut result = Vec::new();
-    for line in lines.into_iter() {
-        let is_instruction = match line {
-            Line::Instruction {..} => true,
-            _ => false
-        };
-        result.push((line, address));
-        if is_instruction {
-            address += 1
+fn parse_link_arguments(arguments: &[String]) -> Result<LinkArguments, String> {
+    let mut object_paths = Vec::new();
+    let mut destination_path = None;
+    let mut output = OutputOptions::default();
+    let mut symbols_path = None;
+    let mut map_path = None;
+    let mut pic = false;
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        if argument == "-o" {
+            let path = try!(arguments.next().ok_or("-o requires a path".to_string()));
+            destination_path = Some(path.clone());
+        } else if argument == "--format" {
+            output.format = try!(arguments.next()
+                .and_then(|format| Format::from_name(format))
+                .ok_or("--format requires `container`, `raw`, `ihex`, `srec`, `readmemh`, `c-header`, `rust`, `hex`, `base64` or `json`".to_string()));
+        } else if argument == "--no-checksum" {
+            output.omit_checksum = true;
+        } else if argument == "--symbols" {
+            let path = try!(arguments.next().ok_or("--symbols requires a path".to_string()));
+            symbols_path = Some(path.clone());
+        } else if argument == "--map" {
+            let path = try!(arguments.next().ok_or("--map requires a path".to_string()));
+            map_path = Some(path.clone());
+        } else if argument == "--pic" {
+            pic = true;
+        } else if argument.starts_with("-") {
+            return Err(format!("unknown option `{}`", argument));
+        } else {
+            object_paths.push(argument.clone());
         }
     }
-    result
+    if object_paths.is_empty() {
+        return Err("expected at least one object or archive path".to_string());
+    }
+    Ok(LinkArguments {
+        object_paths: object_paths,
+        destination_path: try!(destination_path.ok_or("expected a destination path, given with -o".to_string())),
+        output: output,
+        symbols_path: symbols_path,
+        map_path: map_path,
+        pic: pic
+    })
 }
 
-fn find_labels<'a>(lines: &Vec<(Line<'a>, i16)>) -> HashMap<String, i16> {
-    let mut labels = HashMap::new();
-    for &(ref line, address) in lines {
-        match line {
-            &Line::Label { name: name } => {
-                labels.insert(name.to_string(), address);
-            },
-            _ => ()
+// The pass named by `--opt=NAME`, and whether it's being turned on rather
+// than off by `--opt=no-NAME`.
+fn parse_pass(name: &str) -> Result<(Pass, bool), String> {
+    let (name, enabled) = if name.starts_with("no-") { (&name[3..], false) } else { (name, true) };
+    match Pass::from_name(name) {
+        Some(pass) => Ok((pass, enabled)),
+        None => {
+            let names: Vec<_> = Pass::all().iter().map(|pass| format!("`{}`", pass.name())).collect();
+            Err(format!("unknown optimization pass `{}`, expected one of {}", name, names.join(", ")))
         }
     }
-    labels
 }
 
-fn read_lines(path: &String) -> io::Result<Vec<String>> {
-    let file = try!(File::open(&path));
-    let lines = BufReader::new(file).lines();
-    lines.collect()
+fn parse_arguments(arguments: &[String], destination: bool) -> Result<Arguments, String> {
+    let mut paths = Vec::new();
+    let mut options = Options::default();
+    let mut output = OutputOptions::default();
+    let mut listing_path = None;
+    let mut symbols_path = None;
+    let mut map_path = None;
+    let mut debug_info_path = None;
+    let mut stats = false;
+    let mut color = None;
+    let mut json = false;
+    let mut level = OptimizationLevel::default();
+    let mut toggled_passes = Vec::new();
+    let mut no_opt = false;
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        if argument == "-D" {
+            let definition = try!(arguments.next().ok_or("-D requires NAME=VALUE".to_string()));
+            try!(options.define(definition));
+        } else if argument.starts_with("-D") {
+            try!(options.define(&argument[2..]));
+        } else if argument == "--warn-fall-through" {
+            options.warn_fall_through = true;
+        } else if argument == "--strict" {
+            options.strict = true;
+        } else if argument == "--deny-warnings" {
+            options.deny_warnings = true;
+        } else if argument == "--warn-unreachable" {
+            options.warn_unreachable = true;
+        } else if argument == "--warn-unused-labels" {
+            options.warn_unused_labels = true;
+        } else if argument == "--warn-stack" {
+            options.warn_stack = true;
+        } else if argument == "--object" {
+            options.object = true;
+        } else if argument == "--pic" {
+            options.pic = true;
+        } else if argument.starts_with("-O") {
+            level = try!(OptimizationLevel::from_name(&argument[2..])
+                .ok_or(format!("unknown optimization level `{}`, expected -O0, -O1, -O2 or -Os", argument)));
+        } else if argument.starts_with("--opt=") {
+            toggled_passes.push(try!(parse_pass(&argument[6..])));
+        } else if argument == "--no-opt" {
+            no_opt = true;
+        } else if argument == "--isa-spec" {
+            let path = try!(arguments.next().ok_or("--isa-spec requires a path".to_string()));
+            options.instruction_set = try!(InstructionSet::read(Path::new(path)));
+        } else if argument == "--endian" {
+            options.endian = try!(parse_endian(arguments.next()));
+        } else if argument == "--profile" {
+            options.profile = try!(parse_profile(arguments.next()));
+        } else if argument == "--format" {
+            output.format = try!(arguments.next()
+                .and_then(|format| Format::from_name(format))
+                .ok_or("--format requires `container`, `raw`, `ihex`, `srec`, `readmemh`, `c-header`, `rust`, `hex`, `base64` or `json`".to_string()));
+        } else if argument == "--base-address" {
+            output.base_address = try!(arguments.next()
+                .and_then(|address| parse_address(address))
+                .ok_or("--base-address requires an address".to_string()));
+        } else if argument == "--address-bits" {
+            let bits = try!(arguments.next()
+                .and_then(|bits| bits.parse().ok())
+                .ok_or("--address-bits requires 16, 24 or 32".to_string()));
+            output.address_bits = Some(bits);
+        } else if argument == "--address-markers" {
+            output.address_markers = true;
+        } else if argument == "--array-name" {
+            let name = try!(arguments.next().ok_or("--array-name requires a name".to_string()));
+            output.array_name = Some(name.clone());
+        } else if argument == "--label-constants" {
+            output.label_constants = true;
+        } else if argument == "--no-checksum" {
+            output.omit_checksum = true;
+        } else if argument == "--listing" {
+            let path = try!(arguments.next().ok_or("--listing requires a path".to_string()));
+            listing_path = Some(path.clone());
+        } else if argument == "--symbols" {
+            let path = try!(arguments.next().ok_or("--symbols requires a path".to_string()));
+            symbols_path = Some(path.clone());
+        } else if argument == "--map" {
+            let path = try!(arguments.next().ok_or("--map requires a path".to_string()));
+            map_path = Some(path.clone());
+        } else if argument == "--debug-info" {
+            let path = try!(arguments.next().ok_or("--debug-info requires a path".to_string()));
+            debug_info_path = Some(path.clone());
+        } else if argument == "--color" {
+            color = match arguments.next().map(|color| color.as_str()) {
+                Some("auto") => None,
+                Some("always") => Some(true),
+                Some("never") => Some(false),
+                _ => return Err("--color requires `auto`, `always` or `never`".to_string())
+            };
+        } else if argument == "--message-format" {
+            json = match arguments.next().map(|format| format.as_str()) {
+                Some("human") => false,
+                Some("json") => true,
+                _ => return Err("--message-format requires `human` or `json`".to_string())
+            };
+        } else if argument == "--stats" {
+            stats = true;
+        } else if argument == "--raw" {
+            output.format = Format::Raw;
+        } else if argument.starts_with("-") {
+            return Err(format!("unknown option `{}`", argument));
+        } else {
+            paths.push(argument.clone());
+        }
+    }
+    // `--opt` changes the passes of the `-O` level, and `--no-opt` turns off
+    // every optimization, wherever they're given.
+    options.passes = level.passes();
+    options.repeat_passes = level.repeats();
+    for (pass, enabled) in toggled_passes {
+        options.passes.retain(|&other| other != pass);
+        if enabled {
+            options.passes.push(pass);
+        }
+    }
+    if no_opt {
+        options.passes.clear();
+    }
+    match (paths.len(), destination) {
+        (2, true) | (1, false) => Ok(Arguments {
+            destination_path: if destination { paths.pop().unwrap() } else { String::new() },
+            source_path: paths.pop().unwrap(),
+            options: options,
+            output: output,
+            listing_path: listing_path,
+            symbols_path: symbols_path,
+            map_path: map_path,
+            debug_info_path: debug_info_path,
+            stats: stats,
+            messages: if json {
+                MessageFormat::Json
+            } else {
+                MessageFormat::Human { color: color.unwrap_or_else(terminal_color) }
+            }
+        }),
+        (_, true) => Err("expected a source and a destination path".to_string()),
+        (_, false) => Err("expected a source path".to_string())
+    }
 }
 
-fn write_lines<I: Iterator<Item=i32>>(path: &String, bytecodes: I) -> io::Result<()> {
-    let file = try!(File::create(path));
-    let mut writer = BufWriter::new(file);
-    let bytecodes_vec: Vec<i32> = bytecodes.collect();
-    let bytes: &[u8] = unsafe {
-        std::slice::from_raw_parts(
-            bytecodes_vec.as_ptr() as *const u8,
-            bytecodes_vec.len() * std::mem::size_of::<i32>())
+// Only color what's read in a terminal, unless asked not to.
+fn terminal_color() -> bool {
+    io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+fn parse_endian(argument: Option<&String>) -> Result<Endian, String> {
+    argument
+        .and_then(|endian| Endian::from_name(endian))
+        .ok_or("--endian requires `little` or `big`".to_string())
+}
+
+fn parse_profile(argument: Option<&String>) -> Result<Profile, String> {
+    argument
+        .and_then(|profile| Profile::from_name(profile))
+        .ok_or("--profile requires `word32` or `word64`".to_string())
+}
+
+// Parses a decimal address, or a hexadecimal one prefixed with `0x`.
+fn parse_address(text: &str) -> Option<u32> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        u32::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn compile(arguments: &Arguments) -> Result<(), QuasmError> {
+    let program = try!(quasm::assemble_file(Path::new(&arguments.source_path), &arguments.options));
+    report_warnings(&program.warnings, arguments.messages);
+    if arguments.options.object {
+        try!(write_bytes(&arguments.destination_path, program.object().to_text().as_bytes()));
+    } else {
+        let output = try!(program.output(&arguments.output));
+        try!(write_bytes(&arguments.destination_path, &output));
+    }
+    if let Some(ref listing_path) = arguments.listing_path {
+        try!(write_bytes(listing_path, program.listing().as_bytes()));
+    }
+    if let Some(ref symbols_path) = arguments.symbols_path {
+        try!(write_bytes(symbols_path, program.symbols().as_bytes()));
+    }
+    if let Some(ref map_path) = arguments.map_path {
+        try!(write_bytes(map_path, program.map().as_bytes()));
+    }
+    if let Some(ref debug_info_path) = arguments.debug_info_path {
+        try!(write_bytes(debug_info_path, program.debug_info().to_text().as_bytes()));
+    }
+    // Stdout only has diagnostics on it with `--message-format json`.
+    if arguments.stats {
+        let stats = program.stats(&arguments.options.instruction_set);
+        match arguments.messages {
+            MessageFormat::Human {..} => print!("{}", stats),
+            MessageFormat::Json => write!(io::stderr(), "{}", stats).unwrap()
+        }
+    }
+    Ok(())
+}
+
+fn cfg(arguments: &Arguments) -> Result<(), QuasmError> {
+    let program = try!(quasm::assemble_file(Path::new(&arguments.source_path), &arguments.options));
+    report_warnings(&program.warnings, arguments.messages);
+    try!(write_bytes(&arguments.destination_path, program.cfg(&arguments.options.instruction_set).as_bytes()));
+    Ok(())
+}
+
+// Each warning, as `Diagnostic::render` or `Diagnostic::to_json` gives it.
+fn report_warnings(warnings: &[Diagnostic], messages: MessageFormat) {
+    for warning in warnings {
+        match messages {
+            MessageFormat::Human { color } => write!(io::stderr(), "{}", render(warning, color)).unwrap(),
+            MessageFormat::Json => println!("{}", warning.to_json())
+        }
+    }
+}
+
+// Diagnostics are reported with the line they're about, and any other error
+// as it's displayed, which is always on stderr.
+fn report_error(error: &QuasmError, messages: MessageFormat) {
+    match error {
+        &QuasmError::Invalid(ref diagnostics) => {
+            report_warnings(diagnostics, messages);
+            let errors = diagnostics.iter().filter(|diagnostic| !diagnostic.is_warning()).count();
+            writeln!(io::stderr(), "{} error(s)", errors).unwrap();
+        },
+        _ => writeln!(io::stderr(), "{}", error).unwrap()
+    }
+}
+
+// Renders a diagnostic with its line, read from its file again.
+fn render(diagnostic: &Diagnostic, color: bool) -> String {
+    let source = diagnostic.location.file.as_ref()
+        .and_then(|file| read_file(Path::new(file)).ok())
+        .and_then(|bytes| String::from_utf8_lossy(&bytes).lines().nth(diagnostic.location.line_number - 1).map(|line| line.to_string()));
+    diagnostic.render(source.as_ref().map(|line| line.as_str()), color)
+}
+
+fn tags(source_paths: &[String], destination_path: &String, etags: bool) -> Result<(), String> {
+    let mut texts = Vec::new();
+    for path in source_paths {
+        texts.push(String::from_utf8_lossy(&try!(read_file(Path::new(path)))).into_owned());
+    }
+    let files: Vec<(&str, &str)> = source_paths.iter().map(|path| path.as_str()).zip(texts.iter().map(|text| text.as_str())).collect();
+    let output = if etags { quasm::etags(&files) } else { quasm::ctags(&files) };
+    write_bytes(destination_path, output.as_bytes()).map_err(|error| format!("{}: {}", destination_path, error))
+}
+
+fn read_object(path: &str) -> Result<Object, String> {
+    let text = String::from_utf8_lossy(&try!(read_file(Path::new(path)))).into_owned();
+    Object::parse(&text).map_err(|message| format!("{}:{}", path, message))
+}
+
+// Archives are told apart from objects by their header, and only the
+// members the objects need are linked.
+fn link(arguments: &LinkArguments) -> Result<(), String> {
+    let mut objects = Vec::new();
+    let mut archives = Vec::new();
+    for path in &arguments.object_paths {
+        let text = String::from_utf8_lossy(&try!(read_file(Path::new(path)))).into_owned();
+        if text.starts_with("qarch ") {
+            let archive = try!(Archive::parse(&text).map_err(|message| format!("{}:{}", path, message)));
+            archives.push((path.clone(), archive));
+        } else {
+            let object = try!(Object::parse(&text).map_err(|message| format!("{}:{}", path, message)));
+            objects.push((path.clone(), object));
+        }
+    }
+    let objects = quasm::select_members(objects, archives);
+    if arguments.pic {
+        try!(quasm::check_position_independent(&objects).map_err(|error| error.to_string()));
+    }
+    let program = try!(quasm::link(&objects).map_err(|error| error.to_string()));
+    let output = try!(program.output(&arguments.output).map_err(|error| error.to_string()));
+    try!(write_bytes(&arguments.destination_path, &output).map_err(|error| format!("{}: {}", arguments.destination_path, error)));
+    if let Some(ref symbols_path) = arguments.symbols_path {
+        try!(write_bytes(symbols_path, program.symbols().as_bytes()).map_err(|error| format!("{}: {}", symbols_path, error)));
+    }
+    if let Some(ref map_path) = arguments.map_path {
+        try!(write_bytes(map_path, program.map().as_bytes()).map_err(|error| format!("{}: {}", map_path, error)));
+    }
+    Ok(())
+}
+
+// Each member is named after its object's file name.
+fn archive(archive_path: &str, object_paths: &[String]) -> Result<(), String> {
+    let mut members = Vec::new();
+    for path in object_paths {
+        let name = Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or(path.clone());
+        members.push((name, try!(read_object(path))));
+    }
+    let archive = Archive { members: members };
+    write_bytes(&archive_path.to_string(), archive.to_text().as_bytes()).map_err(|error| format!("{}: {}", archive_path, error))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    try!(File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|error| format!("{}: {}", path.display(), error)));
+    Ok(bytes)
+}
+
+fn disassemble(arguments: &ToolArguments) -> Result<String, String> {
+    let bytes = try!(read_file(Path::new(&arguments.input_path)));
+    let bytecode = try!(quasm::read_bytecode(&bytes, arguments.endian, arguments.profile)
+        .map_err(|error| format!("{}: {}", arguments.input_path, error)));
+    if arguments.round_trip {
+        quasm::disassemble_source(&bytecode, arguments.origin, &arguments.instruction_set)
+            .map_err(|message| format!("{}: {}", arguments.input_path, message))
+    } else {
+        Ok(quasm::disassemble(&bytecode.words, arguments.origin, &arguments.instruction_set, bytecode.profile.encoding()))
+    }
+}
+
+// Debug info is read from `--debug-info`, or else from the input path with
+// its extension replaced by `.qdbg`, if there's a file there.
+fn read_debug_info(arguments: &ToolArguments) -> Result<Option<DebugInfo>, String> {
+    let debug_info_path = match arguments.debug_info_path {
+        Some(ref path) => Some(PathBuf::from(path)),
+        None => Some(Path::new(&arguments.input_path).with_extension("qdbg")).filter(|path| path.is_file())
     };
-    writer.write_all(bytes)
+    match debug_info_path {
+        Some(path) => {
+            let text = String::from_utf8_lossy(&try!(read_file(&path))).into_owned();
+            DebugInfo::parse(&text).map(Some).map_err(|message| format!("{}:{}", path.display(), message))
+        },
+        None => Ok(None)
+    }
 }
 
-fn with_lines<F: Fn(String) -> String>(f: F) -> io::Result<()> {
-    let arguments: Vec<_> = env::args().collect();
-    let (source_file, destination_file) = match &arguments[..] {
-        [_, ref source_file, ref destination_file, ..] => (source_file, destination_file),
-        _ => panic!("Not enough arguments")
+fn inspect(arguments: &ToolArguments) -> Result<String, String> {
+    let bytes = try!(read_file(Path::new(&arguments.input_path)));
+    let debug_info = try!(read_debug_info(arguments));
+    quasm::inspect(&bytes, debug_info.as_ref(), &arguments.instruction_set, arguments.endian, arguments.profile)
+        .map_err(|error| format!("{}: {}", arguments.input_path, error))
+}
+
+// Any violations are the error, so that they're reported like other errors.
+fn verify(arguments: &ToolArguments) -> Result<String, String> {
+    let bytes = try!(read_file(Path::new(&arguments.input_path)));
+    let debug_info = try!(read_debug_info(arguments));
+    let violations = try!(quasm::verify(&bytes, debug_info.as_ref(), &arguments.instruction_set, arguments.endian, arguments.profile)
+        .map_err(|error| format!("{}: {}", arguments.input_path, error)));
+    if violations.is_empty() {
+        Ok(String::new())
+    } else {
+        let messages: Vec<_> = violations.iter().map(|violation| format!("{}: {}", arguments.input_path, violation)).collect();
+        Err(messages.join("\n"))
+    }
+}
+
+// Which words are in which function is only known from debug info.
+fn size(arguments: &ToolArguments) -> Result<String, String> {
+    let bytes = try!(read_file(Path::new(&arguments.input_path)));
+    let debug_info = try!(try!(read_debug_info(arguments))
+        .ok_or(format!("{}: no debug info, which `size` needs to tell where functions are; give it with --debug-info", arguments.input_path)));
+    quasm::size(&bytes, &debug_info, arguments.endian, arguments.profile)
+        .map_err(|error| format!("{}: {}", arguments.input_path, error))
+}
+
+// Runs a program, which is assembled first if it's source rather than
+// bytecode, with its output on stdout. A program that halts exits with the
+// status it halted with, and one that returns prints what's left on the
+// stack, top last. A program stopped by `--max-steps` or `--timeout` exits
+// with `EXIT_STOPPED`. Tracing writes a line before each step, as
+// `Machine::trace_line` gives it.
+fn run(arguments: &ToolArguments) {
+    let (mut machine, debug_info) = match load_machine(arguments) {
+        Ok(loaded) => loaded,
+        Err(message) => {
+            writeln!(io::stderr(), "{}", message).unwrap();
+            process::exit(1)
+        }
+    };
+    machine.trace_depth = arguments.trace_depth;
+    machine.max_steps = arguments.max_steps;
+    machine.deadline = arguments.timeout.map(|timeout| Instant::now() + timeout);
+    machine.trace = match arguments.trace {
+        Some(Some(ref path)) => match File::create(path) {
+            Ok(file) => Some(Box::new(BufWriter::new(file))),
+            Err(error) => {
+                writeln!(io::stderr(), "{}: {}", path, error).unwrap();
+                process::exit(1)
+            }
+        },
+        Some(None) => Some(Box::new(io::stderr())),
+        None => None
     };
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut finished = None;
+    if let Some(ref address) = arguments.gdb {
+        let debugger = serve_gdb(address, Debugger::new(machine, debug_info), &mut stdin.lock(), &mut stdout.lock());
+        machine = debugger.machine;
+        finished = debugger.finished;
+    }
+    let result = match finished {
+        Some(stop) => Ok(stop),
+        None => machine.run(&mut stdin.lock(), &mut stdout.lock())
+    };
+    // The process exits without dropping the machine.
+    if let Some(ref mut trace) = machine.trace {
+        trace.flush().unwrap();
+    }
+    match result {
+        Ok(Stop::Halted(status)) => {
+            writeln!(io::stderr(), "halted with status {}", status).unwrap();
+            process::exit(status as i32)
+        },
+        Ok(Stop::OutOfSteps) => {
+            writeln!(io::stderr(), "fuel exhausted: stopped after {} steps", machine.steps).unwrap();
+            process::exit(EXIT_STOPPED)
+        },
+        Ok(Stop::TimedOut) => {
+            writeln!(io::stderr(), "fuel exhausted: timed out after {} steps", machine.steps).unwrap();
+            process::exit(EXIT_STOPPED)
+        },
+        Ok(Stop::Returned) => {
+            let stack: Vec<_> = machine.stack.iter().map(|value| value.to_string()).collect();
+            writeln!(io::stderr(), "returned with stack [{}]", stack.join(", ")).unwrap();
+        },
+        Ok(Stop::Ended) => {
+            let stack: Vec<_> = machine.stack.iter().map(|value| value.to_string()).collect();
+            writeln!(io::stderr(), "ended with stack [{}]", stack.join(", ")).unwrap();
+        },
+        Err(trap) => {
+            writeln!(io::stderr(), "{}", trap).unwrap();
+            process::exit(1)
+        }
+    }
+}
 
-    let in_file = try!(File::open(source_file));
+// Waits for gdb to connect to `address`, which is `HOST:PORT`, or `:PORT` for
+// localhost, and lets it control the program until it detaches, exiting if
+// it kills the program. If gdb only detaches, the program runs on.
+fn serve_gdb(address: &str, mut debugger: Debugger, input: &mut dyn BufRead, output: &mut dyn Write) -> Debugger {
+    let address = if address.starts_with(":") { format!("localhost{}", address) } else { address.to_string() };
+    let served = TcpListener::bind(&address)
+        .and_then(|listener| {
+            writeln!(io::stderr(), "waiting for gdb on {}", try!(listener.local_addr())).unwrap();
+            listener.accept()
+        })
+        .and_then(|(mut stream, _)| quasm::serve_gdb(&mut stream, &mut debugger, input, output));
+    match served {
+        Ok(true) => debugger,
+        Ok(false) => {
+            writeln!(io::stderr(), "killed by gdb").unwrap();
+            process::exit(1)
+        },
+        Err(error) => {
+            writeln!(io::stderr(), "{}: {}", address, error).unwrap();
+            process::exit(1)
+        }
+    }
+}
 
-    let out_file = try!(File::create(destination_file));
-    let mut writer = BufWriter::new(out_file);
+// Bytecode always has a zero byte in each word, as no opcode's code needs
+// more than one, so anything that's text without any is taken to be source,
+// unless it's bytecode written as base64 or hex. Source has debug info from
+// assembling it, and bytecode has any that's found as for `inspect`.
+fn load_machine(arguments: &ToolArguments) -> Result<(Machine, Option<DebugInfo>), String> {
+    let path = Path::new(&arguments.input_path);
+    let bytes = try!(read_file(path));
+    let source = !bytes.starts_with(b"QASM") && !bytes.contains(&0) && String::from_utf8(bytes.clone()).is_ok() && !quasm::is_text_bytecode(&bytes);
+    if source {
+        let mut options = Options::default();
+        options.instruction_set = arguments.instruction_set.clone();
+        options.endian = arguments.endian;
+        options.profile = arguments.profile;
+        let messages = MessageFormat::Human { color: terminal_color() };
+        match quasm::assemble_file(path, &options) {
+            Ok(program) => {
+                report_warnings(&program.warnings, messages);
+                let words: Vec<_> = program.words.iter().map(|word| word.encoded).collect();
+                let machine = try!(Machine::new(&words, program.origin, program.entry, options.instruction_set, program.profile, arguments.memory_size));
+                Ok((machine, Some(program.debug_info())))
+            },
+            Err(error) => {
+                report_error(&error, messages);
+                Err(format!("{}: couldn't be assembled", arguments.input_path))
+            }
+        }
+    } else {
+        let bytecode = try!(quasm::read_bytecode(&bytes, arguments.endian, arguments.profile)
+            .map_err(|error| format!("{}: {}", arguments.input_path, error)));
+        let entry = bytecode.header.as_ref().and_then(|header| header.entry);
+        let machine = try!(Machine::new(&bytecode.words, arguments.origin, entry, arguments.instruction_set.clone(), bytecode.profile, arguments.memory_size));
+        Ok((machine, try!(read_debug_info(arguments))))
+    }
+}
 
-    let lines = BufReader::new(in_file).lines();
-    for line in lines {
-        let line2 = f(try!(line));
-        try!(writer.write_fmt(format_args!("{}\n", line2)));
+// Reads commands from stdin until `quit` or the end of the input, sharing it
+// with the program, as `Debugger::command` describes.
+fn debug(arguments: &ToolArguments) {
+    let (machine, debug_info) = match load_machine(arguments) {
+        Ok(loaded) => loaded,
+        Err(message) => {
+            writeln!(io::stderr(), "{}", message).unwrap();
+            process::exit(1)
+        }
+    };
+    let mut debugger = Debugger::new(machine, debug_info);
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    if arguments.tui {
+        let (width, height) = terminal_size();
+        Tui::new(debugger).run(&mut input, &mut output, width, height).unwrap();
+        return;
     }
-    Ok(())
+    writeln!(output, "{}", debugger.describe(debugger.machine.pc)).unwrap();
+    loop {
+        write!(output, "(quasm) ").unwrap();
+        output.flush().unwrap();
+        let mut line = String::new();
+        if input.read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        match debugger.command(&line, &mut input, &mut output) {
+            Some(ref report) if report.is_empty() => (),
+            Some(report) => writeln!(output, "{}", report).unwrap(),
+            None => break
+        }
+    }
+}
+
+// Serves the Debug Adapter Protocol on stdin and stdout. A launch gives the
+// program as `program`, and any options `debug` takes as `args`.
+fn dap_main(arguments: &[String]) {
+    if !arguments.is_empty() {
+        writeln!(io::stderr(), "dap takes no arguments\n{}", USAGE).unwrap();
+        process::exit(2)
+    }
+    let mut load = |launch: &Json| {
+        let program = try!(launch.get("program").as_str().ok_or("launch needs a `program`".to_string()));
+        let mut arguments: Vec<String> = launch.get("args").as_array().iter()
+            .filter_map(|argument| argument.as_str())
+            .map(|argument| argument.to_string())
+            .collect();
+        arguments.push(program.to_string());
+        let arguments = try!(parse_tool_arguments(Tool::Debug, &arguments));
+        let (machine, debug_info) = try!(load_machine(&arguments));
+        Ok(Debugger::new(machine, debug_info))
+    };
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(error) = quasm::serve_dap(&mut stdin.lock(), &mut stdout.lock(), &mut load) {
+        writeln!(io::stderr(), "{}", error).unwrap();
+        process::exit(1)
+    }
+}
+
+// The width and height of the terminal, as `stty` gives them, or otherwise
+// as `COLUMNS` and `LINES` do, or 80 by 24.
+fn terminal_size() -> (usize, usize) {
+    let stty = process::Command::new("stty").arg("size").stdin(process::Stdio::inherit()).output().ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|size| {
+            let size: Vec<usize> = size.split_whitespace().filter_map(|number| number.parse().ok()).collect();
+            if size.len() == 2 && size[0] > 0 && size[1] > 0 { Some((size[1], size[0])) } else { None }
+        });
+    let variable = |name: &str| env::var(name).ok().and_then(|value| value.parse().ok());
+    stty.unwrap_or_else(|| (variable("COLUMNS").unwrap_or(80), variable("LINES").unwrap_or(24)))
+}
+
+fn diff(arguments: &ToolArguments) -> Result<String, String> {
+    let read_bytecode = |path: &str| {
+        let bytes = try!(read_file(Path::new(path)));
+        quasm::read_bytecode(&bytes, arguments.endian, arguments.profile).map_err(|error| format!("{}: {}", path, error))
+    };
+    let old = try!(read_bytecode(&arguments.input_path));
+    let new = try!(read_bytecode(arguments.new_path.as_ref().unwrap()));
+    let changes = quasm::diff(&old, &new, &arguments.instruction_set);
+    if arguments.json {
+        Ok(quasm::diff_json(&changes))
+    } else {
+        Ok(quasm::diff_text(&changes))
+    }
+}
+
+fn write_bytes(path: &String, bytes: &[u8]) -> io::Result<()> {
+    let file = try!(File::create(path));
+    let mut writer = BufWriter::new(file);
+    writer.write_all(bytes)
 }
@@ -0,0 +1,36 @@
+use object::{Object, RelocationTarget};
+use parser::Section;
+
+// The symbols of an object, sorted by name, as nm lists them:
+// `ADDRESS TYPE NAME`, with the address the symbol was assembled at. The
+// type is `T` or `D` for a symbol the object defines in `.text` or `.data`,
+// which are always `.global`, as objects only keep those, or `U` for one it
+// uses but another object has to define, which has no address.
+pub fn nm(object: &Object) -> String {
+    let mut symbols: Vec<(&str, Option<u32>, char)> = object.symbols.iter()
+        .map(|&(ref name, section, offset)| {
+            let address = object.sections.iter()
+                .find(|object_section| object_section.section == section)
+                .map_or(offset, |object_section| object_section.address + offset);
+            let kind = match section {
+                Section::Text => 'T',
+                Section::Data => 'D'
+            };
+            (name.as_str(), Some(address), kind)
+        })
+        .collect();
+    for relocation in &object.relocations {
+        if let RelocationTarget::Symbol(ref name) = relocation.target {
+            if !symbols.iter().any(|&(symbol, _, _)| symbol == name) {
+                symbols.push((name, None, 'U'));
+            }
+        }
+    }
+    symbols.sort_by_key(|&(name, _, _)| name);
+    symbols.iter()
+        .map(|&(name, address, kind)| match address {
+            Some(address) => format!("{:08X} {} {}\n", address, kind, name),
+            None => format!("{:8} {} {}\n", "", kind, name)
+        })
+        .collect()
+}
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+
+use encoder::{self, Endian};
+use encoding::{self, Profile};
+use error::{Location, QuasmError};
+use parser::Section;
+use {Label, Program, Region, WordInfo};
+
+// An object is assembled on its own, to be linked with others into a
+// program. It's written to a `.qo` file as text, one record per line:
+//
+//     qobj 1
+//     profile PROFILE
+//     endian ENDIAN
+//     section NAME ADDRESS COUNT
+//     word WORD
+//     symbol SECTION OFFSET NAME
+//     relocation SECTION OFFSET KIND section NAME
+//     relocation SECTION OFFSET KIND symbol NAME
+//     entry SECTION OFFSET
+//
+// Each `section` is followed by its COUNT words. Addresses, offsets and
+// words are hexadecimal, and counts decimal. Offsets are from the start of
+// the section, and sections are named without their leading `.`.
+pub const VERSION: u32 = 1;
+
+// How a word changes when what it refers to moves.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RelocationKind {
+    // The instruction's argument is the offset of the target from the next
+    // word.
+    Relative,
+    // The instruction's argument is the target's address.
+    Absolute,
+    // The whole word is the target's address.
+    Data
+}
+
+// What a relocated word refers to: a section of the same object, or a label
+// another object defines.
+#[derive(Clone, PartialEq)]
+pub enum RelocationTarget {
+    Section(Section),
+    Symbol(String)
+}
+
+#[derive(Clone)]
+pub struct Relocation {
+    pub section: Section,
+    pub offset: u32,
+    pub kind: RelocationKind,
+    pub target: RelocationTarget
+}
+
+pub struct ObjectSection {
+    pub section: Section,
+    // The address the section was assembled at.
+    pub address: u32,
+    pub words: Vec<i64>
+}
+
+pub struct Object {
+    pub profile: Profile,
+    pub endian: Endian,
+    // Each section that has any words or labels, in output order.
+    pub sections: Vec<ObjectSection>,
+    // The section and offset of each label other objects can use.
+    pub symbols: Vec<(String, Section, u32)>,
+    pub relocations: Vec<Relocation>,
+    pub entry: Option<(Section, u32)>
+}
+
+impl RelocationKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            &RelocationKind::Relative => "relative",
+            &RelocationKind::Absolute => "absolute",
+            &RelocationKind::Data => "data"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RelocationKind> {
+        match name {
+            "relative" => Some(RelocationKind::Relative),
+            "absolute" => Some(RelocationKind::Absolute),
+            "data" => Some(RelocationKind::Data),
+            _ => None
+        }
+    }
+}
+
+impl Object {
+    // Only `.global` labels are exported. The entry is in the section
+    // it comes before the end of, if there is one.
+    pub fn new(program: &Program) -> Object {
+        let mut sections: Vec<ObjectSection> = program.sections.iter()
+            .map(|region| {
+                let start = (region.address - program.origin) as usize;
+                ObjectSection {
+                    section: region.section,
+                    address: region.address,
+                    words: program.words[start..start + region.size as usize].iter().map(|word| word.encoded).collect()
+                }
+            })
+            .collect();
+        for label in &program.labels {
+            if !sections.iter().any(|section| section.section == label.section) {
+                sections.push(ObjectSection { section: label.section, address: label.address, words: Vec::new() });
+            }
+        }
+        sections.sort_by_key(|section| section.section);
+        let offset = |section: Section, address: u32| {
+            address - sections.iter().find(|object_section| object_section.section == section).unwrap().address
+        };
+        let symbols = program.labels.iter()
+            .filter(|label| label.global)
+            .map(|label| (label.name.clone(), label.section, offset(label.section, label.address)))
+            .collect();
+        let entry = program.entry.and_then(|entry| {
+            sections.iter()
+                .find(|section| entry < section.address + section.words.len() as u32)
+                .or(sections.iter().find(|section| entry == section.address + section.words.len() as u32))
+                .map(|section| (section.section, entry - section.address))
+        });
+        Object {
+            profile: program.profile,
+            endian: program.endian,
+            relocations: program.relocations.clone(),
+            sections: sections,
+            symbols: symbols,
+            entry: entry
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let word_digits = self.profile.encoding().word_size() * 2;
+        let mask = !0u64 >> (64 - word_digits * 4);
+        let mut output = format!("qobj {}\nprofile {}\nendian {}\n", VERSION, self.profile.name(), self.endian.name());
+        for section in &self.sections {
+            output.push_str(&format!("section {} {:08X} {}\n", section.section.name(), section.address, section.words.len()));
+            for &word in &section.words {
+                output.push_str(&format!("word {:01$X}\n", word as u64 & mask, word_digits));
+            }
+        }
+        for &(ref name, section, offset) in &self.symbols {
+            output.push_str(&format!("symbol {} {:08X} {}\n", section.name(), offset, name));
+        }
+        for relocation in &self.relocations {
+            let target = match relocation.target {
+                RelocationTarget::Section(section) => format!("section {}", section.name()),
+                RelocationTarget::Symbol(ref name) => format!("symbol {}", name)
+            };
+            output.push_str(&format!("relocation {} {:08X} {} {}\n", relocation.section.name(), relocation.offset, relocation.kind.name(), target));
+        }
+        if let Some((section, offset)) = self.entry {
+            output.push_str(&format!("entry {} {:08X}\n", section.name(), offset));
+        }
+        output
+    }
+
+    // Reads an object written by `to_text`. Errors are prefixed with the
+    // line number they were found on.
+    pub fn parse(text: &str) -> Result<Object, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, line)) if line.trim() == format!("qobj {}", VERSION) => (),
+            Some((_, line)) if line.starts_with("qobj ") =>
+                return Err(format!("1: unsupported object version `{}`, expected {}", line[5..].trim(), VERSION)),
+            _ => return Err("1: not a quasm object: missing `qobj` header".to_string())
+        }
+        let mut object = Object {
+            profile: Profile::default(),
+            endian: Endian::default(),
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+            entry: None
+        };
+        // The number of words still to come in the last section.
+        let mut remaining = 0;
+        let mut line_number = 1;
+        for (index, line) in lines {
+            line_number = index + 1;
+            try!(object.parse_line(line, &mut remaining).map_err(|message| format!("{}: {}", line_number, message)));
+        }
+        if remaining > 0 {
+            return Err(format!("{}: expected {} more words in the section", line_number, remaining));
+        }
+        Ok(object)
+    }
+
+    fn parse_line(&mut self, line: &str, remaining: &mut usize) -> Result<(), String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+        let fields: Vec<_> = line.splitn(6, ' ').collect();
+        if fields[0] != "word" && *remaining > 0 {
+            return Err(format!("expected {} more words in the section", remaining));
+        }
+        match (fields[0], fields.len()) {
+            ("profile", 2) =>
+                self.profile = try!(Profile::from_name(fields[1]).ok_or(format!("`{}` is not a profile", fields[1]))),
+            ("endian", 2) =>
+                self.endian = try!(Endian::from_name(fields[1]).ok_or(format!("`{}` is not a byte order", fields[1]))),
+            ("section", 4) => {
+                let section = try!(parse_section(fields[1]));
+                if self.sections.iter().any(|object_section| object_section.section == section) {
+                    return Err(format!("section `{}` is given twice", fields[1]));
+                }
+                *remaining = try!(fields[3].parse().map_err(|_| format!("`{}` is not a number", fields[3])));
+                self.sections.push(ObjectSection { section: section, address: try!(parse_hex(fields[2])) as u32, words: Vec::new() });
+            },
+            ("word", 2) => {
+                let encoding = self.profile.encoding();
+                let word = try!(parse_hex(fields[1]));
+                match self.sections.last_mut() {
+                    Some(section) if *remaining > 0 => section.words.push(encoding::sign_extend(word as i64, encoding.word_bits())),
+                    _ => return Err("`word` is outside a section".to_string())
+                }
+                *remaining -= 1;
+            },
+            ("symbol", 4) =>
+                self.symbols.push((fields[3].to_string(), try!(parse_section(fields[1])), try!(parse_hex(fields[2])) as u32)),
+            ("relocation", 6) => {
+                let target = match fields[4] {
+                    "section" => RelocationTarget::Section(try!(parse_section(fields[5]))),
+                    "symbol" => RelocationTarget::Symbol(fields[5].to_string()),
+                    _ => return Err(format!("`{}` is not a relocation target", fields[4]))
+                };
+                self.relocations.push(Relocation {
+                    section: try!(parse_section(fields[1])),
+                    offset: try!(parse_hex(fields[2])) as u32,
+                    kind: try!(RelocationKind::from_name(fields[3]).ok_or(format!("`{}` is not a relocation kind", fields[3]))),
+                    target: target
+                });
+            },
+            ("entry", 3) => self.entry = Some((try!(parse_section(fields[1])), try!(parse_hex(fields[2])) as u32)),
+            ("profile", _) | ("endian", _) | ("section", _) | ("word", _) | ("symbol", _) | ("relocation", _) | ("entry", _) =>
+                return Err(format!("wrong number of fields for `{}`", fields[0])),
+            (kind, _) => return Err(format!("`{}` is not an object record", kind))
+        }
+        Ok(())
+    }
+
+    fn section(&self, section: Section) -> Option<&ObjectSection> {
+        self.sections.iter().find(|object_section| object_section.section == section)
+    }
+}
+
+fn parse_section(name: &str) -> Result<Section, String> {
+    Section::from_name(name).ok_or(format!("`{}` is not a section", name))
+}
+
+fn parse_hex(text: &str) -> Result<u64, String> {
+    u64::from_str_radix(text, 16).map_err(|_| format!("`{}` is not a hexadecimal number", text))
+}
+
+// Links objects, each named after the file it was read from, into a program
+// starting at address zero. The sections of each kind are placed together,
+// in the order the objects are given, and each relocation is applied for
+// where its target ends up.
+pub fn link(objects: &[(String, Object)]) -> Result<Program, QuasmError> {
+    let (profile, endian) = match objects.first() {
+        Some(&(_, ref object)) => (object.profile, object.endian),
+        None => return Err(QuasmError::Link(vec!["no objects to link".to_string()]))
+    };
+    let mut errors = Vec::new();
+    for &(ref name, ref object) in objects {
+        if object.profile != profile || object.endian != endian {
+            errors.push(format!("{}: assembled with `--profile {} --endian {}`, but {} was assembled with `--profile {} --endian {}`",
+                name, object.profile.name(), object.endian.name(), objects[0].0, profile.name(), endian.name()));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(QuasmError::Link(errors));
+    }
+    let encoding = profile.encoding();
+
+    // The address each object's sections are placed at, and their words in
+    // output order, each with the object it's from.
+    let mut bases: Vec<HashMap<Section, u32>> = objects.iter().map(|_| HashMap::new()).collect();
+    let mut words: Vec<(usize, i64)> = Vec::new();
+    let mut regions: Vec<Region> = Vec::new();
+    for &section in &[Section::Text, Section::Data] {
+        let start = words.len() as u32;
+        for (index, &(_, ref object)) in objects.iter().enumerate() {
+            if let Some(object_section) = object.section(section) {
+                bases[index].insert(section, words.len() as u32);
+                words.extend(object_section.words.iter().map(|&word| (index, word)));
+            }
+        }
+        if words.len() as u32 > start {
+            regions.push(Region { name: format!(".{}", section.name()), section: section, address: start, size: words.len() as u32 - start });
+        }
+    }
+
+    let mut symbols: HashMap<&str, (u32, Section, usize)> = HashMap::new();
+    for (index, &(ref name, ref object)) in objects.iter().enumerate() {
+        for &(ref symbol, section, offset) in &object.symbols {
+            let address = match bases[index].get(&section) {
+                Some(&base) => base + offset,
+                None => {
+                    errors.push(format!("{}: `{}` is in the {} section, which the object doesn't have", name, symbol, section.name()));
+                    continue;
+                }
+            };
+            match symbols.get(symbol.as_str()) {
+                Some(&(_, _, previous)) =>
+                    errors.push(format!("{}: `{}` is already defined by {}", name, symbol, objects[previous].0)),
+                None => {
+                    symbols.insert(symbol, (address, section, index));
+                }
+            }
+        }
+    }
+
+    for (index, &(ref name, ref object)) in objects.iter().enumerate() {
+        // How far each section has moved from where it was assembled.
+        let delta = |section: Section| {
+            match (bases[index].get(&section), object.section(section)) {
+                (Some(&base), Some(object_section)) => Some(base as i64 - object_section.address as i64),
+                _ => None
+            }
+        };
+        for relocation in &object.relocations {
+            let word_delta = try_link(delta(relocation.section), &mut errors,
+                || format!("{}: relocation in the {} section, which the object doesn't have", name, relocation.section.name()));
+            let target_delta = match relocation.target {
+                RelocationTarget::Section(section) => try_link(delta(section), &mut errors,
+                    || format!("{}: relocation against the {} section, which the object doesn't have", name, section.name())),
+                RelocationTarget::Symbol(ref symbol) => try_link(symbols.get(symbol.as_str()).map(|&(address, _, _)| address as i64), &mut errors,
+                    || format!("{}: `{}` is used, but no object defines it", name, symbol))
+            };
+            let (word_delta, target_delta) = match (word_delta, target_delta) {
+                (Some(word_delta), Some(target_delta)) => (word_delta, target_delta),
+                _ => continue
+            };
+            let length = object.section(relocation.section).unwrap().words.len();
+            if relocation.offset as usize >= length {
+                errors.push(format!("{}: relocation at {} offset {:04X} is outside the section", name, relocation.section.name(), relocation.offset));
+                continue;
+            }
+            let address = bases[index][&relocation.section] + relocation.offset;
+            let word = &mut words[address as usize].1;
+            let (minimum, maximum, value) = match relocation.kind {
+                RelocationKind::Data => {
+                    let (minimum, maximum) = encoding.word_range();
+                    (minimum, maximum, *word + target_delta)
+                },
+                RelocationKind::Absolute | RelocationKind::Relative => {
+                    let (minimum, maximum) = encoding.argument_range();
+                    let (_, argument) = encoding.decode(*word);
+                    let delta = if relocation.kind == RelocationKind::Relative { target_delta - word_delta } else { target_delta };
+                    (minimum, maximum, argument + delta)
+                }
+            };
+            if value < minimum || value > maximum {
+                errors.push(format!("{}: relocated value {} at {:04X} is out of range; expected {} to {}", name, value, address, minimum, maximum));
+                continue;
+            }
+            *word = match relocation.kind {
+                RelocationKind::Data => value,
+                _ => encoding.encode(encoding.decode(*word).0, value)
+            };
+        }
+    }
+
+    let mut entry: Option<(u32, usize)> = None;
+    for (index, &(ref name, ref object)) in objects.iter().enumerate() {
+        if let Some((section, offset)) = object.entry {
+            match entry {
+                Some((_, previous)) =>
+                    errors.push(format!("{}: the entry point is already given by {}", name, objects[previous].0)),
+                None => match bases[index].get(&section) {
+                    Some(&base) => entry = Some((base + offset, index)),
+                    None => errors.push(format!("{}: the entry point is in the {} section, which the object doesn't have", name, section.name()))
+                }
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(QuasmError::Link(errors));
+    }
+
+    let mut labels: Vec<_> = symbols.into_iter()
+        .map(|(name, (address, section, _))| Label { name: name.to_string(), address: address, section: section, global: true })
+        .collect();
+    labels.sort_by(|label, other| (label.address, &label.name).cmp(&(other.address, &other.name)));
+    Ok(Program {
+        bytes: encoder::to_bytes(words.iter().map(|&(_, word)| word).collect(), encoding.word_size(), endian),
+        words: words.iter().enumerate()
+            .map(|(address, &(index, word))| WordInfo {
+                address: address as u32,
+                encoded: word,
+                instruction: None,
+                location: Location { file: Some(objects[index].0.clone()), line_number: 0, column: 0 },
+                source: String::new()
+            })
+            .collect(),
+        lines: Vec::new(),
+        entry: entry.map(|(address, _)| address),
+        origin: 0,
+        gaps: Vec::new(),
+        labels: labels,
+        sections: regions,
+        functions: Vec::new(),
+        relocations: Vec::new(),
+        endian: endian,
+        profile: profile,
+        warnings: Vec::new()
+    })
+}
+
+// Checks that no object holds the absolute address of a label, which would
+// be wrong if the linked program were loaded anywhere but address zero.
+pub fn check_position_independent(objects: &[(String, Object)]) -> Result<(), QuasmError> {
+    let errors: Vec<_> = objects.iter()
+        .flat_map(|&(ref name, ref object)| object.relocations.iter()
+            .filter(|relocation| relocation.kind != RelocationKind::Relative)
+            .map(move |relocation| format!("{}: the word at {} offset {:04X} holds an absolute address, which isn't allowed with `--pic`",
+                name, relocation.section.name(), relocation.offset)))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(QuasmError::Link(errors))
+    }
+}
+
+// The value, or `None` after recording an error if there isn't one. Each
+// error is only recorded once, however many relocations it affects.
+fn try_link<T, F: FnOnce() -> String>(value: Option<T>, errors: &mut Vec<String>, message: F) -> Option<T> {
+    if value.is_none() {
+        let message = message();
+        if !errors.contains(&message) {
+            errors.push(message);
+        }
+    }
+    value
+}
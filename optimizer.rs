@@ -0,0 +1,590 @@
+use std::collections::{HashMap, HashSet};
+
+use encoding::Encoding;
+use expression::{self, Expression};
+use isa::{InstructionSet, Operand};
+use options::Options;
+use parser::{SourceLine, Line, Argument, DataWidth, Section};
+use resolver;
+use symbols::{self, LabelScope};
+
+// An optimization, which can be turned on or off with `--opt=NAME` or
+// `--opt=no-NAME`. Passes always run in the order they're declared here.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Pass {
+    ThreadJumps,
+    DeadCode,
+    FoldConstants,
+    Peephole
+}
+
+// The passes run at each `-O` level. `-O1` runs every pass once, and `-O2`
+// repeats them until they stop changing anything, since one pass can leave
+// more for another to do. Every pass only ever makes code smaller, so `-Os` is
+// the same as `-O2`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OptimizationLevel {
+    O0,
+    O1,
+    O2,
+    Os
+}
+
+impl Pass {
+    pub fn all() -> &'static [Pass] {
+        &[Pass::ThreadJumps, Pass::DeadCode, Pass::FoldConstants, Pass::Peephole]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Pass::ThreadJumps => "thread-jumps",
+            &Pass::DeadCode => "dead-code",
+            &Pass::FoldConstants => "fold-constants",
+            &Pass::Peephole => "peephole"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::all().iter().cloned().find(|pass| pass.name() == name)
+    }
+
+    // Changes the lines in place, returning whether anything changed.
+    fn run<'a>(&self, lines: &mut [SourceLine<'a>], instruction_set: &InstructionSet, encoding: &dyn Encoding) -> bool {
+        match self {
+            &Pass::ThreadJumps => thread_jumps(lines, instruction_set, encoding),
+            &Pass::DeadCode => eliminate_dead_code(lines, instruction_set, encoding),
+            &Pass::FoldConstants => fold_constants(lines, instruction_set, encoding),
+            &Pass::Peephole => peephole(lines, instruction_set, encoding)
+        }
+    }
+}
+
+impl Default for OptimizationLevel {
+    fn default() -> OptimizationLevel {
+        OptimizationLevel::O0
+    }
+}
+
+impl OptimizationLevel {
+    pub fn from_name(name: &str) -> Option<OptimizationLevel> {
+        match name {
+            "0" => Some(OptimizationLevel::O0),
+            "1" => Some(OptimizationLevel::O1),
+            "2" => Some(OptimizationLevel::O2),
+            "s" => Some(OptimizationLevel::Os),
+            _ => None
+        }
+    }
+
+    pub fn passes(&self) -> Vec<Pass> {
+        match self {
+            &OptimizationLevel::O0 => Vec::new(),
+            _ => Pass::all().to_vec()
+        }
+    }
+
+    pub fn repeats(&self) -> bool {
+        *self == OptimizationLevel::O2 || *self == OptimizationLevel::Os
+    }
+}
+
+// Rewrites lines before they're resolved, so that labels and branch offsets
+// are worked out for the optimized code as if it had been written that way.
+// A branch given as an offset rather than a label would no longer go where
+// it was meant to if any code moved, so then nothing is optimized.
+pub fn optimize<'a>(mut lines: Vec<SourceLine<'a>>, instruction_set: &InstructionSet, options: &Options) -> Vec<SourceLine<'a>> {
+    if lines.iter().any(|line| branch_target(line, instruction_set) == Some(None)) {
+        return lines;
+    }
+    let encoding = options.profile.encoding();
+    let mut passes = options.passes.clone();
+    passes.sort();
+    passes.dedup();
+    loop {
+        let mut changed = false;
+        for pass in &passes {
+            changed = pass.run(&mut lines, instruction_set, encoding) || changed;
+        }
+        if !changed || !options.repeat_passes {
+            return lines;
+        }
+    }
+}
+
+// Instructions that never continue to the next one.
+const TERMINATORS: &'static [&'static str] = &["halt", "jmp", "ret"];
+
+pub fn is_terminator<'a>(line: &SourceLine<'a>) -> bool {
+    match line.line {
+        Line::Instruction { opcode, .. } => TERMINATORS.contains(&opcode),
+        _ => false
+    }
+}
+
+// The lines in `.text` that can be reached from where the program starts, or
+// from any label whose address is used other than as the target of a branch.
+// There's no telling if a branch's target isn't a plain label, since it can't
+// be told where it goes.
+pub fn reachable_lines<'a>(lines: &[SourceLine<'a>], instruction_set: &InstructionSet) -> Option<HashSet<usize>> {
+    let scopes = resolver::label_scopes(&lines);
+    let sections = resolver::line_sections(&lines);
+    let targets = Targets::new(&lines, &scopes);
+    let code: Vec<usize> = (0..lines.len()).filter(|&index| sections[index] == Section::Text).collect();
+    let next: HashMap<usize, usize> = code.windows(2).map(|pair| (pair[0], pair[1])).collect();
+
+    // Without a `.start`, the program starts at its first word.
+    let has_start = lines.iter().any(|line| match line.line { Line::Start {..} => true, _ => false });
+    let mut roots: Vec<usize> = code.first().cloned().into_iter().filter(|_| !has_start).collect();
+    for (index, line) in lines.iter().enumerate() {
+        if let Some(target) = branch_target(line, instruction_set) {
+            match target {
+                Some(_) => continue,
+                None => return None
+            }
+        }
+        let names = match line.line {
+            Line::Global(name) => vec![name],
+            _ => line_expressions(line).into_iter().flat_map(|expression| expression::labels(expression)).collect()
+        };
+        roots.extend(names.into_iter().filter_map(|name| targets.find(name, &scopes[index])));
+    }
+
+    let mut reachable = HashSet::new();
+    while let Some(index) = roots.pop() {
+        if sections[index] != Section::Text || !reachable.insert(index) {
+            continue;
+        }
+        let line = &lines[index];
+        if let Some(Some(name)) = branch_target(line, instruction_set) {
+            roots.extend(targets.find(name, &scopes[index]));
+        }
+        if !is_terminator(line) {
+            roots.extend(next.get(&index).cloned());
+        }
+    }
+    Some(reachable)
+}
+
+// Removes each instruction in `.text` that can't be reached. Any label on a
+// removed instruction is kept, on a line of its own.
+fn eliminate_dead_code<'a>(lines: &mut [SourceLine<'a>], instruction_set: &InstructionSet, _encoding: &dyn Encoding) -> bool {
+    let reachable = match reachable_lines(lines, instruction_set) {
+        Some(reachable) => reachable,
+        None => return false
+    };
+    let sections = resolver::line_sections(&lines);
+    let mut changed = false;
+    for index in 0..lines.len() {
+        if let Line::Instruction {..} = lines[index].line {
+            if sections[index] == Section::Text && !reachable.contains(&index) {
+                lines[index].line = Line::Empty;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// Retargets each branch to a `jmp` so that it goes straight to where the
+// `jmp` goes, following chains of them. Trampolines that are no longer used
+// are then removed as dead code. A branch is only retargeted if its label
+// refers to the same place where the branch is, and if the branch is
+// certain to still reach, counting each line between as the most words it
+// could assemble to.
+fn thread_jumps<'a>(lines: &mut [SourceLine<'a>], instruction_set: &InstructionSet, encoding: &dyn Encoding) -> bool {
+    let scopes = resolver::label_scopes(&lines);
+    let sections = resolver::line_sections(&lines);
+    let targets = Targets::new(&lines, &scopes);
+    let (_, maximum) = encoding.argument_range();
+    let mut changed = false;
+    for index in 0..lines.len() {
+        let name = match branch_target(&lines[index], instruction_set) {
+            Some(Some(name)) => name,
+            _ => continue
+        };
+        let mut target = None;
+        let mut visited = HashSet::new();
+        let mut jump = targets.find(name, &scopes[index]).and_then(|line_index| next_instruction(&lines, &sections, line_index));
+        while let Some(jump_index) = jump {
+            if !visited.insert(jump_index) {
+                break;
+            }
+            let jump_name = match lines[jump_index].line {
+                Line::Instruction { opcode: "jmp", arg: Argument::Expression { expression: Expression::Label(jump_name), .. }, .. } => jump_name,
+                _ => break
+            };
+            let destination = match targets.find(jump_name, &scopes[jump_index]) {
+                Some(destination) if targets.find(jump_name, &scopes[index]) == Some(destination) => destination,
+                _ => break
+            };
+            if distance_bound(&lines, &sections, index, destination, encoding).map(|distance| distance <= maximum) != Some(true) {
+                break;
+            }
+            target = Some(jump_name);
+            jump = next_instruction(&lines, &sections, destination);
+        }
+        if let (Some(target), Line::Instruction { ref mut arg, .. }) = (target.filter(|&target| target != name), &mut lines[index].line) {
+            if let &mut Argument::Expression { ref mut expression, .. } = arg {
+                *expression = Expression::Label(target);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// The first instruction at or after `index` in the same section, if no words
+// come before it.
+fn next_instruction<'a>(lines: &[SourceLine<'a>], sections: &[Section], index: usize) -> Option<usize> {
+    for line_index in index..lines.len() {
+        if sections[line_index] != sections[index] {
+            return None;
+        }
+        match lines[line_index].line {
+            Line::Instruction {..} => return Some(line_index),
+            Line::Empty | Line::Constant {..} | Line::Function {..} | Line::EndFunction {..} | Line::Start {..} | Line::Global(_) | Line::Extern(_) | Line::StackEffect {..} => (),
+            _ => return None
+        }
+    }
+    None
+}
+
+// The most words there could be between a branch and its target, or `None`
+// if there's no telling, such as if there's a `.space` between them.
+fn distance_bound<'a>(lines: &[SourceLine<'a>], sections: &[Section], branch: usize, target: usize, encoding: &dyn Encoding) -> Option<i64> {
+    if sections[branch] != sections[target] {
+        return None;
+    }
+    let between = if target > branch { branch + 1..target } else { target..branch + 1 };
+    let word_size = encoding.word_size();
+    let mut distance = 0;
+    for line_index in between.filter(|&line_index| sections[line_index] == sections[branch]) {
+        distance += match lines[line_index].line {
+            Line::Instruction { opcode: "li", .. } => resolver::LOAD_IMMEDIATE_SIZE,
+            Line::Instruction {..} => 1,
+            Line::Data { width: DataWidth::Word, ref values } => values.len() as i64,
+            Line::Data { width: DataWidth::Byte, ref values } => ((values.len() + word_size - 1) / word_size) as i64,
+            Line::String { ref bytes } => ((bytes.len() + word_size - 1) / word_size) as i64,
+            Line::Space {..} | Line::Align {..} | Line::Origin {..} => return None,
+            _ => 0
+        };
+    }
+    Some(distance)
+}
+
+// For an instruction whose argument is a label, the name of the label if
+// it's written plainly, as in `jmp :loop`, and otherwise `None`.
+pub fn branch_target<'a>(line: &SourceLine<'a>, instruction_set: &InstructionSet) -> Option<Option<&'a str>> {
+    match line.line {
+        Line::Instruction { opcode, ref arg, .. } if instruction_set.opcode(opcode).map(|opcode| opcode.operand) == Some(Operand::Label) =>
+            match arg {
+                &Argument::Expression { expression: Expression::Label(name), .. } => Some(Some(name)),
+                _ => Some(None)
+            },
+        _ => None
+    }
+}
+
+// The expressions written on a line.
+pub fn line_expressions<'l, 'a>(line: &'l SourceLine<'a>) -> Vec<&'l Expression<'a>> {
+    let arguments: Vec<&Argument<'a>> = match line.line {
+        Line::Instruction { ref arg, .. } => vec![arg],
+        Line::Constant { ref value, .. } => vec![value],
+        Line::Data { ref values, .. } => values.iter().collect(),
+        Line::Space { ref count, .. } => vec![count],
+        Line::Align { ref alignment, .. } => vec![alignment],
+        Line::Origin { ref address, .. } => vec![address],
+        Line::Start { ref label, .. } => vec![label],
+        _ => Vec::new()
+    };
+    arguments.into_iter()
+        .filter_map(|argument| match argument {
+            &Argument::Expression { ref expression, .. } => Some(expression),
+            &Argument::None | &Argument::Invalid => None
+        })
+        .collect()
+}
+
+// The line each label is defined on, found as the resolver does.
+pub struct Targets {
+    named: HashMap<String, usize>,
+    anonymous: HashMap<String, Vec<usize>>
+}
+
+impl Targets {
+    pub fn new<'a>(lines: &[SourceLine<'a>], scopes: &[LabelScope]) -> Targets {
+        let mut named = HashMap::new();
+        let mut anonymous = HashMap::new();
+        for (index, line) in lines.iter().enumerate() {
+            match line.label {
+                Some(name) if symbols::is_anonymous_label(name) =>
+                    anonymous.entry(name.to_string()).or_insert(Vec::new()).push(index),
+                Some(name) => {
+                    named.entry(symbols::qualify_label(&scopes[index].name, name)).or_insert(index);
+                },
+                None => ()
+            }
+        }
+        Targets { named: named, anonymous: anonymous }
+    }
+
+    pub fn find(&self, name: &str, scope: &LabelScope) -> Option<usize> {
+        match symbols::anonymous_reference(name) {
+            Some((number, forward)) => self.anonymous.get(number).and_then(|definitions| if forward {
+                definitions.iter().cloned().find(|&index| index > scope.line)
+            } else {
+                definitions.iter().cloned().rev().find(|&index| index <= scope.line)
+            }),
+            None => self.named.get(&symbols::qualify_label(&scope.name, name)).cloned()
+        }
+    }
+}
+
+// Runs of instructions that can be replaced by a shorter one, or removed,
+// each with what it's replaced by. An argument of `None` matches an
+// instruction without one.
+const PATTERNS: &'static [(&'static [(&'static str, Option<i64>)], Option<(&'static str, Option<i64>)>)] = &[
+    (&[("const", Some(0)), ("add", None)], None),
+    (&[("const", Some(0)), ("sub", None)], None),
+    (&[("const", Some(0)), ("or", None)], None),
+    (&[("const", Some(0)), ("xor", None)], None),
+    (&[("const", Some(1)), ("mul", None)], None),
+    (&[("const", Some(1)), ("div", None)], None),
+    (&[("const", Some(-1)), ("mul", None)], Some(("neg", None))),
+    (&[("shli", Some(0))], None),
+    (&[("shri", Some(0))], None),
+    (&[("swap", Some(1)), ("swap", Some(1))], None),
+    (&[("dup", None), ("pop", None)], None),
+    (&[("neg", None), ("neg", None)], None),
+    (&[("not", None), ("not", None)], None)
+];
+
+// Replaces each run of instructions that matches a pattern, until none do.
+fn peephole<'a>(lines: &mut [SourceLine<'a>], _instruction_set: &InstructionSet, _encoding: &dyn Encoding) -> bool {
+    let mut changed = false;
+    let mut matched_any = true;
+    while matched_any {
+        matched_any = false;
+        for index in 0..lines.len() {
+            let found = PATTERNS.iter()
+                .filter_map(|&(pattern, replacement)| match_pattern(lines, index, pattern).map(|matched| (matched, replacement)))
+                .next();
+            if let Some((matched, replacement)) = found {
+                rewrite(lines, &matched, replacement);
+                matched_any = true;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// The indices of the instructions matching `pattern` starting at `index`.
+fn match_pattern<'a>(lines: &[SourceLine<'a>], index: usize, pattern: &[(&str, Option<i64>)]) -> Option<Vec<usize>> {
+    let instructions = match run(lines, index, pattern.len()) {
+        Some(instructions) => instructions,
+        None => return None
+    };
+    let is_match = instructions.iter().zip(pattern).all(|(&(_, opcode, arg), &(pattern_opcode, value))| {
+        opcode == pattern_opcode && match (arg, value) {
+            (&Argument::Expression { ref expression, .. }, Some(value)) => literal(expression) == Some(value),
+            (&Argument::None, None) => true,
+            _ => false
+        }
+    });
+    if is_match {
+        Some(instructions.iter().map(|&(line_index, _, _)| line_index).collect())
+    } else {
+        None
+    }
+}
+
+// Replaces each run of instructions that only works out a constant, such as
+// `const 2; const 3; mul`, with a `const` of the result. Runs are found as
+// by `peephole`, and aren't folded if the result is out of range for
+// `const` or the instruction would trap.
+fn fold_constants<'a>(lines: &mut [SourceLine<'a>], _instruction_set: &InstructionSet, encoding: &dyn Encoding) -> bool {
+    let (minimum, maximum) = encoding.argument_range();
+    let mut changed = false;
+    let mut folded_any = true;
+    while folded_any {
+        folded_any = false;
+        for index in 0..lines.len() {
+            let folded = fold_binary(lines, index, encoding).or_else(|| fold_unary(lines, index, encoding));
+            if let Some((matched, value)) = folded {
+                if value >= minimum && value <= maximum {
+                    rewrite(lines, &matched, Some(("const", Some(value))));
+                    folded_any = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+    changed
+}
+
+// `const A; const B; OPERATION`, where the operation pops B and then A.
+fn fold_binary<'a>(lines: &[SourceLine<'a>], index: usize, encoding: &dyn Encoding) -> Option<(Vec<usize>, i64)> {
+    let instructions = match run(lines, index, 3) {
+        Some(instructions) => instructions,
+        None => return None
+    };
+    let (second, top) = match (const_value(&instructions[0], encoding), const_value(&instructions[1], encoding)) {
+        (Some(second), Some(top)) => (second, top),
+        _ => return None
+    };
+    let bits = encoding.word_bits() as i64;
+    let value = match instructions[2].1 {
+        "add" => second.checked_add(top),
+        "sub" => second.checked_sub(top),
+        "mul" => second.checked_mul(top),
+        "div" => second.checked_div(top),
+        "mod" => second.checked_rem(top),
+        "and" => Some(second & top),
+        "or" => Some(second | top),
+        "xor" => Some(second ^ top),
+        "shl" if top >= 0 && top < bits => second.checked_mul(1 << top),
+        "shr" if top >= 0 && top < bits && second >= 0 => Some(second >> top),
+        _ => None
+    };
+    value.map(|value| (instructions.iter().map(|&(line_index, _, _)| line_index).collect(), value))
+}
+
+// `const A; OPERATION`, where the operation pops A, and any argument it has
+// is a literal.
+fn fold_unary<'a>(lines: &[SourceLine<'a>], index: usize, encoding: &dyn Encoding) -> Option<(Vec<usize>, i64)> {
+    let instructions = match run(lines, index, 2) {
+        Some(instructions) => instructions,
+        None => return None
+    };
+    let value = match const_value(&instructions[0], encoding) {
+        Some(value) => value,
+        None => return None
+    };
+    let argument = match instructions[1].2 {
+        &Argument::Expression { ref expression, .. } => literal(expression),
+        _ => None
+    };
+    let bits = encoding.word_bits() as i64;
+    let result = match (instructions[1].1, argument) {
+        ("neg", None) => value.checked_neg(),
+        ("not", None) => Some(!value),
+        ("shli", Some(shift)) if shift >= 0 && shift < bits => value.checked_mul(1 << shift),
+        ("shri", Some(shift)) if shift >= 0 && shift < bits && value >= 0 => Some(value >> shift),
+        _ => None
+    };
+    result.map(|result| (instructions.iter().map(|&(line_index, _, _)| line_index).collect(), result))
+}
+
+// The value a `const` pushes, read as the resolver would.
+fn const_value<'a>(instruction: &(usize, &'a str, &Argument<'a>), encoding: &dyn Encoding) -> Option<i64> {
+    match instruction {
+        &(_, "const", &Argument::Expression { ref expression, text }) =>
+            literal(expression).map(|value| resolver::immediate_value(value, text, encoding)),
+        _ => None
+    }
+}
+
+// The index, opcode and argument of each of `count` instructions starting at
+// `index`, skipping over any empty lines without labels between them. Only
+// the first instruction may have a label, since a branch could go to the
+// middle of the run.
+fn run<'l, 'a>(lines: &'l [SourceLine<'a>], index: usize, count: usize) -> Option<Vec<(usize, &'a str, &'l Argument<'a>)>> {
+    let mut instructions = Vec::new();
+    let mut line_index = index;
+    while instructions.len() < count {
+        if !instructions.is_empty() {
+            while line_index < lines.len() && lines[line_index].label.is_none() && is_empty(&lines[line_index]) {
+                line_index += 1;
+            }
+        }
+        match lines.get(line_index) {
+            Some(&SourceLine { label, line: Line::Instruction { opcode, ref arg, .. }, .. }) if instructions.is_empty() || label.is_none() =>
+                instructions.push((line_index, opcode, arg)),
+            _ => return None
+        }
+        line_index += 1;
+    }
+    Some(instructions)
+}
+
+// Replaces the instructions at `matched` with `replacement`, if there is one,
+// which takes the place of the first. Removed instructions are left as empty
+// lines, so they're still listed, and keep any label.
+fn rewrite<'a>(lines: &mut [SourceLine<'a>], matched: &[usize], replacement: Option<(&'static str, Option<i64>)>) {
+    let token = match lines[matched[0]].line {
+        Line::Instruction { token, .. } => token,
+        _ => return
+    };
+    for &line_index in matched {
+        lines[line_index].line = Line::Empty;
+    }
+    if let Some((opcode, value)) = replacement {
+        let arg = match value {
+            Some(value) => Argument::Expression { expression: Expression::Integer(value), text: token },
+            None => Argument::None
+        };
+        lines[matched[0]].line = Line::Instruction { opcode: opcode, token: token, arg: arg };
+    }
+}
+
+// The value of an integer literal, which may be negated.
+pub fn literal(expression: &Expression) -> Option<i64> {
+    match expression {
+        &Expression::Integer(value) => Some(value),
+        &Expression::Negate(_, ref operand) => literal(operand).and_then(|value| value.checked_neg()),
+        _ => None
+    }
+}
+
+fn is_empty(line: &SourceLine) -> bool {
+    match line.line {
+        Line::Empty => true,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use encoding::Profile;
+    use options::Options;
+    use vm::{Machine, MEMORY_WORDS};
+    use super::*;
+
+    // What's left on the stack after running `source`, assembled with `level`.
+    fn run(source: &str, profile: Profile, level: OptimizationLevel) -> Vec<i64> {
+        let mut options = Options::default();
+        options.profile = profile;
+        options.passes = level.passes();
+        options.repeat_passes = level.repeats();
+        let program = match ::assemble_with_options(source, &options) {
+            Ok(program) => program,
+            Err(error) => panic!("{}\n{}", error, source)
+        };
+        let words: Vec<i64> = program.words.iter().map(|word| word.encoded).collect();
+        let mut machine = Machine::new(&words, program.origin, program.entry, options.instruction_set, profile, MEMORY_WORDS).unwrap();
+        machine.run(&mut io::empty(), &mut io::sink()).ok().unwrap();
+        machine.stack
+    }
+
+    #[test]
+    fn folding_bit_patterns_keeps_their_values() {
+        let sources = [
+            "const 0xFF00\nconst 8\nshr\n",
+            "const 0xFFFE\nconst 2\ndiv\n",
+            "const 0x8000\nneg\n",
+            "const 0b1111111111111111\nnot\n",
+            "const 0o177777\nconst 1\nadd\n",
+            "const 0xFFFF\nshri 4\n"
+        ];
+        for &profile in &[Profile::Word32, Profile::Word64] {
+            for source in sources.iter() {
+                let unoptimized = run(source, profile, OptimizationLevel::O0);
+                let optimized = run(source, profile, OptimizationLevel::O1);
+                assert!(unoptimized == optimized, "{} gives {:?} at -O0 but {:?} at -O1:\n{}", profile.name(), unoptimized, optimized, source);
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use expression::{self, Environment, EvaluationErrorKind, Value};
+use encoder::Endian;
+use encoding::Profile;
+use extension::OpcodeExtension;
+use isa::InstructionSet;
+use lint::Lints;
+use optimizer::Pass;
+use parser;
+
+#[derive(Default)]
+pub struct Options {
+    // Constants predefined before assembly starts, as if by `.equ`.
+    pub defines: HashMap<String, i64>,
+    // Warn when execution can run off the end of the program, rather than
+    // reaching a `halt` or an unconditional jump.
+    pub warn_fall_through: bool,
+    // Make running off the end of the program an error rather than a warning.
+    pub strict: bool,
+    // Warn about instructions that follow a `halt`, `ret` or unconditional
+    // jump with no label in between.
+    pub warn_unreachable: bool,
+    // Warn about labels that nothing refers to, other than `.global` ones.
+    pub warn_unused_labels: bool,
+    // Warn about instructions that can take more values than are on the
+    // stack, and lines reached with different stack depths, throughout the
+    // program rather than only in functions given a stack effect.
+    pub warn_stack: bool,
+    // The rules `quasm lint` checks, if it's linting.
+    pub lints: Option<Lints>,
+    // Make every warning an error.
+    pub deny_warnings: bool,
+    // The opcodes to assemble for, which are the built-in ones unless read
+    // from a spec with `--isa-spec`.
+    pub instruction_set: InstructionSet,
+    pub extensions: Vec<Box<dyn OpcodeExtension>>,
+    // The byte order of each output word.
+    pub endian: Endian,
+    // How instructions are encoded, and so the size of a word.
+    pub profile: Profile,
+    // Assemble an object to be linked with others. Labels named by `.extern`
+    // are imported from the other objects, and each word that depends on
+    // where a label ends up is recorded as a relocation.
+    pub object: bool,
+    // Reject any use of a label's absolute address, so that the program
+    // runs wherever it's loaded.
+    pub pic: bool,
+    // The optimizer passes to run, as chosen with `-O` and `--opt`.
+    pub passes: Vec<Pass>,
+    // Repeat the passes until they stop changing anything.
+    pub repeat_passes: bool
+}
+
+struct NoSymbols;
+
+impl Environment for NoSymbols {
+    fn label(&self, _name: &str) -> Result<Value, EvaluationErrorKind> {
+        Err(EvaluationErrorKind::UndefinedLabel)
+    }
+
+    fn symbol(&self, _name: &str) -> Result<Value, EvaluationErrorKind> {
+        Err(EvaluationErrorKind::UndefinedSymbol)
+    }
+}
+
+impl Options {
+    // Adds a definition in the command-line form `NAME=VALUE`, where VALUE
+    // is a constant expression. A bare `NAME` defines NAME as 1.
+    pub fn define(&mut self, definition: &str) -> Result<(), String> {
+        let (name, value) = match definition.find('=') {
+            Some(index) => (&definition[..index], &definition[index + 1..]),
+            None => (definition, "1")
+        };
+        if !parser::is_symbol_name(name) {
+            return Err(format!("`{}` is not a valid symbol name", name));
+        }
+        let value = match expression::parse(value).ok().map(|value| expression::evaluate(&value, &NoSymbols)) {
+            Some(Ok(Value::Integer(value))) => value,
+            _ => return Err(format!("`{}` is not a constant integer expression", value))
+        };
+        self.defines.insert(name.to_string(), value);
+        Ok(())
+    }
+}
@@ -0,0 +1,501 @@
+use error::{Diagnostic, DiagnosticKind, Location};
+use expression::{self, Expression, SyntaxError, SyntaxErrorKind};
+use extension::OpcodeExtension;
+use literals;
+use suggestions;
+
+pub struct SourceLine<'a> {
+    pub file: Option<&'a str>,
+    pub line_number: usize,
+    pub text: &'a str,
+    pub label: Option<&'a str>,
+    pub line: Line<'a>
+}
+
+// `token` is where an instruction's opcode is written, which is only
+// different from `opcode` for instructions expanded from another.
+pub enum Line<'a> {
+    Instruction { opcode: &'a str, token: &'a str, arg: Argument<'a> },
+    Constant { name: &'a str, value: Argument<'a> },
+    Data { width: DataWidth, values: Vec<Argument<'a>> },
+    String { bytes: Vec<u8> },
+    Space { directive: &'a str, count: Argument<'a> },
+    Align { directive: &'a str, alignment: Argument<'a> },
+    Origin { directive: &'a str, address: Argument<'a> },
+    Section(Section),
+    Function { directive: &'a str, name: &'a str },
+    EndFunction { directive: &'a str },
+    Start { directive: &'a str, label: Argument<'a> },
+    // `.global name`, which exports a label from an object.
+    Global(&'a str),
+    // `.extern name`, which imports a label from another object.
+    Extern(&'a str),
+    // `.stack -POPS +PUSHES`, which gives how many values a function takes
+    // from the stack and leaves on it.
+    StackEffect { directive: &'a str, pops: i64, pushes: i64 },
+    Empty
+}
+
+// Sections are output in the order they're declared here.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Section {
+    Text,
+    Data
+}
+
+impl Section {
+    // The name of the section, without the leading `.` of its directive.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Section::Text => "text",
+            &Section::Data => "data"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Section> {
+        match name {
+            "text" => Some(Section::Text),
+            "data" => Some(Section::Data),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum DataWidth {
+    Byte,
+    Word
+}
+
+// An argument that couldn't be parsed is `Invalid`, and has already been
+// reported.
+pub enum Argument<'a> {
+    Expression { expression: Expression<'a>, text: &'a str },
+    None,
+    Invalid
+}
+
+impl<'a> SourceLine<'a> {
+    pub fn location(&self, token: &str) -> Location {
+        let column = token.as_ptr() as usize - self.text.as_ptr() as usize + 1;
+        Location { file: self.file.map(|file| file.to_string()), line_number: self.line_number, column: column }
+    }
+}
+
+pub fn parse_line<'a>(file: Option<&'a str>, line_number: usize, text: &'a str, extensions: &[Box<dyn OpcodeExtension>], diagnostics: &mut Vec<Diagnostic>) -> SourceLine<'a> {
+    let mut source_line = SourceLine { file: file, line_number: line_number, text: text, label: None, line: Line::Empty };
+    let mut parts = tokenize(text).into_iter().peekable();
+    if let Some(label) = parts.peek().and_then(|part| parse_label_definition(part)) {
+        source_line.label = Some(label);
+        parts.next();
+    }
+    let rest: Vec<&str> = parts.collect();
+    if let Some((&opcode, operands)) = rest.split_first() {
+        source_line.line = if opcode.starts_with(".") {
+            parse_directive(&source_line, opcode, operands, extensions, diagnostics)
+        } else {
+            let arg = parse_argument(&source_line, operands, diagnostics);
+            Line::Instruction { opcode: opcode, token: opcode, arg: arg }
+        };
+    }
+    // A function's name is also a label for its first instruction.
+    if let Line::Function { name, .. } = source_line.line {
+        if let Some(label) = source_line.label {
+            diagnostics.push(Diagnostic::new(
+                source_line.location(label),
+                DiagnosticKind::UnexpectedToken { token: label.to_string() }));
+        }
+        source_line.label = Some(name);
+    }
+    source_line
+}
+
+const DIRECTIVES: &'static [&'static str] = &[
+    ".equ", ".include", ".macro", ".endmacro", ".if", ".ifdef", ".ifndef", ".else", ".endif", ".rept", ".endr",
+    ".word", ".byte", ".string", ".space", ".zero", ".align", ".org", ".text", ".data", ".func", ".endfunc", ".start",
+    ".global", ".extern", ".stack"
+];
+
+// Finds the path of an `.include` directive, along with the column it
+// starts at. Included files are loaded before the rest of parsing, which
+// reports any malformed `.include` lines.
+pub fn parse_include(text: &str) -> Option<(usize, String)> {
+    let tokens: Vec<&str> = tokenize(text).into_iter()
+        .skip_while(|token| parse_label_definition(token).is_some())
+        .collect();
+    match &tokens[..] {
+        [".include", path] => match parse_string(path) {
+            Some(Ok(bytes)) => Some((path.as_ptr() as usize - text.as_ptr() as usize + 1, String::from_utf8_lossy(&bytes).into_owned())),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+fn parse_directive<'a>(source_line: &SourceLine<'a>, directive: &'a str, operands: &[&'a str], extensions: &[Box<dyn OpcodeExtension>], diagnostics: &mut Vec<Diagnostic>) -> Line<'a> {
+    match directive {
+        ".equ" => match operands.split_first() {
+            Some((&name, value)) if is_symbol_name(name) && !value.is_empty() =>
+                match parse_argument(source_line, value, diagnostics) {
+                    Argument::None | Argument::Invalid => Line::Empty,
+                    value => Line::Constant { name: name, value: value }
+                },
+            Some((&name, _)) if !is_symbol_name(name) => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(name),
+                    DiagnosticKind::BadSymbolName { name: name.to_string() }));
+                Line::Empty
+            },
+            _ => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::MissingOperand { directive: directive.to_string() }));
+                Line::Empty
+            }
+        },
+        ".include" => {
+            match operands {
+                [path] if parse_string(path).is_some() =>
+                    check_string(source_line, path, diagnostics),
+                [path, ..] => diagnostics.push(Diagnostic::new(
+                    source_line.location(path),
+                    DiagnosticKind::ExpectedString { argument: span(source_line.text, path, operands[operands.len() - 1]).to_string() })),
+                [] => diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::MissingOperand { directive: directive.to_string() }))
+            }
+            Line::Empty
+        },
+        ".word" | ".byte" => {
+            let width = if directive == ".word" { DataWidth::Word } else { DataWidth::Byte };
+            match (operands.first(), operands.last()) {
+                (Some(first), Some(last)) => {
+                    let values = split_list(span(source_line.text, first, last)).into_iter()
+                        .map(|value| match parse_arg(value) {
+                            Ok(arg) => arg,
+                            Err(error) => {
+                                diagnostics.push(syntax_error_diagnostic(source_line, error));
+                                Argument::Invalid
+                            }
+                        })
+                        .collect();
+                    Line::Data { width: width, values: values }
+                },
+                _ => {
+                    diagnostics.push(Diagnostic::new(
+                        source_line.location(directive),
+                        DiagnosticKind::MissingOperand { directive: directive.to_string() }));
+                    Line::Empty
+                }
+            }
+        },
+        ".space" | ".zero" if !operands.is_empty() =>
+            Line::Space { directive: directive, count: parse_argument(source_line, operands, diagnostics) },
+        ".align" if !operands.is_empty() =>
+            Line::Align { directive: directive, alignment: parse_argument(source_line, operands, diagnostics) },
+        ".org" if !operands.is_empty() =>
+            Line::Origin { directive: directive, address: parse_argument(source_line, operands, diagnostics) },
+        ".start" if !operands.is_empty() =>
+            Line::Start { directive: directive, label: parse_argument(source_line, operands, diagnostics) },
+        ".text" | ".data" => {
+            if let Some(operand) = operands.first() {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(operand),
+                    DiagnosticKind::UnexpectedToken { token: operand.to_string() }));
+            }
+            Line::Section(if directive == ".text" { Section::Text } else { Section::Data })
+        },
+        ".func" | ".global" | ".extern" => match operands {
+            [name] if is_symbol_name(name) => match directive {
+                ".func" => Line::Function { directive: directive, name: name },
+                ".global" => Line::Global(name),
+                _ => Line::Extern(name)
+            },
+            [name, ..] => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(name),
+                    DiagnosticKind::BadSymbolName { name: span(source_line.text, name, operands[operands.len() - 1]).to_string() }));
+                Line::Empty
+            },
+            [] => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::MissingOperand { directive: directive.to_string() }));
+                Line::Empty
+            }
+        },
+        ".endfunc" => {
+            if let Some(operand) = operands.first() {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(operand),
+                    DiagnosticKind::UnexpectedToken { token: operand.to_string() }));
+            }
+            Line::EndFunction { directive: directive }
+        },
+        ".stack" => match operands {
+            [pops, pushes] => match (parse_stack_count(pops, "-"), parse_stack_count(pushes, "+")) {
+                (Some(pops), Some(pushes)) => Line::StackEffect { directive: directive, pops: pops, pushes: pushes },
+                _ => {
+                    diagnostics.push(Diagnostic::new(
+                        source_line.location(pops),
+                        DiagnosticKind::BadStackEffect { argument: span(source_line.text, pops, pushes).to_string() }));
+                    Line::Empty
+                }
+            },
+            [first, ..] => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(first),
+                    DiagnosticKind::BadStackEffect { argument: span(source_line.text, first, operands[operands.len() - 1]).to_string() }));
+                Line::Empty
+            },
+            [] => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::MissingOperand { directive: directive.to_string() }));
+                Line::Empty
+            }
+        },
+        ".space" | ".zero" | ".align" | ".org" | ".start" => {
+            diagnostics.push(Diagnostic::new(
+                source_line.location(directive),
+                DiagnosticKind::MissingOperand { directive: directive.to_string() }));
+            Line::Empty
+        },
+        ".string" => match (operands, operands.first().and_then(|string| parse_string(string))) {
+            ([_], Some(Ok(bytes))) => Line::String { bytes: bytes },
+            ([string], Some(Err(_))) => {
+                check_string(source_line, string, diagnostics);
+                Line::Empty
+            },
+            ([string, ..], _) => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(string),
+                    DiagnosticKind::ExpectedString { argument: span(source_line.text, string, operands[operands.len() - 1]).to_string() }));
+                Line::Empty
+            },
+            ([], _) => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::MissingOperand { directive: directive.to_string() }));
+                Line::Empty
+            }
+        },
+        _ => match extension_directive(source_line, directive, operands, extensions) {
+            Some(Ok(words)) => Line::Data {
+                width: DataWidth::Word,
+                values: words.into_iter()
+                    .map(|word| Argument::Expression { expression: Expression::Integer(word), text: directive })
+                    .collect()
+            },
+            Some(Err(message)) => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::ExtensionFailed { directive: directive.to_string(), message: message }));
+                Line::Empty
+            },
+            None => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::UnknownDirective {
+                    directive: directive.to_string(),
+                        suggestion: suggestions::closest(directive, DIRECTIVES.iter().map(|&name| name))
+                            .map(|name| name.to_string())
+                    }));
+                Line::Empty
+            }
+        }
+    }
+}
+
+// Asks each extension in turn to handle a directive that isn't built in.
+fn extension_directive<'a>(source_line: &SourceLine<'a>, directive: &str, operands: &[&'a str], extensions: &[Box<dyn OpcodeExtension>]) -> Option<Result<Vec<i64>, String>> {
+    let text = match (operands.first(), operands.last()) {
+        (Some(first), Some(last)) => span(source_line.text, first, last),
+        _ => ""
+    };
+    extensions.iter()
+        .filter_map(|extension| extension.directive(directive, text))
+        .next()
+}
+
+fn parse_argument<'a>(source_line: &SourceLine<'a>, tokens: &[&'a str], diagnostics: &mut Vec<Diagnostic>) -> Argument<'a> {
+    match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => match parse_arg(span(source_line.text, first, last)) {
+            Ok(arg) => arg,
+            Err(error) => {
+                diagnostics.push(syntax_error_diagnostic(source_line, error));
+                Argument::Invalid
+            }
+        },
+        _ => Argument::None
+    }
+}
+
+// The bytes of a quoted string with its escapes decoded, or the malformed
+// escape. Gives `None` if `token` isn't a quoted string at all.
+fn parse_string<'a>(token: &'a str) -> Option<Result<Vec<u8>, &'a str>> {
+    if token.len() >= 2 && token.starts_with("\"") && token.ends_with("\"") {
+        let contents = &token[1..token.len() - 1];
+        Some(literals::unescape(contents).map_err(|(start, end)| &contents[start..end]))
+    } else {
+        None
+    }
+}
+
+// Reports any malformed escape in a quoted string.
+fn check_string(source_line: &SourceLine, token: &str, diagnostics: &mut Vec<Diagnostic>) {
+    if let Some(Err(escape)) = parse_string(token) {
+        diagnostics.push(Diagnostic::new(
+            source_line.location(escape),
+            DiagnosticKind::BadEscape { escape: escape.to_string() }));
+    }
+}
+
+pub fn is_symbol_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_alphabetic() || c == '_') && name.chars().all(expression::is_name_character)
+}
+
+// Labels are defined either as `:name` or `name:`, and are always stored
+// without the colon.
+pub fn parse_label_definition(part: &str) -> Option<&str> {
+    if part.starts_with(":") {
+        Some(&part[1..])
+    } else if part.ends_with(":") {
+        Some(&part[..part.len() - 1])
+    } else {
+        None
+    }
+}
+
+// A count of values in `.stack`, written with the given sign unless it's
+// zero, as in `-2` and `+1`.
+fn parse_stack_count(text: &str, sign: &str) -> Option<i64> {
+    if text == "0" {
+        Some(0)
+    } else if text.starts_with(sign) && text[1..].chars().all(|c| c.is_digit(10)) {
+        text[1..].parse().ok()
+    } else {
+        None
+    }
+}
+
+// The trimmed text of a line's `;` or `#` comment, if it has one.
+pub fn line_comment(text: &str) -> Option<&str> {
+    let rest = match tokenize(text).last() {
+        Some(token) => &text[token.as_ptr() as usize - text.as_ptr() as usize + token.len()..],
+        None => text
+    }.trim_start();
+    if rest.starts_with(";") || rest.starts_with("#") {
+        Some(rest[1..].trim())
+    } else {
+        None
+    }
+}
+
+// The stack effect given by a Forth-style comment, such as `; ( a b -- c )`
+// for a function that takes two values and leaves one, as the number of
+// values before and after the `--`.
+pub fn stack_comment(text: &str) -> Option<(i64, i64)> {
+    let comment = match line_comment(text) {
+        Some(comment) => comment,
+        None => return None
+    };
+    if !comment.starts_with("(") || !comment.ends_with(")") {
+        return None;
+    }
+    let names: Vec<_> = comment[1..comment.len() - 1].split_whitespace().collect();
+    match names.iter().position(|&name| name == "--") {
+        Some(position) if !names[position + 1..].contains(&"--") =>
+            Some((position as i64, (names.len() - position - 1) as i64)),
+        _ => None
+    }
+}
+
+// Splits a line into whitespace-separated tokens, stopping at a `;` or `#`
+// comment. Quoted literals such as `' '` and `';'` are kept as one token.
+pub fn tokenize(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        if let Some(delimiter) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delimiter {
+                quote = None;
+            }
+        } else if c.is_whitespace() || c == ';' || c == '#' {
+            if let Some(token_start) = start {
+                tokens.push(&text[token_start..index]);
+                start = None;
+            }
+            if !c.is_whitespace() {
+                return tokens;
+            }
+        } else {
+            if start.is_none() {
+                start = Some(index);
+            }
+            if c == '\'' || c == '"' {
+                quote = Some(c);
+            }
+        }
+    }
+    if let Some(token_start) = start {
+        tokens.push(&text[token_start..]);
+    }
+    tokens
+}
+
+// Splits a comma-separated list, ignoring commas inside quoted literals such
+// as `','`. Each item is trimmed, but remains a slice of `text`.
+fn split_list(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        if let Some(delimiter) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delimiter {
+                quote = None;
+            }
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+        } else if c == ',' {
+            items.push(text[start..index].trim());
+            start = index + 1;
+        }
+    }
+    items.push(text[start..].trim());
+    items
+}
+
+fn parse_arg<'a>(text: &'a str) -> Result<Argument<'a>, SyntaxError<'a>> {
+    expression::parse(text).map(|expression| Argument::Expression { expression: expression, text: text })
+}
+
+fn syntax_error_diagnostic(source_line: &SourceLine, error: SyntaxError) -> Diagnostic {
+    let token = error.token.to_string();
+    let kind = match error.kind {
+        SyntaxErrorKind::MalformedLiteral => DiagnosticKind::BadArgument { argument: token },
+        SyntaxErrorKind::LiteralOutOfRange => DiagnosticKind::ImmediateOutOfRange { literal: token },
+        SyntaxErrorKind::UnexpectedToken => DiagnosticKind::UnexpectedToken { token: token },
+        SyntaxErrorKind::UnexpectedEnd => DiagnosticKind::UnexpectedEndOfArgument
+    };
+    Diagnostic::new(source_line.location(error.token), kind)
+}
+
+// The text from the start of `first` to the end of `last`, both of which are
+// tokens within `text`.
+pub fn span<'a>(text: &'a str, first: &str, last: &str) -> &'a str {
+    let start = first.as_ptr() as usize - text.as_ptr() as usize;
+    let end = last.as_ptr() as usize - text.as_ptr() as usize + last.len();
+    &text[start..end]
+}
@@ -0,0 +1,474 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use error::{Diagnostic, DiagnosticKind, Location};
+use expression::{self, Environment, EvaluationErrorKind, Value};
+use options::Options;
+use parser;
+
+// A line after `.include` expansion. Lines produced by expanding a macro own
+// their substituted text, and keep the location of the line in the macro body.
+#[derive(Clone)]
+pub struct LogicalLine<'a> {
+    pub file: Option<&'a str>,
+    pub line_number: usize,
+    pub text: Cow<'a, str>
+}
+
+impl<'a> LogicalLine<'a> {
+    fn location(&self, token: &str) -> Location {
+        let column = token.as_ptr() as usize - self.text.as_ptr() as usize + 1;
+        Location { file: self.file.map(|file| file.to_string()), line_number: self.line_number, column: column }
+    }
+
+    fn with_text(&self, text: String) -> LogicalLine<'a> {
+        LogicalLine { file: self.file, line_number: self.line_number, text: Cow::Owned(text) }
+    }
+}
+
+struct Macro<'a> {
+    location: Location,
+    parameters: Vec<String>,
+    body: Vec<LogicalLine<'a>>
+}
+
+enum Directive {
+    Macro { name: String, parameters: Vec<String> },
+    Repeat { count: Option<i64>, counter: Option<String> },
+    End(&'static str),
+    Invocation { label: Option<String>, name: String, arguments: Vec<String> },
+    None
+}
+
+struct Conditional {
+    location: Location,
+    enclosing_active: bool,
+    taken: bool,
+    active: bool,
+    seen_else: bool
+}
+
+// The constants that conditions can use: those defined on the command line,
+// and `.equ` constants defined earlier in the source. A `.equ` constant whose
+// value depends on labels or later constants has no value at this point.
+struct Constants {
+    values: HashMap<String, Option<i64>>
+}
+
+impl Environment for Constants {
+    fn label(&self, _name: &str) -> Result<Value, EvaluationErrorKind> {
+        Err(EvaluationErrorKind::NotConstant)
+    }
+
+    fn symbol(&self, name: &str) -> Result<Value, EvaluationErrorKind> {
+        match self.values.get(name) {
+            Some(&Some(value)) => Ok(Value::Integer(value)),
+            Some(&None) => Err(EvaluationErrorKind::NotConstant),
+            None => Err(EvaluationErrorKind::UndefinedSymbol)
+        }
+    }
+}
+
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+struct Preprocessor<'a> {
+    macros: HashMap<String, Macro<'a>>,
+    constants: Constants,
+    expansions: usize,
+    output: Vec<LogicalLine<'a>>
+}
+
+pub fn preprocess<'a>(lines: Vec<LogicalLine<'a>>, options: &Options, diagnostics: &mut Vec<Diagnostic>) -> Vec<LogicalLine<'a>> {
+    let constants = options.defines.iter().map(|(name, &value)| (name.clone(), Some(value))).collect();
+    let mut preprocessor = Preprocessor {
+        macros: HashMap::new(),
+        constants: Constants { values: constants },
+        expansions: 0,
+        output: Vec::new()
+    };
+    preprocessor.process(lines, 0, diagnostics);
+    preprocessor.output
+}
+
+impl<'a> Preprocessor<'a> {
+    fn process(&mut self, lines: Vec<LogicalLine<'a>>, depth: usize, diagnostics: &mut Vec<Diagnostic>) {
+        let mut lines = lines.into_iter();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        while let Some(line) = lines.next() {
+            let active = conditionals.last().map_or(true, |conditional| conditional.active);
+            if self.process_conditional(&line, active, &mut conditionals, diagnostics) || !active {
+                continue;
+            }
+            match self.classify(&line, diagnostics) {
+                Directive::Macro { name, parameters } => {
+                    let location = first_token_location(&line);
+                    let body = collect_block(&mut lines, ".macro", ".endmacro", &location, diagnostics);
+                    self.define(name, location, parameters, body, diagnostics);
+                },
+                Directive::Repeat { count, counter } => {
+                    let location = first_token_location(&line);
+                    let body = collect_block(&mut lines, ".rept", ".endr", &location, diagnostics);
+                    if let Some(count) = count {
+                        self.repeat(&location, count, counter, body, depth, diagnostics);
+                    }
+                },
+                Directive::End(directive) => diagnostics.push(Diagnostic::new(
+                    first_token_location(&line),
+                    DiagnosticKind::UnmatchedDirective { directive: directive.to_string() })),
+                Directive::Invocation { label, name, arguments } => {
+                    if let Some(label) = label {
+                        self.output.push(line.with_text(format!("{}:", label)));
+                    }
+                    self.expand(&line, &name, arguments, depth, diagnostics);
+                },
+                Directive::None => {
+                    self.record_constant(&line);
+                    self.output.push(line)
+                }
+            }
+        }
+        for conditional in conditionals {
+            diagnostics.push(Diagnostic::new(
+                conditional.location,
+                DiagnosticKind::UnterminatedDirective { directive: ".if".to_string() }));
+        }
+    }
+
+    // Handles `.if`, `.ifdef`, `.ifndef`, `.else` and `.endif`, returning
+    // whether the line was one of them. Conditions in inactive branches are
+    // not evaluated, but are still tracked so that nesting is respected.
+    fn process_conditional(&self, line: &LogicalLine<'a>, active: bool, conditionals: &mut Vec<Conditional>, diagnostics: &mut Vec<Diagnostic>) -> bool {
+        let directive = match directive_name(&line.text) {
+            Some(directive) => directive,
+            None => return false
+        };
+        match directive {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let taken = active && self.evaluate_condition(line, directive, diagnostics);
+                conditionals.push(Conditional {
+                    location: first_token_location(line),
+                    enclosing_active: active,
+                    taken: taken,
+                    active: taken,
+                    seen_else: false
+                });
+            },
+            ".else" => match conditionals.last_mut() {
+                Some(ref mut conditional) if !conditional.seen_else => {
+                    conditional.seen_else = true;
+                    conditional.active = conditional.enclosing_active && !conditional.taken;
+                },
+                _ => diagnostics.push(Diagnostic::new(
+                    first_token_location(line),
+                    DiagnosticKind::UnmatchedDirective { directive: directive.to_string() }))
+            },
+            ".endif" => if conditionals.pop().is_none() {
+                diagnostics.push(Diagnostic::new(
+                    first_token_location(line),
+                    DiagnosticKind::UnmatchedDirective { directive: directive.to_string() }));
+            },
+            _ => return false
+        }
+        true
+    }
+
+    fn evaluate_condition(&self, line: &LogicalLine<'a>, directive: &str, diagnostics: &mut Vec<Diagnostic>) -> bool {
+        let tokens = parser::tokenize(&line.text);
+        let position = tokens.iter().position(|&token| token == directive).unwrap();
+        let operands = &tokens[position + 1..];
+        let (first, last) = match (operands.first(), operands.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => {
+                diagnostics.push(Diagnostic::new(
+                    line.location(tokens[position]),
+                    DiagnosticKind::MissingOperand { directive: directive.to_string() }));
+                return false;
+            }
+        };
+        if directive != ".if" {
+            if operands.len() != 1 || !parser::is_symbol_name(first) {
+                diagnostics.push(Diagnostic::new(
+                    line.location(first),
+                    DiagnosticKind::BadSymbolName { name: parser::span(&line.text, first, last).to_string() }));
+                return false;
+            }
+            return self.constants.values.contains_key(first) == (directive == ".ifdef");
+        }
+        self.evaluate_constant(line, parser::span(&line.text, first, last), diagnostics)
+            .map_or(false, |value| value != 0)
+    }
+
+    fn evaluate_constant(&self, line: &LogicalLine<'a>, text: &str, diagnostics: &mut Vec<Diagnostic>) -> Option<i64> {
+        let expression = match expression::parse(text) {
+            Ok(expression) => expression,
+            Err(error) => {
+                diagnostics.push(Diagnostic::new(
+                    line.location(error.token),
+                    DiagnosticKind::NotConstantExpression { expression: text.to_string() }));
+                return None;
+            }
+        };
+        match expression::evaluate(&expression, &self.constants) {
+            Ok(Value::Integer(value)) => Some(value),
+            Ok(Value::Address(_)) => None,
+            Err(error) => {
+                let kind = match error.kind {
+                    EvaluationErrorKind::UndefinedSymbol => DiagnosticKind::UndefinedSymbol { symbol: error.token.to_string(), suggestion: None },
+                    EvaluationErrorKind::NotConstant => DiagnosticKind::NotConstant { symbol: error.token.to_string() },
+                    EvaluationErrorKind::DivisionByZero => DiagnosticKind::DivisionByZero,
+                    EvaluationErrorKind::Overflow => DiagnosticKind::Overflow,
+                    _ => DiagnosticKind::NotConstantExpression { expression: text.to_string() }
+                };
+                diagnostics.push(Diagnostic::new(line.location(error.token), kind));
+                None
+            }
+        }
+    }
+
+    // Records a `.equ` constant so that later conditions can use it. Errors
+    // in the definition are left for the resolver to report.
+    fn record_constant(&mut self, line: &LogicalLine<'a>) {
+        let tokens = parser::tokenize(&line.text);
+        let position = match tokens.iter().position(|&token| token == ".equ") {
+            Some(position) => position,
+            None => return
+        };
+        if let (Some(&name), Some(&first), Some(&last)) = (tokens.get(position + 1), tokens.get(position + 2), tokens.last()) {
+            let value = expression::parse(parser::span(&line.text, first, last)).ok()
+                .and_then(|expression| match expression::evaluate(&expression, &self.constants) {
+                    Ok(Value::Integer(value)) => Some(value),
+                    _ => None
+                });
+            self.constants.values.entry(name.to_string()).or_insert(value);
+        }
+    }
+
+    fn classify(&self, line: &LogicalLine<'a>, diagnostics: &mut Vec<Diagnostic>) -> Directive {
+        let tokens = parser::tokenize(&line.text);
+        let (label, rest) = match tokens.split_first() {
+            Some((first, rest)) if parser::parse_label_definition(first).is_some() =>
+                (parser::parse_label_definition(first), rest),
+            _ => (None, &tokens[..])
+        };
+        match rest.split_first() {
+            Some((&".macro", operands)) => {
+                let names = split_operands(&line.text, operands);
+                match names.split_first() {
+                    Some((name, parameters)) if names.iter().all(|name| parser::is_symbol_name(name)) =>
+                        Directive::Macro {
+                            name: name.to_string(),
+                            parameters: parameters.iter().map(|parameter| parameter.to_string()).collect()
+                        },
+                    Some(_) => {
+                        let name = names.iter().find(|name| !parser::is_symbol_name(name)).unwrap();
+                        diagnostics.push(Diagnostic::new(
+                            line.location(name),
+                            DiagnosticKind::BadSymbolName { name: name.to_string() }));
+                        Directive::Macro { name: String::new(), parameters: Vec::new() }
+                    },
+                    None => {
+                        diagnostics.push(Diagnostic::new(
+                            line.location(rest[0]),
+                            DiagnosticKind::MissingOperand { directive: ".macro".to_string() }));
+                        Directive::Macro { name: String::new(), parameters: Vec::new() }
+                    }
+                }
+            },
+            Some((&".rept", operands)) => {
+                let operands = split_operands(&line.text, operands);
+                match &operands[..] {
+                    [count] | [count, _] => {
+                        let count = self.evaluate_constant(line, count, diagnostics);
+                        match (count, operands.get(1)) {
+                            (Some(count), _) if count < 0 => {
+                                diagnostics.push(Diagnostic::new(
+                                    line.location(operands[0]),
+                                    DiagnosticKind::NegativeCount { directive: ".rept".to_string(), count: count }));
+                                Directive::Repeat { count: None, counter: None }
+                            },
+                            (_, Some(counter)) if !parser::is_symbol_name(counter) => {
+                                diagnostics.push(Diagnostic::new(
+                                    line.location(counter),
+                                    DiagnosticKind::BadSymbolName { name: counter.to_string() }));
+                                Directive::Repeat { count: None, counter: None }
+                            },
+                            (count, counter) => Directive::Repeat { count: count, counter: counter.map(|counter| counter.to_string()) }
+                        }
+                    },
+                    _ => {
+                        diagnostics.push(Diagnostic::new(
+                            line.location(rest[0]),
+                            DiagnosticKind::MissingOperand { directive: ".rept".to_string() }));
+                        Directive::Repeat { count: None, counter: None }
+                    }
+                }
+            },
+            Some((&".endmacro", _)) => Directive::End(".endmacro"),
+            Some((&".endr", _)) => Directive::End(".endr"),
+            Some((name, operands)) if self.macros.contains_key(*name) => Directive::Invocation {
+                label: label.map(|label| label.to_string()),
+                name: name.to_string(),
+                arguments: split_operands(&line.text, operands).iter().map(|argument| argument.to_string()).collect()
+            },
+            _ => Directive::None
+        }
+    }
+
+    fn define(&mut self, name: String, location: Location, parameters: Vec<String>, body: Vec<LogicalLine<'a>>, diagnostics: &mut Vec<Diagnostic>) {
+        // An empty name means the `.macro` line was malformed, which has
+        // already been reported, but the body still had to be skipped.
+        if name.is_empty() {
+            return;
+        }
+        if let Some(previous) = self.macros.get(&name) {
+            diagnostics.push(Diagnostic::new(
+                location,
+                DiagnosticKind::DuplicateMacro { name: name, previous: previous.location.clone() }));
+            return;
+        }
+        self.macros.insert(name, Macro { location: location, parameters: parameters, body: body });
+    }
+
+    fn expand(&mut self, line: &LogicalLine<'a>, name: &str, arguments: Vec<String>, depth: usize, diagnostics: &mut Vec<Diagnostic>) {
+        let location = first_token_location(line);
+        if depth >= MAX_EXPANSION_DEPTH {
+            diagnostics.push(Diagnostic::new(location, DiagnosticKind::MacroRecursion { name: name.to_string() }));
+            return;
+        }
+        let expanded = {
+            let definition = &self.macros[name];
+            if arguments.len() != definition.parameters.len() {
+                diagnostics.push(Diagnostic::new(
+                    location,
+                    DiagnosticKind::WrongMacroArguments {
+                        name: name.to_string(),
+                        expected: definition.parameters.len(),
+                        found: arguments.len()
+                    }));
+                return;
+            }
+            self.expansions += 1;
+            let unique = self.expansions.to_string();
+            definition.body.iter()
+                .map(|body_line| body_line.with_text(substitute(&body_line.text, &definition.parameters, &arguments, &unique)))
+                .collect()
+        };
+        self.process(expanded, depth + 1, diagnostics);
+    }
+
+    // Processes `body` `count` times. When a counter is named, its
+    // occurrences are replaced by the iteration number, counting from zero.
+    fn repeat(&mut self, location: &Location, count: i64, counter: Option<String>, body: Vec<LogicalLine<'a>>, depth: usize, diagnostics: &mut Vec<Diagnostic>) {
+        if depth >= MAX_EXPANSION_DEPTH {
+            diagnostics.push(Diagnostic::new(location.clone(), DiagnosticKind::MacroRecursion { name: ".rept".to_string() }));
+            return;
+        }
+        let parameters: Vec<String> = counter.into_iter().collect();
+        for iteration in 0..count {
+            self.expansions += 1;
+            let unique = self.expansions.to_string();
+            let arguments = vec![iteration.to_string()];
+            let expanded = body.iter()
+                .map(|body_line| body_line.with_text(substitute(&body_line.text, &parameters, &arguments, &unique)))
+                .collect();
+            self.process(expanded, depth + 1, diagnostics);
+        }
+    }
+}
+
+// Collects the lines up to the `closing` directive that matches `opening`,
+// leaving `lines` just after it. Nested blocks of the same kind are kept in
+// the body, and processed whenever the enclosing block is.
+fn collect_block<'a, I: Iterator<Item=LogicalLine<'a>>>(lines: &mut I, opening: &str, closing: &str, location: &Location, diagnostics: &mut Vec<Diagnostic>) -> Vec<LogicalLine<'a>> {
+    let mut body = Vec::new();
+    let mut nesting = 0;
+    for line in lines {
+        match directive_name(&line.text) {
+            Some(directive) if directive == opening => nesting += 1,
+            Some(directive) if directive == closing && nesting == 0 => return body,
+            Some(directive) if directive == closing => nesting -= 1,
+            _ => ()
+        }
+        body.push(line);
+    }
+    diagnostics.push(Diagnostic::new(
+        location.clone(),
+        DiagnosticKind::UnterminatedDirective { directive: opening.to_string() }));
+    body
+}
+
+fn directive_name(text: &str) -> Option<&str> {
+    parser::tokenize(text).into_iter()
+        .skip_while(|token| parser::parse_label_definition(token).is_some())
+        .next()
+        .filter(|token| token.starts_with("."))
+}
+
+fn first_token_location(line: &LogicalLine) -> Location {
+    let tokens = parser::tokenize(&line.text);
+    let token = tokens.iter()
+        .find(|token| parser::parse_label_definition(token).is_none())
+        .or(tokens.first())
+        .map(|&token| token)
+        .unwrap_or(&line.text[..]);
+    line.location(token)
+}
+
+// Macro operands are separated by commas, or by whitespace when there are
+// no commas, so that `push2 1 2` and `push2 (1 + 2), 3` both work.
+fn split_operands<'t>(text: &'t str, operands: &[&'t str]) -> Vec<&'t str> {
+    match (operands.first(), operands.last()) {
+        (Some(first), Some(last)) if operands.iter().any(|operand| operand.contains(',')) =>
+            parser::span(text, first, last).split(',').map(|operand| operand.trim()).collect(),
+        _ => operands.to_vec()
+    }
+}
+
+// Substitutes whole-word occurrences of each parameter, and replaces `\@`
+// with a number unique to this expansion so that bodies can define labels.
+// Quoted literals and comments are copied as they are, finding them as
+// `parser::tokenize` does.
+fn substitute(text: &str, parameters: &[String], arguments: &[String], unique: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.char_indices().peekable();
+    let mut quote = None;
+    let mut escaped = false;
+    while let Some((start, c)) = chars.next() {
+        if let Some(delimiter) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delimiter {
+                quote = None;
+            }
+            result.push(c);
+        } else if c == '\'' || c == '"' {
+            quote = Some(c);
+            result.push(c);
+        } else if c == ';' || c == '#' {
+            result.push_str(&text[start..]);
+            break;
+        } else if c == '\\' && chars.peek().map(|&(_, next)| next) == Some('@') {
+            chars.next();
+            result.push_str(unique);
+        } else if expression::is_name_character(c) {
+            let mut end = start + c.len_utf8();
+            while let Some(&(index, c)) = chars.peek() {
+                if !expression::is_name_character(c) {
+                    break;
+                }
+                end = index + c.len_utf8();
+                chars.next();
+            }
+            let word = &text[start..end];
+            match parameters.iter().position(|parameter| parameter == word) {
+                Some(index) => result.push_str(&arguments[index]),
+                None => result.push_str(word)
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
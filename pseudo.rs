@@ -0,0 +1,79 @@
+use error::{Diagnostic, DiagnosticKind};
+use expression::Expression;
+use isa::InstructionSet;
+use parser::{SourceLine, Line, Argument};
+
+// Pseudo-instructions, and the real instructions they lower to. `li` isn't
+// here, since how it's lowered depends on its value, so the resolver expands
+// it once constants are known.
+const PSEUDO_INSTRUCTIONS: &'static [(&'static str, &'static [(&'static str, Option<i64>)])] = &[
+    ("inc", &[("const", Some(1)), ("add", None)]),
+    ("dec", &[("const", Some(-1)), ("add", None)]),
+    ("clear", &[("pop", None), ("const", Some(0))])
+];
+
+// Replaces each pseudo-instruction with the instructions it lowers to. Any
+// label stays on the first of them. Opcodes are also replaced by their
+// canonical names, so later passes needn't handle case or aliases.
+pub fn expand<'a>(lines: Vec<SourceLine<'a>>, instruction_set: &'a InstructionSet, diagnostics: &mut Vec<Diagnostic>) -> Vec<SourceLine<'a>> {
+    let mut result = Vec::new();
+    for mut line in lines {
+        if let Line::Instruction { ref mut opcode, .. } = line.line {
+            *opcode = canonical_opcode(opcode, instruction_set);
+        }
+        let expansion = match line.line {
+            Line::Instruction { opcode, .. } => PSEUDO_INSTRUCTIONS.iter()
+                .find(|&&(name, _)| name == opcode)
+                .map(|&(_, expansion)| expansion),
+            _ => None
+        };
+        match expansion {
+            Some(expansion) => expand_line(line, expansion, &mut result, diagnostics),
+            None => result.push(line)
+        }
+    }
+    result
+}
+
+// Whether `name` is handled by the assembler rather than the instruction
+// set, so an opcode with that name can't be written as an instruction.
+pub fn is_reserved(name: &str) -> bool {
+    name.eq_ignore_ascii_case("li") || PSEUDO_INSTRUCTIONS.iter().any(|&(pseudo, _)| pseudo.eq_ignore_ascii_case(name))
+}
+
+fn canonical_opcode<'a>(opcode: &'a str, instruction_set: &'a InstructionSet) -> &'a str {
+    if opcode.eq_ignore_ascii_case("li") {
+        return "li";
+    }
+    PSEUDO_INSTRUCTIONS.iter()
+        .map(|&(name, _)| name)
+        .find(|name| name.eq_ignore_ascii_case(opcode))
+        .or_else(|| instruction_set.canonical_opcode(opcode))
+        .unwrap_or(opcode)
+}
+
+fn expand_line<'a>(line: SourceLine<'a>, expansion: &[(&'static str, Option<i64>)], result: &mut Vec<SourceLine<'a>>, diagnostics: &mut Vec<Diagnostic>) {
+    let token = match line.line {
+        Line::Instruction { token, arg: Argument::Expression { text, .. }, .. } => {
+            diagnostics.push(Diagnostic::new(
+                line.location(text),
+                DiagnosticKind::UnexpectedToken { token: text.to_string() }));
+            token
+        },
+        Line::Instruction { token, .. } => token,
+        _ => return
+    };
+    for (index, &(opcode, value)) in expansion.iter().enumerate() {
+        let arg = match value {
+            Some(value) => Argument::Expression { expression: Expression::Integer(value), text: token },
+            None => Argument::None
+        };
+        result.push(SourceLine {
+            file: line.file,
+            line_number: line.line_number,
+            text: line.text,
+            label: if index == 0 { line.label } else { None },
+            line: Line::Instruction { opcode: opcode, token: token, arg: arg }
+        });
+    }
+}
@@ -0,0 +1,822 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::{i8, u8};
+
+use error::{Diagnostic, DiagnosticKind, Location};
+use encoding::{self, Encoding};
+use expression::{self, Expression, Value};
+use isa::{self, InstructionSet, Operand};
+use literals;
+use object::{Relocation, RelocationKind, RelocationTarget};
+use optimizer;
+use options::Options;
+use parser::{SourceLine, Line, Argument, DataWidth, Section};
+use suggestions;
+use symbols::{self, Symbols, Definition, Labels, LabelScope};
+
+pub struct Instruction<'a> {
+    pub location: Location,
+    pub opcode: &'a str,
+    pub arg: i64
+}
+
+// A word of output: either an instruction still to be encoded, or raw data.
+pub enum Word<'a> {
+    Instruction(Instruction<'a>),
+    Data(i64)
+}
+
+// The assembled words, along with what's needed to write them out: the
+// address of the first word, the address and size of each gap a `.org` fills
+// with zeros, the address given by `.start`, and the address and section of
+// each named label and whether it's `.global`, in address order. `sections` and `functions` give the
+// address and size of each non-empty section and each `.func`. `sources` gives the start and text of
+// the line each word came from, and `lines` the start, text, address and
+// range of words of every line, in output order. `relocations` is only filled
+// in when assembling an object.
+pub struct Resolved<'a> {
+    pub words: Vec<Word<'a>>,
+    pub sources: Vec<(Location, &'a str)>,
+    pub lines: Vec<(Location, &'a str, i64, Range<usize>)>,
+    pub origin: i64,
+    pub gaps: Vec<(i64, i64)>,
+    pub entry: Option<i64>,
+    pub labels: Vec<(String, i64, Section, bool)>,
+    pub sections: Vec<(Section, i64, i64)>,
+    pub functions: Vec<(String, Section, i64, i64)>,
+    pub relocations: Vec<Relocation>
+}
+
+// Where a line is placed in the output, and how many words it takes up.
+#[derive(Clone, Copy)]
+struct Placement {
+    address: i64,
+    size: i64
+}
+
+pub fn resolve<'a>(lines: Vec<SourceLine<'a>>, instruction_set: &InstructionSet, options: &Options, diagnostics: &mut Vec<Diagnostic>) -> Resolved<'a> {
+    check_functions(&lines, diagnostics);
+    let scopes = label_scopes(&lines);
+    let constants = find_constants(&lines, &scopes, options, diagnostics);
+    // Addresses are assigned before labels are known, so sizes such as the
+    // count of a `.space` may only depend on constants that don't use labels.
+    let encoding = options.profile.encoding();
+    let sections = line_sections(&lines);
+    let order = output_order(&sections);
+    let placements = place_lines(&lines, &scopes, &order, &Symbols::new(None, constants.clone(), &options.defines), encoding, diagnostics);
+    let mut label_addresses = find_labels(&lines, &scopes, &placements, diagnostics);
+    let global = find_visibility(&lines, &mut label_addresses, options, diagnostics);
+    let labels = label_table(&lines, &scopes, &sections, &label_addresses, &global);
+    let symbols = Symbols::new(Some(label_addresses), constants, &options.defines);
+    symbols.evaluate_constants(diagnostics);
+    let entry = find_entry(&symbols, &lines, &scopes, diagnostics);
+    let mut words = Vec::new();
+    let mut sources = Vec::new();
+    let mut listed_lines = Vec::new();
+    for &index in order.iter() {
+        let line = &lines[index];
+        let start = words.len();
+        resolve_line(&symbols, instruction_set, encoding, line, &scopes[index], sections[index], placements[index], &mut words, diagnostics);
+        let location = line.location(line.text);
+        sources.resize(words.len(), (location.clone(), line.text));
+        listed_lines.push((location, line.text, placements[index].address, start..words.len()));
+    }
+    if options.warn_fall_through || options.strict {
+        check_ending(&lines, instruction_set, options.strict, diagnostics);
+    }
+    let origin = order.iter()
+        .map(|&index| placements[index])
+        .find(|placement| placement.size > 0)
+        .map(|placement| placement.address)
+        .unwrap_or(0);
+    let gaps = order.iter()
+        .filter(|&&index| match lines[index].line { Line::Origin {..} => placements[index].size > 0, _ => false })
+        .map(|&index| (placements[index].address - placements[index].size, placements[index].size))
+        .collect();
+    let extents = section_extents(&lines, &order, &sections, &placements);
+    let relocations = if options.object && diagnostics.iter().all(|diagnostic| diagnostic.is_warning()) {
+        find_relocations(&symbols, &lines, &scopes, &sections, &placements, &labels, &extents, diagnostics)
+    } else {
+        Vec::new()
+    };
+    if options.pic && diagnostics.iter().all(|diagnostic| diagnostic.is_warning()) {
+        check_position_independent(&symbols, &lines, &scopes, &sections, &labels, diagnostics);
+    }
+    Resolved {
+        words: words,
+        sources: sources,
+        lines: listed_lines,
+        origin: origin,
+        gaps: gaps,
+        entry: entry,
+        labels: labels,
+        sections: extents,
+        functions: function_extents(&lines, &sections, &placements),
+        relocations: relocations
+    }
+}
+
+// The address of the label given by `.start`, if there is one.
+fn find_entry<'a>(symbols: &Symbols, lines: &[SourceLine<'a>], scopes: &[LabelScope], diagnostics: &mut Vec<Diagnostic>) -> Option<i64> {
+    let mut entry: Option<(Location, Option<i64>)> = None;
+    for (index, line) in lines.iter().enumerate() {
+        let (directive, label) = match line.line {
+            Line::Start { directive, ref label } => (directive, label),
+            _ => continue
+        };
+        if let Some((ref previous, _)) = entry {
+            diagnostics.push(Diagnostic::new(
+                line.location(directive),
+                DiagnosticKind::DuplicateDirective { directive: directive.to_string(), previous: previous.clone() }));
+            continue;
+        }
+        let address = match label {
+            &Argument::Expression { ref expression, text } => match symbols.evaluate(expression, &scopes[index]) {
+                Ok(Value::Address(address)) => Some(address),
+                Ok(Value::Integer(_)) => {
+                    diagnostics.push(Diagnostic::new(
+                        line.location(text),
+                        DiagnosticKind::ExpectedLabel { opcode: directive.to_string() }));
+                    None
+                },
+                Err(error) => {
+                    diagnostics.extend(symbols.evaluation_error_diagnostic(line, error));
+                    None
+                }
+            },
+            &Argument::None | &Argument::Invalid => None
+        };
+        entry = Some((line.location(directive), address));
+    }
+    entry.and_then(|(_, address)| address)
+}
+
+// Checks that execution can't run past the last line of `.text`, which it
+// can if that line can be reached and isn't a `halt`, `jmp` or `ret`. If it
+// can't be told which lines are reached, only the last instruction is checked.
+pub fn check_ending<'a>(lines: &[SourceLine<'a>], instruction_set: &InstructionSet, strict: bool, diagnostics: &mut Vec<Diagnostic>) {
+    let sections = line_sections(lines);
+    let code: Vec<usize> = (0..lines.len()).filter(|&index| sections[index] == Section::Text).collect();
+    let last_instruction = code.iter().rev()
+        .filter_map(|&index| match lines[index].line {
+            Line::Instruction { token, .. } => Some((&lines[index], token)),
+            _ => None
+        })
+        .next();
+    let (last, (instruction, token)) = match (code.last(), last_instruction) {
+        (Some(&last), Some(last_instruction)) => (last, last_instruction),
+        _ => return
+    };
+    let falls_off = match optimizer::reachable_lines(lines, instruction_set) {
+        Some(reachable) => reachable.contains(&last) && !optimizer::is_terminator(&lines[last]),
+        None => !optimizer::is_terminator(instruction)
+    };
+    if falls_off {
+        // A label after the last instruction is where execution falls off, if
+        // it's jumped to.
+        let location = match (&lines[last].line, lines[last].label) {
+            (&Line::Instruction {..}, _) | (_, None) => instruction.location(token),
+            (_, Some(label)) => lines[last].location(label)
+        };
+        diagnostics.push(Diagnostic::new(location, DiagnosticKind::FallsOffEnd { strict: strict }));
+    }
+}
+
+// The section each line is in. Lines before any section directive are in
+// `.text`.
+pub fn line_sections<'a>(lines: &[SourceLine<'a>]) -> Vec<Section> {
+    let mut section = Section::Text;
+    let mut sections = Vec::new();
+    for line in lines {
+        if let Line::Section(line_section) = line.line {
+            section = line_section;
+        }
+        sections.push(section);
+    }
+    sections
+}
+
+// The indices of lines in the order they're output: each section is laid out
+// contiguously, with sections in a fixed order and lines within a section in
+// source order.
+fn output_order(sections: &[Section]) -> Vec<usize> {
+    let mut order: Vec<_> = (0..sections.len()).collect();
+    order.sort_by_key(|&index| sections[index]);
+    order
+}
+
+// The addresses of the words a line is placed at. A `.org`'s words are the
+// gap before it.
+fn placed_words(placement: Placement, line: &SourceLine) -> Range<i64> {
+    match line.line {
+        Line::Origin {..} => placement.address - placement.size..placement.address,
+        _ => placement.address..placement.address + placement.size
+    }
+}
+
+// The address and size of each section that has any words, in output order.
+fn section_extents<'a>(lines: &[SourceLine<'a>], order: &[usize], sections: &[Section], placements: &[Placement]) -> Vec<(Section, i64, i64)> {
+    let mut extents: Vec<(Section, i64, i64)> = Vec::new();
+    for &index in order {
+        if placements[index].size == 0 {
+            continue;
+        }
+        let words = placed_words(placements[index], &lines[index]);
+        match extents.last_mut() {
+            Some(&mut (section, _, ref mut end)) if section == sections[index] => *end = words.end,
+            _ => extents.push((sections[index], words.start, words.end))
+        }
+    }
+    extents.into_iter().map(|(section, start, end)| (section, start, end - start)).collect()
+}
+
+// The name, section, address and size of each `.func`. Only the words in
+// the section the function starts in are counted.
+fn function_extents<'a>(lines: &[SourceLine<'a>], sections: &[Section], placements: &[Placement]) -> Vec<(String, Section, i64, i64)> {
+    let mut functions = Vec::new();
+    let mut open: Option<(&str, Section, Option<Range<i64>>, i64)> = None;
+    for (index, line) in lines.iter().enumerate() {
+        match line.line {
+            Line::Function { name, .. } => open = Some((name, sections[index], None, placements[index].address)),
+            Line::EndFunction {..} => if let Some((name, section, words, address)) = open.take() {
+                let words = words.unwrap_or(address..address);
+                functions.push((name.to_string(), section, words.start, words.end - words.start));
+            },
+            _ => if let Some((_, section, ref mut words, _)) = open {
+                let placement = placements[index];
+                if section == sections[index] && placement.size > 0 {
+                    let line_words = placed_words(placement, line);
+                    *words = Some(match words.take() {
+                        Some(words) => words.start..line_words.end,
+                        None => line_words
+                    });
+                }
+            }
+        }
+    }
+    functions.sort_by_key(|&(_, section, address, _)| (section, address));
+    functions
+}
+
+// Each named label with its address and section, and whether it's
+// `.global`, in address order.
+fn label_table<'a>(lines: &[SourceLine<'a>], scopes: &[LabelScope], sections: &[Section], labels: &Labels, global: &HashSet<String>) -> Vec<(String, i64, Section, bool)> {
+    let mut label_sections = HashMap::new();
+    for (index, line) in lines.iter().enumerate() {
+        if let Some(label) = line.label {
+            label_sections.entry(symbols::qualify_label(&scopes[index].name, label)).or_insert(sections[index]);
+        }
+    }
+    let mut table: Vec<_> = labels.named.iter()
+        .map(|(name, &address)| (name.clone(), address, label_sections[name], global.contains(name)))
+        .collect();
+    table.sort_by(|&(ref name, address, _, _), &(ref other_name, other_address, _, _)| (address, name).cmp(&(other_address, other_name)));
+    table
+}
+
+// The scope of local labels on each line. Each file starts a scope of its
+// own, and each non-local label then starts a new one until the end of the
+// file, so an included file doesn't affect the scope of the file including it.
+pub fn label_scopes<'a>(lines: &[SourceLine<'a>]) -> Vec<LabelScope> {
+    let mut file_scopes: HashMap<Option<&str>, String> = HashMap::new();
+    let mut scopes = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        let scope = file_scopes.entry(line.file)
+            .or_insert_with(|| format!("<{}>", line.file.unwrap_or("")));
+        if let Some(label) = line.label {
+            if !label.starts_with(".") && !symbols::is_anonymous_label(label) {
+                *scope = label.to_string();
+            }
+        }
+        scopes.push(LabelScope { name: scope.clone(), line: index });
+    }
+    scopes
+}
+
+// Checks that each `.func` is closed by an `.endfunc` before the next one.
+fn check_functions<'a>(lines: &[SourceLine<'a>], diagnostics: &mut Vec<Diagnostic>) {
+    let mut open: Option<Location> = None;
+    for line in lines {
+        match line.line {
+            Line::Function { directive, .. } => {
+                if let Some(location) = open.take() {
+                    diagnostics.push(Diagnostic::new(location, DiagnosticKind::UnterminatedDirective { directive: directive.to_string() }));
+                }
+                open = Some(line.location(directive));
+            },
+            Line::EndFunction { directive } => {
+                if open.take().is_none() {
+                    diagnostics.push(Diagnostic::new(
+                        line.location(directive),
+                        DiagnosticKind::UnmatchedDirective { directive: directive.to_string() }));
+                }
+            },
+            _ => ()
+        }
+    }
+    if let Some(location) = open {
+        diagnostics.push(Diagnostic::new(location, DiagnosticKind::UnterminatedDirective { directive: ".func".to_string() }));
+    }
+}
+
+fn resolve_line<'a>(symbols: &Symbols, instruction_set: &InstructionSet, encoding: &dyn Encoding, source_line: &SourceLine<'a>, scope: &LabelScope, section: Section, placement: Placement, words: &mut Vec<Word<'a>>, diagnostics: &mut Vec<Diagnostic>) {
+    let address = placement.address;
+    match source_line.line {
+        Line::Instruction { opcode: "li", token, ref arg } if placement.size > 1 =>
+            words.extend(load_immediate(symbols, encoding, source_line, scope, token, arg, diagnostics)),
+        Line::Instruction { opcode: "li", token, ref arg } =>
+            words.push(Word::Instruction(Instruction {
+                location: source_line.location(token),
+                opcode: "const",
+                arg: resolve_operand(symbols, instruction_set, encoding, source_line, scope, address, "const", token, arg, diagnostics)
+            })),
+        Line::Instruction { opcode: opcode, token, arg: ref arg } =>
+            words.push(Word::Instruction(Instruction {
+                location: source_line.location(token),
+                opcode: opcode,
+                arg: resolve_operand(symbols, instruction_set, encoding, source_line, scope, address, opcode, token, arg, diagnostics)
+            })),
+        Line::Data { width: DataWidth::Word, ref values } => {
+            let (minimum, maximum) = encoding.word_range();
+            words.extend(values.iter()
+                .map(|value| Word::Data(resolve_data(symbols, source_line, scope, value, minimum, maximum, diagnostics))));
+        },
+        Line::Data { width: DataWidth::Byte, ref values } => {
+            let bytes: Vec<u8> = values.iter()
+                .map(|value| resolve_data(symbols, source_line, scope, value, i8::MIN as i64, u8::MAX as i64, diagnostics) as u8)
+                .collect();
+            words.extend(pack_bytes(&bytes, encoding.word_size()));
+        },
+        Line::String { ref bytes } =>
+            words.extend(pack_bytes(bytes, encoding.word_size())),
+        // Code that runs on into the padding from `.align` shouldn't find
+        // anything there but `nop`.
+        Line::Align { directive, .. } if section == Section::Text && instruction_set.opcode("nop").is_some() =>
+            words.extend((0..placement.size).map(|_| Word::Instruction(Instruction {
+                location: source_line.location(directive),
+                opcode: "nop",
+                arg: 0
+            }))),
+        Line::Space {..} | Line::Align {..} | Line::Origin {..} =>
+            words.extend((0..placement.size).map(|_| Word::Data(0))),
+        _ => ()
+    }
+}
+
+// `li` loads a value too wide for `const` by building it from its high and
+// low parts, each the width of an argument. With 16-bit arguments, that's
+// `const HIGH; const 256; mul; const 256; mul; const LOW; add`.
+pub const LOAD_IMMEDIATE_SIZE: i64 = 7;
+
+fn load_immediate<'a>(symbols: &Symbols, encoding: &dyn Encoding, source_line: &SourceLine<'a>, scope: &LabelScope, token: &'a str, argument: &Argument<'a>, diagnostics: &mut Vec<Diagnostic>) -> Vec<Word<'a>> {
+    let (minimum, maximum) = encoding.word_range();
+    let value = resolve_data(symbols, source_line, scope, argument, minimum, maximum, diagnostics);
+    let bits = encoding.argument_bits();
+    let low = encoding::sign_extend(value, bits);
+    let high = value.wrapping_sub(low) >> bits;
+    let factor = 1 << (bits / 2);
+    let location = source_line.location(token);
+    [("const", high), ("const", factor), ("mul", 0), ("const", factor), ("mul", 0), ("const", low), ("add", 0)].iter()
+        .map(|&(opcode, arg)| Word::Instruction(Instruction { location: location.clone(), opcode: opcode, arg: arg }))
+        .collect()
+}
+
+// Evaluates a data value. Unlike instruction arguments, labels in data
+// resolve to their absolute address, since there's no instruction for an
+// offset to be relative to.
+fn resolve_data<'a>(symbols: &Symbols, source_line: &SourceLine<'a>, scope: &LabelScope, argument: &Argument<'a>, minimum: i64, maximum: i64, diagnostics: &mut Vec<Diagnostic>) -> i64 {
+    let (expression, text) = match argument {
+        &Argument::Expression { ref expression, text } => (expression, text),
+        &Argument::None | &Argument::Invalid => return 0
+    };
+    let value = match symbols.evaluate(expression, scope) {
+        Ok(Value::Integer(value)) | Ok(Value::Address(value)) => value,
+        Err(error) => {
+            diagnostics.extend(symbols.evaluation_error_diagnostic(source_line, error));
+            return 0;
+        }
+    };
+    if value < minimum || value > maximum {
+        diagnostics.push(Diagnostic::new(
+            source_line.location(text),
+            DiagnosticKind::ValueOutOfRange { value: value, minimum: minimum, maximum: maximum }));
+        0
+    } else {
+        value
+    }
+}
+
+// Packs bytes into words, least significant byte first, padding the last
+// word with zeros.
+fn pack_bytes<'a>(bytes: &[u8], word_size: usize) -> Vec<Word<'a>> {
+    bytes.chunks(word_size)
+        .map(|chunk| chunk.iter().rev().fold(0u64, |word, &byte| (word << 8) | byte as u64))
+        .map(|word| Word::Data(encoding::sign_extend(word as i64, word_size as u32 * 8)))
+        .collect()
+}
+
+// Checks an instruction's argument against what its opcode expects, naming
+// the opcode as it was written. Unknown opcodes are reported when they're
+// encoded.
+fn resolve_operand<'a>(symbols: &Symbols, instruction_set: &InstructionSet, encoding: &dyn Encoding, source_line: &SourceLine<'a>, scope: &LabelScope, address: i64, opcode: &str, token: &str, argument: &Argument<'a>, diagnostics: &mut Vec<Diagnostic>) -> i64 {
+    let operand = instruction_set.opcode(opcode).map(|opcode| opcode.operand);
+    match (operand, argument) {
+        (Some(Operand::None), &Argument::Expression { text, .. }) => {
+            diagnostics.push(Diagnostic::new(
+                source_line.location(text),
+                DiagnosticKind::UnexpectedArgument { opcode: token.to_string() }));
+            0
+        },
+        (Some(Operand::Immediate), &Argument::None) | (Some(Operand::Depth), &Argument::None) | (Some(Operand::Label), &Argument::None) => {
+            diagnostics.push(Diagnostic::new(
+                source_line.location(token),
+                DiagnosticKind::MissingArgument { opcode: token.to_string() }));
+            0
+        },
+        _ => resolve_arg(symbols, encoding, source_line, scope, address, argument, operand, diagnostics)
+    }
+}
+
+// The value of an immediate written as `text`, where a hexadecimal, binary
+// or octal literal too big to be positive is taken as the argument's bits.
+pub fn immediate_value(value: i64, text: &str, encoding: &dyn Encoding) -> i64 {
+    let (_, maximum) = encoding.argument_range();
+    let bits = encoding.argument_bits();
+    if literals::is_bit_pattern(text) && value > maximum && value < 1 << bits {
+        encoding::sign_extend(value, bits)
+    } else {
+        value
+    }
+}
+
+fn resolve_arg<'a>(symbols: &Symbols, encoding: &dyn Encoding, source_line: &SourceLine<'a>, scope: &LabelScope, address: i64, argument: &Argument<'a>, operand: Option<Operand>, diagnostics: &mut Vec<Diagnostic>) -> i64 {
+    let (expression, text) = match argument {
+        &Argument::Expression { ref expression, text } => (expression, text),
+        &Argument::None | &Argument::Invalid => return 0
+    };
+    let (value, is_offset) = match symbols.evaluate(expression, scope) {
+        Ok(Value::Integer(value)) => (value, false),
+        Ok(Value::Address(label_address)) => (label_address - (address + 1), true),
+        Err(error) => {
+            diagnostics.extend(symbols.evaluation_error_diagnostic(source_line, error));
+            return 0;
+        }
+    };
+    let (minimum, maximum) = match operand {
+        Some(Operand::Depth) => (1, isa::MAX_DEPTH),
+        _ => encoding.argument_range()
+    };
+    let value = if operand == Some(Operand::Immediate) { immediate_value(value, text, encoding) } else { value };
+    if value >= minimum && value <= maximum {
+        return value;
+    }
+    let kind = if is_offset {
+        DiagnosticKind::OffsetOutOfRange { offset: value, minimum: minimum, maximum: maximum }
+    } else {
+        DiagnosticKind::ValueOutOfRange { value: value, minimum: minimum, maximum: maximum }
+    };
+    diagnostics.push(Diagnostic::new(source_line.location(text), kind));
+    0
+}
+
+// The output starts at the first `.org` address, or at zero if code comes
+// before any `.org`. Later `.org`s move forward by filling the gap with zeros.
+fn place_lines<'a>(lines: &[SourceLine<'a>], scopes: &[LabelScope], order: &[usize], symbols: &Symbols, encoding: &dyn Encoding, diagnostics: &mut Vec<Diagnostic>) -> Vec<Placement> {
+    let mut address = 0;
+    let mut is_empty = true;
+    let mut placements = vec![Placement { address: 0, size: 0 }; lines.len()];
+    for &index in order {
+        let line = &lines[index];
+        if let Line::Origin { directive, address: ref origin } = line.line {
+            match resolve_count(symbols, line, &scopes[index], origin, diagnostics) {
+                Some(origin) if origin < address => diagnostics.push(Diagnostic::new(
+                    line.location(directive),
+                    DiagnosticKind::OriginBehind { origin: origin, address: address })),
+                Some(origin) => {
+                    let gap = if is_empty { 0 } else { origin - address };
+                    placements[index] = Placement { address: origin, size: gap };
+                    address = origin;
+                    continue;
+                },
+                None => ()
+            }
+            placements[index] = Placement { address: address, size: 0 };
+            continue;
+        }
+        let size = line_size(symbols, encoding, line, &scopes[index], address, diagnostics);
+        placements[index] = Placement { address: address, size: size };
+        address += size;
+        is_empty = is_empty && size == 0;
+    }
+    placements
+}
+
+// The number of words a line assembles to when placed at `address`.
+fn line_size<'a>(symbols: &Symbols, encoding: &dyn Encoding, source_line: &SourceLine<'a>, scope: &LabelScope, address: i64, diagnostics: &mut Vec<Diagnostic>) -> i64 {
+    match source_line.line {
+        // Any errors in the value are reported when it's resolved. A value that
+        // depends on labels is assumed to fit in a single `const`.
+        Line::Instruction { opcode: "li", arg: Argument::Expression { ref expression, .. }, .. } =>
+            match symbols.evaluate(expression, scope) {
+                Ok(Value::Integer(value)) if value < encoding.argument_range().0 || value > encoding.argument_range().1 => LOAD_IMMEDIATE_SIZE,
+                _ => 1
+            },
+        Line::Instruction {..} => 1,
+        Line::Data { width: DataWidth::Word, ref values } => values.len() as i64,
+        Line::Data { width: DataWidth::Byte, ref values } => words_for_bytes(values.len(), encoding),
+        Line::String { ref bytes } => words_for_bytes(bytes.len(), encoding),
+        Line::Space { directive, ref count } => match resolve_count(symbols, source_line, scope, count, diagnostics) {
+            Some(count) if count < 0 => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::NegativeCount { directive: directive.to_string(), count: count }));
+                0
+            },
+            Some(count) => count,
+            None => 0
+        },
+        // Pads with zeros up to the next multiple of the alignment.
+        Line::Align { directive, ref alignment } => match resolve_count(symbols, source_line, scope, alignment, diagnostics) {
+            Some(alignment) if alignment <= 0 => {
+                diagnostics.push(Diagnostic::new(
+                    source_line.location(directive),
+                    DiagnosticKind::BadAlignment { alignment: alignment }));
+                0
+            },
+            Some(alignment) => (alignment - address % alignment) % alignment,
+            None => 0
+        },
+        _ => 0
+    }
+}
+
+fn words_for_bytes(count: usize, encoding: &dyn Encoding) -> i64 {
+    let word_size = encoding.word_size();
+    ((count + word_size - 1) / word_size) as i64
+}
+
+fn resolve_count<'a>(symbols: &Symbols, source_line: &SourceLine<'a>, scope: &LabelScope, argument: &Argument<'a>, diagnostics: &mut Vec<Diagnostic>) -> Option<i64> {
+    let expression = match argument {
+        &Argument::Expression { ref expression, .. } => expression,
+        &Argument::None | &Argument::Invalid => return None
+    };
+    match symbols.evaluate(expression, scope) {
+        Ok(Value::Integer(value)) => Some(value),
+        Ok(Value::Address(_)) => None,
+        Err(error) => {
+            diagnostics.extend(symbols.evaluation_error_diagnostic(source_line, error));
+            None
+        }
+    }
+}
+
+fn find_labels<'a>(lines: &[SourceLine<'a>], scopes: &[LabelScope], placements: &[Placement], diagnostics: &mut Vec<Diagnostic>) -> Labels {
+    let mut labels = HashMap::new();
+    let mut anonymous = HashMap::new();
+    let mut definitions: HashMap<String, Location> = HashMap::new();
+    for ((line, scope), placement) in lines.iter().zip(scopes).zip(placements) {
+        if let Some(name) = line.label {
+            if symbols::is_anonymous_label(name) {
+                anonymous.entry(name.to_string()).or_insert(Vec::new()).push((scope.line, placement.address));
+                continue;
+            }
+            let location = line.location(name);
+            let qualified_name = symbols::qualify_label(&scope.name, name);
+            match definitions.get(&qualified_name) {
+                Some(previous) => diagnostics.push(Diagnostic::new(
+                    location,
+                    DiagnosticKind::DuplicateLabel { label: name.to_string(), previous: previous.clone() })),
+                None => {
+                    labels.insert(qualified_name.clone(), placement.address);
+                    definitions.insert(qualified_name, location);
+                }
+            }
+        }
+    }
+    Labels { named: labels, anonymous: anonymous, external: HashSet::new() }
+}
+
+// The labels named by `.global`, which must be defined, and those named by
+// `.extern`, which mustn't be. Only an object imports labels, so otherwise
+// any use of an `.extern` label is reported as undefined.
+fn find_visibility<'a>(lines: &[SourceLine<'a>], labels: &mut Labels, options: &Options, diagnostics: &mut Vec<Diagnostic>) -> HashSet<String> {
+    let mut global = HashSet::new();
+    let mut external = HashSet::new();
+    for line in lines {
+        match line.line {
+            Line::Global(name) => {
+                if labels.named.contains_key(name) {
+                    global.insert(name.to_string());
+                } else {
+                    diagnostics.push(Diagnostic::new(
+                        line.location(name),
+                        DiagnosticKind::UndefinedLabel {
+                            label: name.to_string(),
+                            suggestion: suggestions::closest(name, labels.named.keys().map(|label| label.as_str())).map(|label| label.to_string())
+                        }));
+                }
+            },
+            Line::Extern(name) => {
+                match lines.iter().find(|definition| definition.label == Some(name)) {
+                    Some(definition) => diagnostics.push(Diagnostic::new(
+                        definition.location(definition.label.unwrap()),
+                        DiagnosticKind::DuplicateLabel { label: name.to_string(), previous: line.location(name) })),
+                    None => {
+                        external.insert(name.to_string());
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+    if options.object {
+        labels.external = external;
+    }
+    global
+}
+
+// Each word of an object whose value depends on where a label ends up once
+// the object is linked: an instruction's offset to a label in another
+// section or object, or the address of a label in an instruction's argument
+// or a word of data. Words that only depend on the distance between labels
+// in the same section don't change.
+fn find_relocations<'a>(symbols: &Symbols, lines: &[SourceLine<'a>], scopes: &[LabelScope], sections: &[Section], placements: &[Placement], labels: &[(String, i64, Section, bool)], extents: &[(Section, i64, i64)], diagnostics: &mut Vec<Diagnostic>) -> Vec<Relocation> {
+    let context = RelocationContext { symbols: symbols, sections: sections, labels: labels };
+    let mut relocations = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        let section = sections[index];
+        let address = placements[index].address;
+        let start = extents.iter()
+            .find(|&&(extent_section, _, _)| extent_section == section)
+            .map(|&(_, start, _)| start)
+            .unwrap_or(address);
+        // Each word's address, the argument it was assembled from, and how
+        // it's relocated if its value is an address.
+        let arguments: Vec<(i64, &Argument<'a>, RelocationKind)> = match line.line {
+            Line::Instruction { ref arg, .. } if placements[index].size == 1 => {
+                let kind = match arg {
+                    &Argument::Expression { ref expression, .. } => match symbols.evaluate(expression, &scopes[index]) {
+                        Ok(Value::Address(_)) => RelocationKind::Relative,
+                        _ => RelocationKind::Absolute
+                    },
+                    &Argument::None | &Argument::Invalid => RelocationKind::Absolute
+                };
+                vec![(address, arg, kind)]
+            },
+            Line::Data { width: DataWidth::Word, ref values } =>
+                values.iter().enumerate().map(|(offset, value)| (address + offset as i64, value, RelocationKind::Data)).collect(),
+            Line::Data { width: DataWidth::Byte, ref values } => {
+                for value in values {
+                    check_unrelocated(&context, line, &scopes[index], value, diagnostics);
+                }
+                continue;
+            },
+            Line::Start { ref label, .. } => {
+                if let &Argument::Expression { ref expression, text } = label {
+                    match relocation_terms(&context, expression, &scopes[index]).as_ref().map(|terms| &terms[..]) {
+                        Some(&[(RelocationTarget::Section(_), 1)]) => (),
+                        _ => diagnostics.push(Diagnostic::new(line.location(text), DiagnosticKind::Unrelocatable { expression: text.to_string() }))
+                    }
+                }
+                continue;
+            },
+            _ => continue
+        };
+        for (word_address, argument, kind) in arguments {
+            let (expression, text) = match argument {
+                &Argument::Expression { ref expression, text } => (expression, text),
+                &Argument::None | &Argument::Invalid => continue
+            };
+            let target = match relocation_terms(&context, expression, &scopes[index]) {
+                Some(ref terms) if terms.is_empty() => continue,
+                Some(ref terms) if terms.len() == 1 && terms[0].1 == 1 => terms[0].0.clone(),
+                _ => {
+                    diagnostics.push(Diagnostic::new(line.location(text), DiagnosticKind::Unrelocatable { expression: text.to_string() }));
+                    continue;
+                }
+            };
+            // An offset within the section stays the same wherever it's placed.
+            if kind == RelocationKind::Relative && target == RelocationTarget::Section(section) {
+                continue;
+            }
+            relocations.push(Relocation { section: section, offset: (word_address - start) as u32, kind: kind, target: target });
+        }
+    }
+    relocations.sort_by_key(|relocation| (relocation.section, relocation.offset));
+    relocations
+}
+
+// Reports each instruction argument and word of data that holds the address
+// of a label rather than an offset to it, which would be wrong if the
+// program were loaded anywhere else.
+fn check_position_independent<'a>(symbols: &Symbols, lines: &[SourceLine<'a>], scopes: &[LabelScope], sections: &[Section], labels: &[(String, i64, Section, bool)], diagnostics: &mut Vec<Diagnostic>) {
+    let context = RelocationContext { symbols: symbols, sections: sections, labels: labels };
+    for (index, line) in lines.iter().enumerate() {
+        let arguments: Vec<&Argument<'a>> = match line.line {
+            Line::Instruction { ref arg, .. } => vec![arg],
+            Line::Data { ref values, .. } => values.iter().collect(),
+            _ => continue
+        };
+        for argument in arguments {
+            let (expression, text) = match argument {
+                &Argument::Expression { ref expression, text } => (expression, text),
+                &Argument::None | &Argument::Invalid => continue
+            };
+            let is_offset = match (&line.line, symbols.evaluate(expression, &scopes[index])) {
+                (&Line::Instruction {..}, Ok(Value::Address(_))) => true,
+                _ => false
+            };
+            if !is_offset && base_terms(&context, expression, &scopes[index]) != Some(0) {
+                diagnostics.push(Diagnostic::new(line.location(text), DiagnosticKind::AbsoluteAddress { expression: text.to_string() }));
+            }
+        }
+    }
+}
+
+// A value that's only checked not to depend on where labels end up, since
+// it can't be relocated.
+fn check_unrelocated<'a>(context: &RelocationContext, line: &SourceLine<'a>, scope: &LabelScope, argument: &Argument<'a>, diagnostics: &mut Vec<Diagnostic>) {
+    if let &Argument::Expression { ref expression, text } = argument {
+        if relocation_terms(context, expression, scope).map(|terms| !terms.is_empty()).unwrap_or(true) {
+            diagnostics.push(Diagnostic::new(line.location(text), DiagnosticKind::Unrelocatable { expression: text.to_string() }));
+        }
+    }
+}
+
+struct RelocationContext<'r, 's: 'r, 'a: 's> {
+    symbols: &'r Symbols<'s, 'a>,
+    // The section of each line.
+    sections: &'r [Section],
+    labels: &'r [(String, i64, Section, bool)]
+}
+
+// What an expression's value moves with when the object is linked: the
+// section of each label it uses, or the label itself if it's imported, each
+// with the number of times it's added.
+fn relocation_terms<'e>(context: &RelocationContext, expression: &Expression<'e>, scope: &LabelScope) -> Option<Vec<(RelocationTarget, i64)>> {
+    target_terms(context, expression, scope, &|name, scope| label_target(context, name, scope))
+}
+
+// How many times an expression's value moves with the address the program is
+// loaded at, which is `None` if it's not a whole number of times.
+fn base_terms<'e>(context: &RelocationContext, expression: &Expression<'e>, scope: &LabelScope) -> Option<i64> {
+    target_terms(context, expression, scope, &|name, scope| label_target(context, name, scope).map(|_| ()))
+        .map(|terms| terms.iter().map(|&(_, count)| count).sum())
+}
+
+// The terms of an expression, with `target` giving what each label moves
+// with. Constants are replaced by the terms of their definitions.
+fn target_terms<'e, T: PartialEq>(context: &RelocationContext, expression: &Expression<'e>, scope: &LabelScope, target: &dyn Fn(&str, &LabelScope) -> Option<T>) -> Option<Vec<(T, i64)>> {
+    let leaf = |leaf: &Expression<'e>| match leaf {
+        &Expression::Label(name) | &Expression::AbsoluteLabel(name) => Some(target(name, scope).into_iter().map(|target| (target, 1)).collect()),
+        &Expression::Symbol(name) => match context.symbols.definition(name) {
+            Some(definition) => target_terms(context, definition.expression, definition.scope, target),
+            None => Some(Vec::new())
+        },
+        _ => Some(Vec::new())
+    };
+    expression::label_terms(expression, &leaf)
+}
+
+fn label_target(context: &RelocationContext, name: &str, scope: &LabelScope) -> Option<RelocationTarget> {
+    let labels = match context.symbols.labels() {
+        Some(labels) => labels,
+        None => return None
+    };
+    if let Some((number, forward)) = symbols::anonymous_reference(name) {
+        return labels.anonymous_definition(number, forward, scope.line)
+            .map(|&(line, _)| RelocationTarget::Section(context.sections[line]));
+    }
+    let name = symbols::qualify_label(&scope.name, name);
+    if labels.external.contains(&name) {
+        return Some(RelocationTarget::Symbol(name));
+    }
+    context.labels.iter()
+        .find(|&&(ref label, _, _, _)| *label == name)
+        .map(|&(_, _, section, _)| RelocationTarget::Section(section))
+}
+
+fn find_constants<'s, 'a>(lines: &'s [SourceLine<'a>], scopes: &'s [LabelScope], options: &Options, diagnostics: &mut Vec<Diagnostic>) -> HashMap<&'a str, Definition<'s, 'a>> {
+    let mut constants: HashMap<&'a str, Definition<'s, 'a>> = HashMap::new();
+    for (line, scope) in lines.iter().zip(scopes) {
+        if let Line::Constant { name, value: Argument::Expression { ref expression, .. } } = line.line {
+            if let Some((&previous_name, previous)) = constants.get_key_value(name) {
+                diagnostics.push(Diagnostic::new(
+                    line.location(name),
+                    DiagnosticKind::DuplicateSymbol {
+                        symbol: name.to_string(),
+                        previous: previous.source_line.location(previous_name)
+                    }));
+                continue;
+            }
+            if options.defines.contains_key(name) {
+                diagnostics.push(Diagnostic::new(
+                    line.location(name),
+                    DiagnosticKind::PredefinedSymbol { symbol: name.to_string() }));
+                continue;
+            }
+            constants.insert(name, Definition { source_line: line, expression: expression, scope: scope });
+        }
+    }
+    constants
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+
+use container::ContainerError;
+use debug::DebugInfo;
+use disasm;
+use encoder::Endian;
+use encoding::Profile;
+
+// How many words of the bytecode each function takes, largest first, as
+// `NAME SECTION WORDS BYTES PERCENT` with a total at the end. A word belongs
+// to the `.func` it's in, or otherwise to the closest label before it in the
+// same section, not counting labels local to another, which are named as in
+// `loop.done`. Words before any label are counted as `(unlabelled)`. Which
+// words are where is only known from debug info.
+pub fn size(bytes: &[u8], debug_info: &DebugInfo, endian: Endian, profile: Profile) -> Result<String, ContainerError> {
+    let bytecode = try!(disasm::read_bytecode(bytes, endian, profile));
+    let word_size = bytecode.profile.encoding().word_size();
+    let origin = debug_info.origin();
+    let mut sizes: Vec<(String, &str, u32, u32)> = Vec::new();
+    let mut indices: HashMap<(String, &str), usize> = HashMap::new();
+    for address in origin..origin + bytecode.words.len() as u32 {
+        let section = section_of(debug_info, address);
+        let function = debug_info.functions.iter()
+            .find(|&&(_, start, size)| address >= start && address < start + size)
+            .map(|&(ref name, _, _)| name.clone());
+        let name = function.or_else(|| debug_info.labels.iter()
+                .filter(|&&(ref name, label_address)| !name.contains('.') && label_address <= address && section_of(debug_info, label_address) == section)
+                .last()
+                .map(|&(ref name, _)| name.clone()))
+            .unwrap_or("(unlabelled)".to_string());
+        let index = *indices.entry((name.clone(), section)).or_insert_with(|| {
+            sizes.push((name, section, address, 0));
+            sizes.len() - 1
+        });
+        sizes[index].3 += 1;
+    }
+    sizes.sort_by(|&(_, _, address, size), &(_, _, other_address, other_size)| other_size.cmp(&size).then(address.cmp(&other_address)));
+    let total = bytecode.words.len() as u32;
+    let mut output = format!("{:<24} {:<8} {:>8} {:>8} {:>7}\n", "name", "section", "words", "bytes", "%");
+    for (name, section, _, size) in sizes {
+        output.push_str(&format!("{:<24} {:<8} {:>8} {:>8} {:>6.1}%\n", name, section, size, size as usize * word_size, 100.0 * size as f64 / total as f64));
+    }
+    output.push_str(&format!("{:<24} {:<8} {:>8} {:>8}\n", "total", "", total, total as usize * word_size));
+    Ok(output)
+}
+
+// The name of the section `address` is in.
+fn section_of(debug_info: &DebugInfo, address: u32) -> &str {
+    debug_info.sections.iter()
+        .find(|&&(_, start, size)| address >= start && address < start + size)
+        .map_or("", |&(ref name, _, _)| name.as_str())
+}
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use error::{Diagnostic, DiagnosticKind, Location};
+use parser;
+
+pub struct SourceFile {
+    pub name: Option<String>,
+    pub path: Option<PathBuf>,
+    pub text: String
+}
+
+// A line of one of the loaded files, in the order it is assembled once
+// `.include` directives have been expanded.
+pub struct SourceLineRef {
+    pub file: usize,
+    pub line_number: usize,
+    start: usize,
+    end: usize
+}
+
+pub struct Sources {
+    pub files: Vec<SourceFile>
+}
+
+impl SourceFile {
+    pub fn from_string(text: String) -> SourceFile {
+        SourceFile { name: None, path: None, text: text }
+    }
+
+    pub fn read(path: &Path) -> io::Result<SourceFile> {
+        let mut file = try!(File::open(path));
+        let mut text = String::new();
+        try!(file.read_to_string(&mut text));
+        Ok(SourceFile {
+            name: Some(path.to_string_lossy().into_owned()),
+            path: Some(path.to_path_buf()),
+            text: text
+        })
+    }
+}
+
+impl Sources {
+    // Loads `root` and, recursively, every file it includes.
+    pub fn load(root: SourceFile, diagnostics: &mut Vec<Diagnostic>) -> (Sources, Vec<SourceLineRef>) {
+        let mut sources = Sources { files: vec![root] };
+        let mut lines = Vec::new();
+        let mut including: Vec<PathBuf> = sources.files[0].path.iter()
+            .map(|path| path.canonicalize().unwrap_or(path.clone()))
+            .collect();
+        sources.load_file(0, &mut including, &mut lines, diagnostics);
+        (sources, lines)
+    }
+
+    pub fn text(&self, line: &SourceLineRef) -> &str {
+        &self.files[line.file].text[line.start..line.end]
+    }
+
+    pub fn name(&self, line: &SourceLineRef) -> Option<&str> {
+        self.files[line.file].name.as_ref().map(|name| name.as_str())
+    }
+
+    fn load_file(&mut self, file: usize, including: &mut Vec<PathBuf>, lines: &mut Vec<SourceLineRef>, diagnostics: &mut Vec<Diagnostic>) {
+        let includes = find_includes(file, &self.files[file]);
+        let directory = self.files[file].path.as_ref()
+            .and_then(|path| path.parent())
+            .map(|directory| directory.to_path_buf())
+            .unwrap_or(PathBuf::new());
+        for (line, include) in includes {
+            lines.push(line);
+            let (location, path) = match include {
+                Some(include) => include,
+                None => continue
+            };
+            let path = directory.join(path);
+            let canonical_path = path.canonicalize().unwrap_or(path.clone());
+            if including.contains(&canonical_path) {
+                diagnostics.push(Diagnostic::new(
+                    location,
+                    DiagnosticKind::IncludeCycle { path: path.to_string_lossy().into_owned() }));
+                continue;
+            }
+            match SourceFile::read(&path) {
+                Ok(source_file) => {
+                    self.files.push(source_file);
+                    let included = self.files.len() - 1;
+                    including.push(canonical_path);
+                    self.load_file(included, including, lines, diagnostics);
+                    including.pop();
+                },
+                Err(error) => diagnostics.push(Diagnostic::new(
+                    location,
+                    DiagnosticKind::IncludeFailed {
+                        path: path.to_string_lossy().into_owned(),
+                        reason: error.to_string()
+                    }))
+            }
+        }
+    }
+}
+
+fn find_includes(file: usize, source_file: &SourceFile) -> Vec<(SourceLineRef, Option<(Location, String)>)> {
+    let text = &source_file.text;
+    let mut result = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let start = line.as_ptr() as usize - text.as_ptr() as usize;
+        let line_ref = SourceLineRef { file: file, line_number: index + 1, start: start, end: start + line.len() };
+        let include = parser::parse_include(line).map(|(column, path)| {
+            let location = Location {
+                file: source_file.name.clone(),
+                line_number: index + 1,
+                column: column
+            };
+            (location, path)
+        });
+        result.push((line_ref, include));
+    }
+    result
+}
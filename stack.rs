@@ -0,0 +1,314 @@
+use std::collections::{HashMap, HashSet};
+
+use error::{Diagnostic, DiagnosticKind, Location};
+use expression::Expression;
+use isa::{InstructionSet, Operand};
+use optimizer::{self, Targets};
+use parser::{self, Argument, Line, Section, SourceLine};
+use resolver;
+use symbols::LabelScope;
+
+// What calling a function does to the stack: how many values it takes from
+// the caller, and how many more or fewer values there are when it returns.
+// A function that never returns has no effect.
+#[derive(Clone, Copy)]
+struct Summary {
+    needs: i64,
+    effect: Option<i64>
+}
+
+// Where a path is followed from.
+#[derive(Clone, Copy, PartialEq)]
+enum Start {
+    // Where the program starts, with an empty stack.
+    Program,
+    // A function without a stack effect, which may take any values.
+    Function,
+    // A function with a stack effect, which takes that many values, and so
+    // must leave the number it says it does.
+    Declared { pops: i64, pushes: i64 }
+}
+
+// Follows each path through `.text` from where the program starts, counting
+// how many values are on the stack before each instruction, and warns about
+// any instruction that takes more values than there are, or any line that's
+// reached with a different number of values along different paths. Functions
+// are followed separately, counting from the values on the stack when they're
+// called, so that each `call` takes the values the function needs and leaves
+// what it returns. A path isn't followed any further once it can't be told
+// what an instruction does to the stack, such as a recursive call or a
+// `syscall`.
+//
+// Functions given a stack effect, with `.stack` or a comment such as
+// `; ( a b -- c )`, are called as they say, and are checked even if the rest
+// of the program isn't.
+pub fn check_stack<'a>(lines: &[SourceLine<'a>], instruction_set: &InstructionSet, whole_program: bool, diagnostics: &mut Vec<Diagnostic>) {
+    let effects = stack_effects(lines, diagnostics);
+    if !whole_program && effects.is_empty() {
+        return;
+    }
+    // There's no telling where a branch goes if its target isn't a label.
+    if lines.iter().any(|line| optimizer::branch_target(line, instruction_set) == Some(None)) {
+        return;
+    }
+    let scopes = resolver::label_scopes(lines);
+    let sections = resolver::line_sections(lines);
+    let code: Vec<usize> = (0..lines.len()).filter(|&index| sections[index] == Section::Text).collect();
+    let mut analysis = Analysis {
+        lines: lines,
+        instruction_set: instruction_set,
+        targets: Targets::new(lines, &scopes),
+        scopes: scopes,
+        sections: sections,
+        next: code.windows(2).map(|pair| (pair[0], pair[1])).collect(),
+        effects: effects,
+        functions: HashMap::new(),
+        diagnostics: diagnostics
+    };
+    if whole_program {
+        if let Some(entry) = analysis.entry(code.first().cloned()) {
+            analysis.follow(entry, Start::Program);
+        }
+    }
+    let mut declared: Vec<usize> = analysis.effects.keys().cloned().collect();
+    declared.sort();
+    for start in declared {
+        analysis.summary(start);
+    }
+}
+
+// The line each stack effect is given for: the `.func` that a `.stack` is in,
+// or otherwise the next label, or a label with a stack effect comment on the
+// same line. A `.stack` is used over a comment.
+fn stack_effects<'a>(lines: &[SourceLine<'a>], diagnostics: &mut Vec<Diagnostic>) -> HashMap<usize, (i64, i64)> {
+    let mut effects = HashMap::new();
+    let mut given: HashMap<usize, Location> = HashMap::new();
+    let mut function = None;
+    let mut pending: Option<(&str, Location, i64, i64)> = None;
+    for (index, line) in lines.iter().enumerate() {
+        match line.line {
+            Line::Function {..} => function = Some(index),
+            Line::EndFunction {..} => function = None,
+            _ => ()
+        }
+        if let Line::StackEffect { directive, pops, pushes } = line.line {
+            if let Some((directive, location, _, _)) = pending.take() {
+                diagnostics.push(Diagnostic::new(location, DiagnosticKind::UnattachedDirective { directive: directive.to_string() }));
+            }
+            pending = Some((directive, line.location(directive), pops, pushes));
+        }
+        let target = match (function, line.label) {
+            (Some(function), _) => Some(function),
+            (None, Some(_)) => Some(index),
+            (None, None) => None
+        };
+        if let (Some(target), Some((directive, location, pops, pushes))) = (target, pending.clone()) {
+            pending = None;
+            match given.get(&target) {
+                Some(previous) => diagnostics.push(Diagnostic::new(
+                    location,
+                    DiagnosticKind::DuplicateDirective { directive: directive.to_string(), previous: previous.clone() })),
+                None => {
+                    given.insert(target, location);
+                    effects.insert(target, (pops, pushes));
+                }
+            }
+        }
+    }
+    if let Some((directive, location, _, _)) = pending {
+        diagnostics.push(Diagnostic::new(location, DiagnosticKind::UnattachedDirective { directive: directive.to_string() }));
+    }
+    for (index, line) in lines.iter().enumerate() {
+        if let (Some(_), Some(effect)) = (line.label, parser::stack_comment(line.text)) {
+            effects.entry(index).or_insert(effect);
+        }
+    }
+    effects
+}
+
+struct Analysis<'l, 'a: 'l, 'd> {
+    lines: &'l [SourceLine<'a>],
+    instruction_set: &'l InstructionSet,
+    targets: Targets,
+    scopes: Vec<LabelScope>,
+    sections: Vec<Section>,
+    next: HashMap<usize, usize>,
+    effects: HashMap<usize, (i64, i64)>,
+    // What each function called so far does, which is `None` while it's
+    // being followed, or if it can't be told.
+    functions: HashMap<usize, Option<Summary>>,
+    diagnostics: &'d mut Vec<Diagnostic>
+}
+
+impl<'l, 'a, 'd> Analysis<'l, 'a, 'd> {
+    // The line given by `.start`, if it's a plain label, or otherwise the
+    // first line of code.
+    fn entry(&self, first: Option<usize>) -> Option<usize> {
+        for (index, line) in self.lines.iter().enumerate() {
+            if let Line::Start { ref label, .. } = line.line {
+                return match label {
+                    &Argument::Expression { expression: Expression::Label(name), .. } => self.targets.find(name, &self.scopes[index]),
+                    _ => None
+                };
+            }
+        }
+        first
+    }
+
+    // Follows every path from `start`, with the stack depth counted from zero
+    // there, or from the values a function says it takes. In a function
+    // without a stack effect, taking values below that is how it reads its
+    // arguments, rather than an underflow.
+    fn follow(&mut self, start: usize, from: Start) -> Option<Summary> {
+        let mut depths: HashMap<usize, i64> = HashMap::new();
+        let mut reported = HashSet::new();
+        let mut lowest = 0;
+        let mut returns: Option<i64> = None;
+        let mut known = true;
+        let mut pending = vec![(start, match from { Start::Declared { pops, .. } => pops, _ => 0 })];
+        while let Some((index, depth)) = pending.pop() {
+            if self.sections[index] != Section::Text {
+                continue;
+            }
+            if let Some(&previous) = depths.get(&index) {
+                if previous != depth && reported.insert(index) {
+                    let location = line_location(&self.lines[index]);
+                    self.diagnostics.push(Diagnostic::new(location, DiagnosticKind::InconsistentStackDepth { depth: depth, previous: previous }));
+                }
+                continue;
+            }
+            depths.insert(index, depth);
+            let line = &self.lines[index];
+            let (opcode, token) = match line.line {
+                Line::Instruction { opcode, token, .. } => (opcode, token),
+                _ => {
+                    pending.extend(self.next.get(&index).map(|&next| (next, depth)));
+                    continue;
+                }
+            };
+            let target = optimizer::branch_target(line, self.instruction_set)
+                .and_then(|target| target)
+                .and_then(|name| self.targets.find(name, &self.scopes[index]));
+            let (pops, pushes, returns_here) = if opcode == "call" {
+                match target.and_then(|target| self.summary(target)) {
+                    Some(Summary { needs, effect }) => (needs, needs + effect.unwrap_or(0), effect.is_some()),
+                    None => {
+                        known = false;
+                        continue;
+                    }
+                }
+            } else if opcode == "syscall" {
+                known = false;
+                continue;
+            } else {
+                match self.effect(line) {
+                    Some((pops, pushes)) => (pops, pushes, true),
+                    None => {
+                        known = false;
+                        continue;
+                    }
+                }
+            };
+            if depth < pops {
+                if from == Start::Function {
+                    lowest = lowest.min(depth - pops);
+                } else {
+                    self.diagnostics.push(Diagnostic::new(
+                        line.location(token),
+                        DiagnosticKind::StackUnderflow { opcode: opcode.to_string(), needs: pops, depth: depth }));
+                    continue;
+                }
+            }
+            let depth = depth - pops + pushes;
+            if opcode == "ret" {
+                if let Start::Declared { pushes, .. } = from {
+                    if depth != pushes {
+                        self.diagnostics.push(Diagnostic::new(
+                            line.location(token),
+                            DiagnosticKind::StackEffectMismatch { depth: depth, pushes: pushes }));
+                    }
+                    continue;
+                }
+                match returns {
+                    Some(previous) if previous != depth && reported.insert(index) =>
+                        self.diagnostics.push(Diagnostic::new(
+                            line.location(token),
+                            DiagnosticKind::InconsistentStackDepth { depth: depth, previous: previous })),
+                    Some(_) => (),
+                    None => returns = Some(depth)
+                }
+                continue;
+            }
+            if opcode != "call" {
+                pending.extend(target.map(|target| (target, depth)));
+            }
+            if returns_here && !optimizer::is_terminator(line) {
+                pending.extend(self.next.get(&index).map(|&next| (next, depth)));
+            }
+        }
+        if known {
+            Some(Summary { needs: -lowest, effect: returns })
+        } else {
+            None
+        }
+    }
+
+    // What calling the function at `start` does, following it the first time
+    // it's called. A function with a stack effect does what it says, and is
+    // only followed to check that it does.
+    fn summary(&mut self, start: usize) -> Option<Summary> {
+        if let Some(&summary) = self.functions.get(&start) {
+            return summary;
+        }
+        match self.effects.get(&start).cloned() {
+            Some((pops, pushes)) => {
+                let summary = Some(Summary { needs: pops, effect: Some(pushes - pops) });
+                self.functions.insert(start, summary);
+                self.follow(start, Start::Declared { pops: pops, pushes: pushes });
+                summary
+            },
+            None => {
+                self.functions.insert(start, None);
+                let summary = self.follow(start, Start::Function);
+                self.functions.insert(start, summary);
+                summary
+            }
+        }
+    }
+
+    // How many values an instruction takes from the stack and leaves on it,
+    // if it can be told: counts that depend on the argument need it to be
+    // written as a number.
+    fn effect(&self, line: &SourceLine<'a>) -> Option<(i64, i64)> {
+        let (opcode, arg) = match line.line {
+            Line::Instruction { opcode, ref arg, .. } => (opcode, arg),
+            _ => return None
+        };
+        let opcode = match self.instruction_set.opcode(opcode) {
+            Some(opcode) => opcode,
+            None => return None
+        };
+        let argument = match arg {
+            &Argument::Expression { ref expression, .. } if opcode.operand != Operand::Label => optimizer::literal(expression),
+            _ => None
+        };
+        let count = |fixed: u32, depends: bool| if depends {
+            argument.map(|argument| fixed as i64 + argument)
+        } else {
+            Some(fixed as i64)
+        };
+        match (count(opcode.pops.fixed, opcode.pops.argument), count(opcode.pushes.fixed, opcode.pushes.argument)) {
+            (Some(pops), Some(pushes)) => Some((pops, pushes)),
+            _ => None
+        }
+    }
+}
+
+// Where a line starts: its label, or otherwise its first character.
+fn line_location(line: &SourceLine) -> Location {
+    match (&line.line, line.label) {
+        (&Line::Instruction { token, .. }, None) => line.location(token),
+        (_, Some(label)) => line.location(label),
+        (_, None) => line.location(line.text)
+    }
+}
@@ -0,0 +1,35 @@
+pub fn closest<'a, I: Iterator<Item=&'a str>>(name: &str, candidates: I) -> Option<&'a str> {
+    let max_distance = ::std::cmp::max(1, name.chars().count() / 3);
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+// Optimal string alignment distance, so that transpositions such as
+// `cosnt` for `const` count as a single edit.
+fn edit_distance(left: &str, right: &str) -> usize {
+    let left: Vec<char> = left.chars().collect();
+    let right: Vec<char> = right.chars().collect();
+    let mut distances = vec![vec![0; right.len() + 1]; left.len() + 1];
+    for i in 0..left.len() + 1 {
+        distances[i][0] = i;
+    }
+    for j in 0..right.len() + 1 {
+        distances[0][j] = j;
+    }
+    for i in 1..left.len() + 1 {
+        for j in 1..right.len() + 1 {
+            let cost = if left[i - 1] == right[j - 1] { 0 } else { 1 };
+            let mut distance = ::std::cmp::min(
+                ::std::cmp::min(distances[i - 1][j] + 1, distances[i][j - 1] + 1),
+                distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && left[i - 1] == right[j - 2] && left[i - 2] == right[j - 1] {
+                distance = ::std::cmp::min(distance, distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[left.len()][right.len()]
+}
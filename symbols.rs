@@ -0,0 +1,225 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use error::{Diagnostic, DiagnosticKind};
+use expression::{self, Environment, EvaluationError, EvaluationErrorKind, Expression, Value};
+use parser::SourceLine;
+use suggestions;
+
+enum Constant {
+    Evaluating,
+    Evaluated(Option<Value>)
+}
+
+#[derive(Clone, Copy)]
+pub struct Definition<'s, 'a: 's> {
+    pub source_line: &'s SourceLine<'a>,
+    pub expression: &'s Expression<'a>,
+    pub scope: &'s LabelScope
+}
+
+pub struct Labels {
+    pub named: HashMap<String, i64>,
+    // The line index and address of each definition of an anonymous label,
+    // in source order.
+    pub anonymous: HashMap<String, Vec<(usize, i64)>>,
+    // Labels imported from other objects, which are at address zero until
+    // they're linked.
+    pub external: HashSet<String>
+}
+
+impl Labels {
+    // `:1b` is the nearest definition of `1` at or before `line`, and `:1f`
+    // the nearest one after it.
+    pub fn anonymous_definition(&self, name: &str, forward: bool, line: usize) -> Option<&(usize, i64)> {
+        let definitions = match self.anonymous.get(name) {
+            Some(definitions) => definitions,
+            None => return None
+        };
+        if forward {
+            definitions.iter().find(|&&(definition_line, _)| definition_line > line)
+        } else {
+            definitions.iter().rev().find(|&&(definition_line, _)| definition_line <= line)
+        }
+    }
+}
+
+// Where an expression appears: the scope of local labels such as `:.loop`,
+// and the index of the line, which anonymous labels such as `:1b` are
+// resolved relative to.
+pub struct LabelScope {
+    pub name: String,
+    pub line: usize
+}
+
+// Labels, `.equ` constants and predefined constants visible to argument
+// expressions. `.equ` constants are evaluated on first use, so they may refer
+// to labels and to constants defined later in the source. While addresses are
+// still being assigned there are no labels, and any use of one is reported
+// as not being constant.
+pub struct Symbols<'s, 'a: 's> {
+    labels: Option<Labels>,
+    predefined: &'s HashMap<String, i64>,
+    definitions: HashMap<&'a str, Definition<'s, 'a>>,
+    constants: RefCell<HashMap<&'a str, Constant>>,
+    diagnostics: RefCell<Vec<Diagnostic>>
+}
+
+impl<'s, 'a> Symbols<'s, 'a> {
+    pub fn new(labels: Option<Labels>, definitions: HashMap<&'a str, Definition<'s, 'a>>, predefined: &'s HashMap<String, i64>) -> Symbols<'s, 'a> {
+        Symbols {
+            labels: labels,
+            predefined: predefined,
+            definitions: definitions,
+            constants: RefCell::new(HashMap::new()),
+            diagnostics: RefCell::new(Vec::new())
+        }
+    }
+
+    pub fn labels(&self) -> Option<&Labels> {
+        self.labels.as_ref()
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&Definition<'s, 'a>> {
+        self.definitions.get(name)
+    }
+
+    // Evaluates every constant, including unused ones, so that errors in
+    // their definitions are always reported.
+    pub fn evaluate_constants(&self, diagnostics: &mut Vec<Diagnostic>) {
+        let mut names: Vec<&&str> = self.definitions.keys().collect();
+        names.sort_by_key(|name| self.definitions[**name].source_line.line_number);
+        for name in names {
+            let _ = self.symbol(name);
+        }
+        diagnostics.extend(self.diagnostics.borrow_mut().drain(..));
+    }
+
+    pub fn evaluate<'e>(&self, expression: &Expression<'e>, scope: &LabelScope) -> Result<Value, EvaluationError<'e>> {
+        expression::evaluate(expression, &Scope { symbols: self, scope: scope })
+    }
+
+    pub fn evaluation_error_diagnostic(&self, source_line: &SourceLine, error: EvaluationError) -> Option<Diagnostic> {
+        let kind = match error.kind {
+            EvaluationErrorKind::UndefinedLabel => DiagnosticKind::UndefinedLabel {
+                label: error.token.to_string(),
+                suggestion: suggestions::closest(error.token, self.labels.iter().flat_map(|labels| labels.named.keys()).map(|label| label.as_str()))
+                    .map(|label| label.to_string())
+            },
+            EvaluationErrorKind::UndefinedSymbol => DiagnosticKind::UndefinedSymbol {
+                symbol: error.token.to_string(),
+                suggestion: suggestions::closest(
+                        error.token,
+                        self.definitions.keys().map(|&name| name).chain(self.predefined.keys().map(|name| name.as_str())))
+                    .map(|name| name.to_string())
+            },
+            EvaluationErrorKind::DivisionByZero => DiagnosticKind::DivisionByZero,
+            EvaluationErrorKind::Overflow => DiagnosticKind::Overflow,
+            EvaluationErrorKind::InvalidAddressArithmetic => DiagnosticKind::InvalidAddressArithmetic,
+            EvaluationErrorKind::CircularDefinition => DiagnosticKind::CircularDefinition { symbol: error.token.to_string() },
+            EvaluationErrorKind::NotConstant => DiagnosticKind::NotConstant { symbol: error.token.to_string() },
+            EvaluationErrorKind::InvalidSymbol => return None
+        };
+        Some(Diagnostic::new(source_line.location(error.token), kind))
+    }
+}
+
+impl<'s, 'a> Environment for Symbols<'s, 'a> {
+    fn label(&self, name: &str) -> Result<Value, EvaluationErrorKind> {
+        match self.labels {
+            Some(ref labels) => labels.named.get(name).cloned()
+                .or(if labels.external.contains(name) { Some(0) } else { None })
+                .map(Value::Address)
+                .ok_or(EvaluationErrorKind::UndefinedLabel),
+            None => Err(EvaluationErrorKind::NotConstant)
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<Value, EvaluationErrorKind> {
+        if let Some(&value) = self.predefined.get(name) {
+            return Ok(Value::Integer(value));
+        }
+        let (&name, definition) = match self.definitions.get_key_value(name) {
+            Some(entry) => entry,
+            None => return Err(EvaluationErrorKind::UndefinedSymbol)
+        };
+        match self.constants.borrow().get(name) {
+            Some(&Constant::Evaluating) => return Err(EvaluationErrorKind::CircularDefinition),
+            Some(&Constant::Evaluated(Some(value))) => return Ok(value),
+            Some(&Constant::Evaluated(None)) => return Err(EvaluationErrorKind::InvalidSymbol),
+            None => ()
+        }
+        self.constants.borrow_mut().insert(name, Constant::Evaluating);
+        let value = match self.evaluate(definition.expression, definition.scope) {
+            Ok(value) => Some(value),
+            Err(EvaluationError { kind: EvaluationErrorKind::NotConstant, .. }) => {
+                // The definition is valid, but can't be evaluated yet.
+                self.constants.borrow_mut().remove(name);
+                return Err(EvaluationErrorKind::NotConstant);
+            },
+            Err(error) => {
+                if let Some(diagnostic) = self.evaluation_error_diagnostic(definition.source_line, error) {
+                    self.diagnostics.borrow_mut().push(diagnostic);
+                }
+                None
+            }
+        };
+        self.constants.borrow_mut().insert(name, Constant::Evaluated(value));
+        value.ok_or(EvaluationErrorKind::InvalidSymbol)
+    }
+}
+
+impl<'s, 'a> Symbols<'s, 'a> {
+    fn anonymous_label(&self, name: &str, forward: bool, line: usize) -> Result<Value, EvaluationErrorKind> {
+        match self.labels {
+            Some(ref labels) => labels.anonymous_definition(name, forward, line)
+                .map(|&(_, address)| Value::Address(address))
+                .ok_or(EvaluationErrorKind::UndefinedLabel),
+            None => Err(EvaluationErrorKind::NotConstant)
+        }
+    }
+}
+
+struct Scope<'e, 's: 'e, 'a: 's> {
+    symbols: &'e Symbols<'s, 'a>,
+    scope: &'e LabelScope
+}
+
+impl<'e, 's, 'a> Environment for Scope<'e, 's, 'a> {
+    fn label(&self, name: &str) -> Result<Value, EvaluationErrorKind> {
+        match anonymous_reference(name) {
+            Some((number, forward)) => self.symbols.anonymous_label(number, forward, self.scope.line),
+            None => self.symbols.label(&qualify_label(&self.scope.name, name))
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<Value, EvaluationErrorKind> {
+        self.symbols.symbol(name)
+    }
+}
+
+// Anonymous labels are numbers, and may be defined any number of times.
+pub fn is_anonymous_label(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_digit(10))
+}
+
+// A reference to an anonymous label, such as `1f`, split into the label and
+// whether it refers forwards.
+pub fn anonymous_reference(name: &str) -> Option<(&str, bool)> {
+    let number = name.trim_end_matches(|c| c == 'b' || c == 'f');
+    if number.len() + 1 == name.len() && is_anonymous_label(number) {
+        Some((number, name.ends_with("f")))
+    } else {
+        None
+    }
+}
+
+// Local labels, which start with a `.`, are stored under the name of their
+// scope, so `.loop` after the label `outer` is `outer.loop`.
+pub fn qualify_label(scope: &str, name: &str) -> String {
+    if name.starts_with(".") {
+        format!("{}{}", scope, name)
+    } else {
+        name.to_string()
+    }
+}
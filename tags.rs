@@ -0,0 +1,110 @@
+use parser;
+use symbols;
+
+// What a tag names, with the letter ctags gives it.
+#[derive(Clone, Copy)]
+enum Kind {
+    Label,
+    Function,
+    Constant,
+    Macro
+}
+
+impl Kind {
+    fn letter(&self) -> char {
+        match self {
+            &Kind::Label => 'l',
+            &Kind::Function => 'f',
+            &Kind::Constant => 'd',
+            &Kind::Macro => 'm'
+        }
+    }
+}
+
+// A definition in a source file: the line it's on, counted from one, where
+// that line starts, and the line itself.
+struct Tag<'a> {
+    name: &'a str,
+    kind: Kind,
+    line_number: usize,
+    offset: usize,
+    text: &'a str
+}
+
+// A ctags file for the labels, functions, `.equ` constants and macros defined
+// in each of `files`, given as their path and text, sorted by name as vim and
+// emacs expect. Each tag is found by searching for the line it's on. Files
+// are read as they're written, so nothing they include is tagged, and
+// definitions on both sides of a conditional are. Local labels such as
+// `:.loop` are tagged without their `.`, as they're usually looked up from a
+// reference such as `jmp .loop`.
+pub fn ctags(files: &[(&str, &str)]) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for &(path, text) in files {
+        for tag in tags(text) {
+            lines.push(format!("{}\t{}\t/^{}$/;\"\t{}\n", tag.name, path, escape(tag.text), tag.kind.letter()));
+        }
+    }
+    lines.sort();
+    let mut output = String::from("!_TAG_FILE_FORMAT\t2\t/extended format/\n!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted/\n");
+    for line in lines {
+        output.push_str(&line);
+    }
+    output
+}
+
+// The same tags in an etags file, with a section for each file listing its
+// tags in the order they're defined.
+pub fn etags(files: &[(&str, &str)]) -> String {
+    let mut output = String::new();
+    for &(path, text) in files {
+        let mut section = String::new();
+        for tag in tags(text) {
+            // The line is given up to the end of the name, which is part of it.
+            let end = tag.name.as_ptr() as usize - tag.text.as_ptr() as usize + tag.name.len();
+            section.push_str(&format!("{}\x7f{}\x01{},{}\n", &tag.text[..end], tag.name, tag.line_number, tag.offset));
+        }
+        output.push_str(&format!("\x0c\n{},{}\n{}", path, section.len(), section));
+    }
+    output
+}
+
+fn tags<'a>(text: &'a str) -> Vec<Tag<'a>> {
+    let mut tags = Vec::new();
+    let mut offset = 0;
+    for (index, line) in text.split('\n').enumerate() {
+        let start = offset;
+        offset += line.len() + 1;
+        let line = line.trim_end_matches('\r');
+        let tokens = parser::tokenize(line);
+        let mut rest = &tokens[..];
+        let mut found = Vec::new();
+        if let Some(label) = tokens.first().and_then(|token| parser::parse_label_definition(token)) {
+            rest = &tokens[1..];
+            let name = if label.starts_with(".") { &label[1..] } else { label };
+            if !symbols::is_anonymous_label(name) {
+                found.push((name, Kind::Label));
+            }
+        }
+        match (rest.get(0), rest.get(1)) {
+            (Some(&".func"), Some(&name)) => found.push((name, Kind::Function)),
+            (Some(&".equ"), Some(&name)) => found.push((name, Kind::Constant)),
+            // Parameters may follow a macro's name after a comma.
+            (Some(&".macro"), Some(&name)) => found.push((name.trim_end_matches(','), Kind::Macro)),
+            _ => ()
+        }
+        // Names that aren't symbols, such as a macro's parameters, aren't
+        // definitions that can be looked up.
+        for (name, kind) in found {
+            if parser::is_symbol_name(name) {
+                tags.push(Tag { name: name, kind: kind, line_number: index + 1, offset: start, text: line });
+            }
+        }
+    }
+    tags
+}
+
+// Escapes a line for a ctags search pattern.
+fn escape(text: &str) -> String {
+    text.replace("\\", "\\\\").replace("/", "\\/")
+}
@@ -0,0 +1,173 @@
+use std::io::{self, BufRead, Write};
+
+use debugger::Debugger;
+
+// How many lines of the program's output are shown.
+const OUTPUT_LINES: usize = 4;
+
+// How many words a memory watch shows on each line.
+const WATCH_ROW: u32 = 4;
+
+const HELP: &'static str = "watch :LABEL|ADDR [N]  show N words of memory from there
+unwatch :LABEL|ADDR    stop showing them";
+
+// A full-screen view of a debugger, drawn with ANSI escape codes: the
+// disassembly around the current instruction, which is highlighted, beside
+// the stack, the breakpoints and any memory being watched, above what the
+// program has printed and what the last command said. Commands are typed on
+// the bottom line, and are as for the command-line debugger, with `watch`
+// and `unwatch` to choose the memory that's shown.
+pub struct Tui {
+    pub debugger: Debugger,
+    // The address and number of words of each memory watch.
+    pub watches: Vec<(u32, u32)>,
+    // What the program has printed, which would otherwise be drawn over.
+    pub output: Vec<u8>,
+    pub message: String
+}
+
+impl Tui {
+    pub fn new(debugger: Debugger) -> Tui {
+        Tui { debugger: debugger, watches: Vec::new(), output: Vec::new(), message: "type `help` for commands".to_string() }
+    }
+
+    // Runs a command, leaving what it says to be drawn, or gives `false` if
+    // it's `quit`.
+    pub fn command(&mut self, line: &str, input: &mut dyn BufRead) -> bool {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        self.message = match (words.first().cloned(), words.get(1).map(|address| self.debugger.address(address))) {
+            (Some("watch"), Some(Ok(address))) => match words.get(2).map_or(Ok(WATCH_ROW), |count| count.parse()) {
+                Ok(count) => {
+                    self.watches.retain(|&(watched, _)| watched != address);
+                    self.watches.push((address, count));
+                    format!("watching {} word(s) from {:04X}", count, address)
+                },
+                Err(_) => "watch takes a number of words".to_string()
+            },
+            (Some("unwatch"), Some(Ok(address))) => {
+                self.watches.retain(|&(watched, _)| watched != address);
+                format!("stopped watching {:04X}", address)
+            },
+            (Some("watch"), Some(Err(message))) | (Some("unwatch"), Some(Err(message))) => message,
+            (Some("watch"), None) | (Some("unwatch"), None) => "watch and unwatch take a label or an address".to_string(),
+            (Some("help"), _) | (Some("h"), _) => format!("{}\n{}", self.debugger.command("help", input, &mut self.output).unwrap(), HELP),
+            _ => match self.debugger.command(line, input, &mut self.output) {
+                Some(report) => report,
+                None => return false
+            }
+        };
+        true
+    }
+
+    // Draws the screen before each command, and reads the next, until there
+    // are no more or one is `quit`.
+    pub fn run(&mut self, input: &mut dyn BufRead, screen: &mut dyn Write, width: usize, height: usize) -> io::Result<()> {
+        loop {
+            try!(write!(screen, "{}", self.draw(width, height)));
+            try!(screen.flush());
+            let mut line = String::new();
+            if try!(input.read_line(&mut line)) == 0 || !self.command(&line, input) {
+                return writeln!(screen);
+            }
+        }
+    }
+
+    // The whole screen, `width` columns by `height` lines, ending with the
+    // prompt.
+    pub fn draw(&self, width: usize, height: usize) -> String {
+        let width = width.max(40);
+        let left = width * 3 / 5;
+        let right = width - left - 1;
+        let messages: Vec<&str> = self.message.lines().collect();
+        let body = height.saturating_sub(OUTPUT_LINES + messages.len() + 4).max(4);
+
+        let mut output = String::from("\x1b[2J\x1b[H");
+        let disassembly = self.disassembly(body);
+        let panes = self.panes(body);
+        output.push_str(&format!("\x1b[1m{}\x1b[0m\n", fit("disassembly", width)));
+        for row in 0..body {
+            let (line, current) = disassembly.get(row).cloned().unwrap_or((String::new(), false));
+            let line = fit(&line, left);
+            let line = if current { format!("\x1b[7m{}\x1b[0m", line) } else { line };
+            output.push_str(&format!("{} {}\n", line, fit(panes.get(row).map_or("", |pane| pane.as_str()), right)));
+        }
+        output.push_str(&format!("\x1b[1m{}\x1b[0m\n", fit("output", width)));
+        let printed = String::from_utf8_lossy(&self.output);
+        let printed: Vec<&str> = printed.lines().collect();
+        for row in 0..OUTPUT_LINES {
+            let line = printed.len().checked_sub(OUTPUT_LINES - row).and_then(|index| printed.get(index)).cloned().unwrap_or("");
+            output.push_str(&format!("{}\n", fit(line, width)));
+        }
+        output.push_str(&format!("{}\n", "-".repeat(width)));
+        for message in messages {
+            output.push_str(&format!("{}\n", fit(message, width)));
+        }
+        output.push_str("(quasm) ");
+        output
+    }
+
+    // Lines of the disassembly, with the current instruction a third of the
+    // way down, and whether each is the current instruction.
+    fn disassembly(&self, rows: usize) -> Vec<(String, bool)> {
+        let machine = &self.debugger.machine;
+        let labels = self.debugger.debug_info.iter().flat_map(|debug_info| &debug_info.labels);
+        let mut lines = Vec::new();
+        let start = machine.pc.saturating_sub(rows as u32 / 3).max(machine.origin);
+        for address in start..machine.end {
+            for &(ref name, _) in labels.clone().filter(|&&(_, label)| label == address) {
+                lines.push((format!("   :{}", name), false));
+            }
+            let breakpoint = if self.debugger.breakpoints.contains(&address) { "*" } else { " " };
+            let marker = if address == machine.pc { "=>" } else { "  " };
+            lines.push((format!("{}{}{}", marker, breakpoint, self.debugger.describe(address)), address == machine.pc));
+            if lines.len() >= rows {
+                break;
+            }
+        }
+        lines.truncate(rows);
+        lines
+    }
+
+    // The stack, top first, then the breakpoints and the memory watches.
+    fn panes(&self, rows: usize) -> Vec<String> {
+        let machine = &self.debugger.machine;
+        let mut lines = vec!["\x1b[1mstack\x1b[0m".to_string()];
+        let shown = machine.stack.len().min(rows / 3);
+        for (depth, value) in machine.stack.iter().rev().take(shown).enumerate() {
+            lines.push(format!("{:>3}: {}", depth, value));
+        }
+        if shown < machine.stack.len() {
+            lines.push(format!("     ... {} more", machine.stack.len() - shown));
+        }
+        lines.push(String::new());
+        lines.push("\x1b[1mbreakpoints\x1b[0m".to_string());
+        for &address in &self.debugger.breakpoints {
+            lines.push(format!("  {}", self.debugger.describe(address)));
+        }
+        for &(address, count) in &self.watches {
+            lines.push(String::new());
+            lines.push(format!("\x1b[1mmemory {:04X}\x1b[0m", address));
+            for row in (0..count).step_by(WATCH_ROW as usize) {
+                let values: Vec<_> = (row..count.min(row + WATCH_ROW))
+                    .map(|offset| machine.memory.get((address + offset) as usize).map_or("-".to_string(), |value| value.to_string()))
+                    .collect();
+                lines.push(format!("  {:04X}: {}", address + row, values.join(" ")));
+            }
+        }
+        lines
+    }
+}
+
+// Pads or cuts `text` to `width` characters, ignoring escape codes, which
+// are only ever at the start and end of a line.
+fn fit(text: &str, width: usize) -> String {
+    let visible: String = text.replace("\x1b[1m", "").replace("\x1b[0m", "");
+    let length = visible.chars().count();
+    if length > width {
+        visible.chars().take(width).collect()
+    } else if visible.len() != text.len() {
+        format!("{}{}", text, " ".repeat(width - length))
+    } else {
+        format!("{:<1$}", text, width)
+    }
+}
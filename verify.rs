@@ -0,0 +1,80 @@
+use std::fmt;
+
+use container::ContainerError;
+use debug::DebugInfo;
+use disasm;
+use encoder::Endian;
+use encoding::Profile;
+use isa::{self, InstructionSet, Operand};
+
+// A problem found in bytecode, at the address of the word it's in if it's in
+// one.
+pub struct Violation {
+    pub address: Option<u32>,
+    pub message: String
+}
+
+// Checks that bytecode can be run: the checksum in its container matches, its
+// entry point is one of its words, and every word is an instruction whose
+// argument is valid for its opcode, with any branch landing on one of the
+// words. Words in a `.data` section are only checked if there's no debug
+// info to say which they are. Addresses are placed as for `inspect`.
+pub fn verify(bytes: &[u8], debug_info: Option<&DebugInfo>, instruction_set: &InstructionSet, endian: Endian, profile: Profile) -> Result<Vec<Violation>, ContainerError> {
+    let bytecode = try!(disasm::read_bytecode(bytes, endian, profile));
+    let mut violations = Vec::new();
+    let mut violation = |address, message| violations.push(Violation { address: address, message: message });
+    if let Some(ref header) = bytecode.header {
+        let words = &bytes[header.size()..bytes.len() - bytecode.trailing];
+        if let Err(error) = header.verify_checksum(words) {
+            violation(None, error.to_string());
+        }
+    }
+    if bytecode.trailing > 0 {
+        violation(None, format!("{} bytes after the last whole word", bytecode.trailing));
+    }
+    let origin = debug_info.map_or(0, |debug_info| debug_info.origin());
+    let addresses = origin as i64..origin as i64 + bytecode.words.len() as i64;
+    if let Some(entry) = bytecode.header.as_ref().and_then(|header| header.entry) {
+        if !addresses.contains(&(entry as i64)) {
+            violation(None, format!("entry point {:04X} is outside the program", entry));
+        }
+    }
+    let data: Vec<_> = debug_info
+        .map_or(&[][..], |debug_info| &debug_info.sections[..]).iter()
+        .filter(|&&(ref name, _, _)| name == ".data")
+        .map(|&(_, address, size)| address as i64..address as i64 + size as i64)
+        .collect();
+    let encoding = bytecode.profile.encoding();
+    for (address, &word) in addresses.clone().zip(&bytecode.words) {
+        if data.iter().any(|data| data.contains(&address)) {
+            continue;
+        }
+        let (code, argument) = encoding.decode(word);
+        let opcode = match instruction_set.opcode_with_code(code) {
+            Some(opcode) => opcode,
+            None => {
+                violation(Some(address as u32), format!("{} is not a known opcode", code));
+                continue;
+            }
+        };
+        match opcode.operand {
+            Operand::None if argument != 0 =>
+                violation(Some(address as u32), format!("`{}` takes no argument, but has {}", opcode.name, argument)),
+            Operand::Depth if argument < 1 || argument > isa::MAX_DEPTH =>
+                violation(Some(address as u32), format!("`{}` needs a depth from 1 to {}, but has {}", opcode.name, isa::MAX_DEPTH, argument)),
+            Operand::Label if !addresses.contains(&(address + 1 + argument)) =>
+                violation(Some(address as u32), format!("`{}` branches to {:04X}, outside the program", opcode.name, address + 1 + argument)),
+            _ => ()
+        }
+    }
+    Ok(violations)
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self.address {
+            Some(address) => write!(formatter, "{:04X}: {}", address, self.message),
+            None => write!(formatter, "{}", self.message)
+        }
+    }
+}
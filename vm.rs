@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::time::Instant;
+
+use disasm::{self, Decoded};
+use encoding::{self, Profile};
+use isa::{self, InstructionSet, Operand};
+
+// How many words of memory there are by default.
+pub const MEMORY_WORDS: u32 = 0x10000;
+
+// How often the time is checked against a deadline, in steps.
+const DEADLINE_INTERVAL: u64 = 1024;
+
+// Why a program stopped without trapping: `halt`, with the status it popped,
+// a `ret` with nothing to return to, or running on past its last word, both
+// of which leave the stack as it is. A program can also be stopped before it
+// finishes, once it's run as many steps as it's allowed to, or for as long.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stop {
+    Halted(i64),
+    Returned,
+    Ended,
+    OutOfSteps,
+    TimedOut
+}
+
+// Why an instruction couldn't be run.
+#[derive(Debug)]
+pub enum TrapKind {
+    // The word at the program counter isn't an instruction. It's shown with
+    // as many digits as a word of `word_size` bytes has.
+    InvalidInstruction { word: i64, word_size: usize },
+    // The instruction set has an opcode the machine doesn't know how to run.
+    UnsupportedOpcode { name: String },
+    // The program counter isn't in the program.
+    OutOfProgram,
+    StackUnderflow { needs: u32, depth: usize },
+    DivisionByZero,
+    // A `load` or `store` of an address outside memory, which has `size`
+    // words.
+    BadAddress { address: i64, size: usize },
+    // A `syscall` of a number no host function is registered with.
+    UnknownSyscall { index: i64 },
+    // A host function failed, saying why.
+    HostFunction { index: i64, message: String },
+    EndOfInput,
+    // `read` found something other than an integer.
+    BadInput { text: String },
+    Io(io::Error)
+}
+
+#[derive(Debug)]
+pub struct Trap {
+    pub address: u32,
+    pub kind: TrapKind
+}
+
+// A function of the host that a program calls with `syscall`. It's given
+// the stack to take its arguments from and leave its results on.
+pub type HostFunction = Box<dyn FnMut(&mut Vec<i64>) -> Result<(), String>>;
+
+// A machine that runs bytecode over an operand stack, with a separate stack
+// of return addresses. Values are words, which wrap around as they would in
+// the program's profile. Memory is word-addressed, with the program image
+// at its origin and zeros everywhere else.
+pub struct Machine {
+    pub memory: Vec<i64>,
+    pub stack: Vec<i64>,
+    pub returns: Vec<u32>,
+    pub pc: u32,
+    // The addresses of the program image, which is all that can be run.
+    pub origin: u32,
+    pub end: u32,
+    pub instruction_set: InstructionSet,
+    pub profile: Profile,
+    // Where each step is traced, with how many of the values at the top of
+    // the stack are shown.
+    pub trace: Option<Box<dyn Write>>,
+    pub trace_depth: usize,
+    // How many instructions have been run, and how many may be, or until
+    // when, by `run`.
+    pub steps: u64,
+    pub max_steps: Option<u64>,
+    pub deadline: Option<Instant>,
+    host_functions: HashMap<i64, HostFunction>
+}
+
+impl Machine {
+    // Loads `words` at `origin` in `memory_size` words of memory, to start at
+    // `entry`, or otherwise the first word. The words are the whole program
+    // image, so any `.data` is loaded with the code, and the rest of memory
+    // is zeros. Memory that can't hold the image is an error.
+    pub fn new(words: &[i64], origin: u32, entry: Option<u32>, instruction_set: InstructionSet, profile: Profile, memory_size: u32) -> Result<Machine, String> {
+        let end = origin as u64 + words.len() as u64;
+        if end > memory_size as u64 {
+            return Err(format!("the program needs {} words of memory, but there are only {}", end, memory_size));
+        }
+        let end = end as u32;
+        let mut memory = vec![0; memory_size as usize];
+        memory[origin as usize..end as usize].copy_from_slice(words);
+        Ok(Machine {
+            memory: memory,
+            stack: Vec::new(),
+            returns: Vec::new(),
+            pc: entry.unwrap_or(origin),
+            origin: origin,
+            end: end,
+            instruction_set: instruction_set,
+            profile: profile,
+            trace: None,
+            trace_depth: 4,
+            steps: 0,
+            max_steps: None,
+            deadline: None,
+            host_functions: HashMap::new()
+        })
+    }
+
+    // Makes `syscall index` call `function`, instead of any function it
+    // called before. If the function fails, the program traps, with the
+    // stack as the function left it.
+    pub fn register_host_fn<F>(&mut self, index: i64, function: F) where F: FnMut(&mut Vec<i64>) -> Result<(), String> + 'static {
+        self.host_functions.insert(index, Box::new(function));
+    }
+
+    // Runs instructions until the program stops or traps. `print` and
+    // `printc` write to `output`, and `read` reads a line from `input`.
+    // The deadline is only checked every so often, and not while waiting for
+    // input.
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<Stop, Trap> {
+        loop {
+            if self.max_steps.map_or(false, |max_steps| self.steps >= max_steps) {
+                return Ok(Stop::OutOfSteps);
+            }
+            if self.steps % DEADLINE_INTERVAL == 0 && self.deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return Ok(Stop::TimedOut);
+            }
+            if let Some(stop) = try!(self.step(input, output)) {
+                return Ok(stop);
+            }
+        }
+    }
+
+    // Runs the instruction at the program counter. If it traps, the machine
+    // is left as it was before it, unless a host function changed it.
+    pub fn step(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<Option<Stop>, Trap> {
+        let address = self.pc;
+        if address == self.end {
+            return Ok(Some(Stop::Ended));
+        }
+        if self.trace.is_some() {
+            let line = self.trace_line();
+            try!(self.trace.as_mut().unwrap().write_all(line.as_bytes())
+                .map_err(|error| Trap { address: address, kind: TrapKind::Io(error) }));
+        }
+        let stop = try!(self.execute(input, output).map_err(|kind| Trap { address: address, kind: kind }));
+        self.steps += 1;
+        Ok(stop)
+    }
+
+    // The instruction about to be run, with its address and the values at
+    // the top of the stack before it, top last, as in
+    // `0007  jle 000C          [... 5, 0]`.
+    pub fn trace_line(&self) -> String {
+        let instruction = self.instruction_text(self.pc);
+        let shown = self.stack.len().min(self.trace_depth);
+        let mut values: Vec<_> = self.stack[self.stack.len() - shown..].iter().map(|value| value.to_string()).collect();
+        if shown < self.stack.len() {
+            values.insert(0, "...".to_string());
+        }
+        format!("{:04X}  {:<16} [{}]\n", self.pc, instruction, values.join(", "))
+    }
+
+    // The instruction at `address` as it would be disassembled, but with any
+    // branch target given as an address. Words outside memory are blank.
+    pub fn instruction_text(&self, address: u32) -> String {
+        match self.memory.get(address as usize) {
+            Some(&word) => {
+                let addresses = address as i64..address as i64 + 1;
+                match disasm::decode_words(&[word], &addresses, &self.instruction_set, self.profile.encoding()).pop() {
+                    Some(Decoded::Branch(opcode, target)) => format!("{} {:04X}", opcode, target),
+                    Some(decoded) => disasm::instruction_text(&decoded, word),
+                    None => String::new()
+                }
+            },
+            None => String::new()
+        }
+    }
+
+    fn execute(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<Option<Stop>, TrapKind> {
+        if self.pc < self.origin || self.pc >= self.end {
+            return Err(TrapKind::OutOfProgram);
+        }
+        let encoding = self.profile.encoding();
+        let word = self.memory[self.pc as usize];
+        let (code, argument) = encoding.decode(word);
+        let invalid = || TrapKind::InvalidInstruction { word: word, word_size: encoding.word_size() };
+        // Bytecode can have arguments the assembler wouldn't allow, such as a
+        // negative depth, which aren't instructions.
+        let (name, pops) = match self.instruction_set.opcode_with_code(code) {
+            Some(opcode) if opcode.operand == Operand::Depth && (argument < 1 || argument > isa::MAX_DEPTH) =>
+                return Err(invalid()),
+            Some(opcode) if opcode.pops.argument && argument < 0 => return Err(invalid()),
+            Some(opcode) => (opcode.name.clone(), opcode.pops.fixed as i64 + if opcode.pops.argument { argument } else { 0 }),
+            None => return Err(invalid())
+        };
+        if (self.stack.len() as i64) < pops {
+            return Err(TrapKind::StackUnderflow { needs: pops as u32, depth: self.stack.len() });
+        }
+        let next = self.pc + 1;
+        let target = (next as i64 + argument) as u32;
+        let bits = encoding.word_bits();
+        let wrap = |value: i64| encoding::sign_extend(value, bits);
+        let mut jump = None;
+        match name.as_str() {
+            // An instruction set given with `--isa-spec` may not count what
+            // these take from the stack by their argument.
+            "swap" | "drop" | "pick" if argument < 0 => return Err(invalid()),
+            "swap" | "pick" if argument as u64 >= self.stack.len() as u64 =>
+                return Err(TrapKind::StackUnderflow { needs: (argument + 1) as u32, depth: self.stack.len() }),
+            "drop" if argument as u64 > self.stack.len() as u64 =>
+                return Err(TrapKind::StackUnderflow { needs: argument as u32, depth: self.stack.len() }),
+            "const" => self.stack.push(wrap(argument)),
+            "pop" => { self.pop(); },
+            "dup" => {
+                let top = self.peek(0);
+                self.stack.push(top);
+            },
+            "swap" => {
+                let top = self.stack.len() - 1;
+                self.stack.swap(top, top - argument as usize);
+            },
+            "over" => {
+                let second = self.peek(1);
+                self.stack.push(second);
+            },
+            "rot" => {
+                let third = self.stack.len() - 3;
+                let value = self.stack.remove(third);
+                self.stack.push(value);
+            },
+            "drop" => {
+                let depth = self.stack.len() - argument as usize;
+                self.stack.truncate(depth);
+            },
+            "pick" => {
+                let value = self.peek(argument as usize);
+                self.stack.push(value);
+            },
+            "div" | "mod" if self.peek(0) == 0 => return Err(TrapKind::DivisionByZero),
+            "cmp" | "add" | "mul" | "sub" | "div" | "mod" | "and" | "or" | "xor" | "shl" | "shr" => {
+                let top = self.pop();
+                let second = self.pop();
+                let value = match name.as_str() {
+                    "cmp" => second.cmp(&top) as i64,
+                    "add" => second.wrapping_add(top),
+                    "mul" => second.wrapping_mul(top),
+                    "sub" => second.wrapping_sub(top),
+                    "div" => second.wrapping_div(top),
+                    "mod" => second.wrapping_rem(top),
+                    "and" => second & top,
+                    "or" => second | top,
+                    "xor" => second ^ top,
+                    "shl" => shift_left(second, top, bits),
+                    _ => shift_right(second, top, bits)
+                };
+                self.stack.push(wrap(value));
+            },
+            "neg" | "not" => {
+                let top = self.pop();
+                self.stack.push(wrap(if name == "neg" { top.wrapping_neg() } else { !top }));
+            },
+            "shli" | "shri" => {
+                let top = self.pop();
+                self.stack.push(wrap(if name == "shli" { shift_left(top, argument, bits) } else { shift_right(top, argument, bits) }));
+            },
+            "jmp" => jump = Some(target),
+            "jle" | "jeq" | "jne" | "jlt" | "jge" | "jgt" => {
+                let value = self.pop();
+                let taken = match name.as_str() {
+                    "jle" => value <= 0,
+                    "jeq" => value == 0,
+                    "jne" => value != 0,
+                    "jlt" => value < 0,
+                    "jge" => value >= 0,
+                    _ => value > 0
+                };
+                if taken {
+                    jump = Some(target);
+                }
+            },
+            "call" => {
+                self.returns.push(next);
+                jump = Some(target);
+            },
+            "ret" => match self.returns.pop() {
+                Some(address) => jump = Some(address),
+                None => {
+                    self.pc = next;
+                    return Ok(Some(Stop::Returned));
+                }
+            },
+            "load" => {
+                let value = try!(self.read_memory(self.peek(0)));
+                self.pop();
+                self.stack.push(value);
+            },
+            "store" => {
+                let (address, value) = (self.peek(0), self.peek(1));
+                try!(self.write_memory(address, value));
+                self.stack.truncate(self.stack.len() - 2);
+            },
+            "loadi" => {
+                let value = try!(self.read_memory(argument));
+                self.stack.push(value);
+            },
+            "storei" => {
+                let value = self.peek(0);
+                try!(self.write_memory(argument, value));
+                self.pop();
+            },
+            "halt" => {
+                let status = self.pop();
+                self.pc = next;
+                return Ok(Some(Stop::Halted(status)));
+            },
+            "syscall" => {
+                let function = try!(self.host_functions.get_mut(&argument).ok_or(TrapKind::UnknownSyscall { index: argument }));
+                try!(function(&mut self.stack).map_err(|message| TrapKind::HostFunction { index: argument, message: message }));
+                for value in &mut self.stack {
+                    *value = wrap(*value);
+                }
+            },
+            "nop" => (),
+            "print" => {
+                let value = self.peek(0);
+                try!(write!(output, "{}", value).and_then(|_| output.flush()).map_err(TrapKind::Io));
+                self.pop();
+            },
+            "printc" => {
+                // Values that aren't characters are printed as U+FFFD.
+                let value = self.peek(0);
+                let character = ::std::char::from_u32(value as u32).filter(|_| value >= 0).unwrap_or('\u{FFFD}');
+                try!(write!(output, "{}", character).and_then(|_| output.flush()).map_err(TrapKind::Io));
+                self.pop();
+            },
+            "read" => {
+                let mut line = String::new();
+                if try!(input.read_line(&mut line).map_err(TrapKind::Io)) == 0 {
+                    return Err(TrapKind::EndOfInput);
+                }
+                let value = try!(line.trim().parse().map_err(|_| TrapKind::BadInput { text: line.trim().to_string() }));
+                self.stack.push(wrap(value));
+            },
+            _ => return Err(TrapKind::UnsupportedOpcode { name: name })
+        }
+        self.pc = jump.unwrap_or(next);
+        Ok(None)
+    }
+
+    // The value `depth` values down, with the top at zero. Instructions only
+    // look as deep as they've been checked to pop.
+    fn peek(&self, depth: usize) -> i64 {
+        self.stack[self.stack.len() - 1 - depth]
+    }
+
+    fn pop(&mut self) -> i64 {
+        self.stack.pop().unwrap()
+    }
+
+    fn read_memory(&self, address: i64) -> Result<i64, TrapKind> {
+        self.memory.get(address as usize)
+            .filter(|_| address >= 0)
+            .cloned()
+            .ok_or(TrapKind::BadAddress { address: address, size: self.memory.len() })
+    }
+
+    fn write_memory(&mut self, address: i64, value: i64) -> Result<(), TrapKind> {
+        if address < 0 || address as usize >= self.memory.len() {
+            return Err(TrapKind::BadAddress { address: address, size: self.memory.len() });
+        }
+        self.memory[address as usize] = value;
+        Ok(())
+    }
+}
+
+// Shifts by the word size or more, or by a negative amount, leave zero.
+fn shift_left(value: i64, shift: i64, bits: u32) -> i64 {
+    if shift >= 0 && shift < bits as i64 { value << shift } else { 0 }
+}
+
+// Zeros are shifted in above the word, rather than copies of its sign.
+fn shift_right(value: i64, shift: i64, bits: u32) -> i64 {
+    let mask = !0u64 >> (64 - bits);
+    if shift >= 0 && shift < bits as i64 { ((value as u64 & mask) >> shift) as i64 } else { 0 }
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &TrapKind::InvalidInstruction { word, word_size } => {
+                let digits = word_size * 2;
+                let mask = !0u64 >> (64 - digits * 4);
+                write!(formatter, "{:01$X} is not an instruction", word as u64 & mask, digits)
+            },
+            &TrapKind::UnsupportedOpcode { ref name } =>
+                write!(formatter, "`{}` can't be run by the virtual machine", name),
+            &TrapKind::OutOfProgram =>
+                write!(formatter, "ran outside the program"),
+            &TrapKind::StackUnderflow { needs, depth } =>
+                write!(formatter, "stack underflow: needs {} value(s), but there are {}", needs, depth),
+            &TrapKind::DivisionByZero =>
+                write!(formatter, "division by zero"),
+            &TrapKind::BadAddress { address, size } =>
+                write!(formatter, "address {} is outside memory, which has {} words", address, size),
+            &TrapKind::UnknownSyscall { index } =>
+                write!(formatter, "no host function is registered for `syscall {}`", index),
+            &TrapKind::HostFunction { index, ref message } =>
+                write!(formatter, "host function {} failed: {}", index, message),
+            &TrapKind::EndOfInput =>
+                write!(formatter, "expected an integer to read, found the end of the input"),
+            &TrapKind::BadInput { ref text } =>
+                write!(formatter, "expected an integer to read, found `{}`", text),
+            &TrapKind::Io(ref error) =>
+                write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "trap at {:04X}: {}", self.address, self.kind)
+    }
+}
+
+impl error::Error for Trap {}
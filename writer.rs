@@ -0,0 +1,475 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use container;
+use encoder;
+use encoding;
+use error::QuasmError;
+use isa::{InstructionSet, Operand};
+use Program;
+
+// The formats programs can be written in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    // The bytecode after a container header.
+    Container,
+    // Just the bytecode.
+    Raw,
+    IntelHex,
+    SRecord,
+    // One hexadecimal word per line, as read by Verilog's `$readmemh`.
+    ReadMemH,
+    // A C header defining the words as an array.
+    CHeader,
+    // Rust source defining the words as a constant array.
+    Rust,
+    // One hexadecimal word per line, with the argument and opcode separated
+    // by an underscore, as in `0005_0007`.
+    Hex,
+    // The container as a single line of base64.
+    Base64,
+    // Each word along with what it was assembled from, and the labels.
+    Json
+}
+
+impl Default for Format {
+    fn default() -> Format {
+        Format::Container
+    }
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "container" => Some(Format::Container),
+            "raw" => Some(Format::Raw),
+            "ihex" => Some(Format::IntelHex),
+            "srec" => Some(Format::SRecord),
+            "readmemh" => Some(Format::ReadMemH),
+            "c-header" => Some(Format::CHeader),
+            "rust" => Some(Format::Rust),
+            "hex" => Some(Format::Hex),
+            "base64" => Some(Format::Base64),
+            "json" => Some(Format::Json),
+            _ => None
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct OutputOptions {
+    pub format: Format,
+    // The address of the first byte, for formats that record addresses.
+    pub base_address: u32,
+    // The number of bits in S-record addresses: 16, 24 or 32. If not given,
+    // the narrowest that fits the program is used.
+    pub address_bits: Option<u32>,
+    // Whether `$readmemh` output skips the gaps left by `.org` using
+    // `@address` markers, rather than filling them with zeros.
+    pub address_markers: bool,
+    // The name of the array in source code output, `program` by default.
+    pub array_name: Option<String>,
+    // Whether Rust output also defines a constant for each label's address.
+    pub label_constants: bool,
+    // Whether containers leave out the checksum of their words.
+    pub omit_checksum: bool
+}
+
+pub fn write(program: &Program, options: &OutputOptions) -> Result<Vec<u8>, QuasmError> {
+    match options.format {
+        Format::Container => Ok(container::wrap(&program.bytes, program.entry, program.endian, program.profile, !options.omit_checksum)),
+        Format::Raw => Ok(program.bytes.clone()),
+        Format::IntelHex => Ok(intel_hex(&program.bytes, options.base_address).into_bytes()),
+        Format::SRecord => s_records(&program.bytes, options.base_address, options.address_bits).map(String::into_bytes),
+        Format::ReadMemH => Ok(read_mem_h(program, options.address_markers).into_bytes()),
+        Format::CHeader => array_name(options).map(|name| c_header(program, name).into_bytes()),
+        Format::Rust => array_name(options).map(|name| rust(program, name, options.label_constants).into_bytes()),
+        Format::Hex => Ok(hex(program).into_bytes()),
+        Format::Base64 => {
+            let container = container::wrap(&program.bytes, program.entry, program.endian, program.profile, !options.omit_checksum);
+            Ok(format!("{}\n", base64(&container)).into_bytes())
+        },
+        Format::Json => Ok(json(program).into_bytes())
+    }
+}
+
+fn array_name(options: &OutputOptions) -> Result<&str, QuasmError> {
+    let name = options.array_name.as_ref().map_or("program", |name| name.as_str());
+    let is_identifier = name.starts_with(|character: char| character.is_ascii_alphabetic() || character == '_') &&
+        name.chars().all(|character| character.is_ascii_alphanumeric() || character == '_');
+    if is_identifier {
+        Ok(name)
+    } else {
+        Err(QuasmError::Output(format!("`{}` is not a valid array name", name)))
+    }
+}
+
+// Addresses in `@address` markers are word addresses, in hexadecimal like the
+// words themselves.
+fn read_mem_h(program: &Program, address_markers: bool) -> String {
+    let word_size = program.profile.encoding().word_size();
+    let words = encoder::from_bytes(&program.bytes, word_size, program.endian);
+    let mut output = String::new();
+    if address_markers && program.origin != 0 {
+        output.push_str(&format!("@{:X}\n", program.origin));
+    }
+    let mut address = program.origin;
+    let mut index = 0;
+    while index < words.len() {
+        if address_markers {
+            if let Some(&(_, size)) = program.gaps.iter().find(|&&(gap, _)| gap == address) {
+                address += size;
+                index += size as usize;
+                output.push_str(&format!("@{:X}\n", address));
+                continue;
+            }
+        }
+        let word = words[index] as u64 & (!0u64 >> (64 - word_size * 8));
+        output.push_str(&format!("{:01$X}\n", word, word_size * 2));
+        address += 1;
+        index += 1;
+    }
+    output
+}
+
+// Intel HEX data records hold up to 16 bytes at a 16-bit address, with
+// extended linear address records giving the upper 16 bits when they change.
+fn intel_hex(bytes: &[u8], base_address: u32) -> String {
+    let mut output = String::new();
+    let mut upper = 0;
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let address = base_address.wrapping_add(offset as u32);
+        if address >> 16 != upper {
+            upper = address >> 16;
+            intel_hex_record(&mut output, 0, 4, &[(upper >> 8) as u8, upper as u8]);
+        }
+        // A record can't cross into the next 64K block.
+        let length = (bytes.len() - offset)
+            .min(16)
+            .min(0x10000 - (address & 0xFFFF) as usize);
+        intel_hex_record(&mut output, address as u16, 0, &bytes[offset..offset + length]);
+        offset += length;
+    }
+    intel_hex_record(&mut output, 0, 1, &[]);
+    output
+}
+
+fn intel_hex_record(output: &mut String, address: u16, record_type: u8, data: &[u8]) {
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, record_type];
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    record.push(0u8.wrapping_sub(sum));
+    output.push(':');
+    for byte in record {
+        output.push_str(&format!("{:02X}", byte));
+    }
+    output.push('\n');
+}
+
+// S-records are written as an S0 header, data records of up to 16 bytes, an
+// S5 record counting them, and a termination record. With 16-bit addresses,
+// the data records are S1 and the termination S9 (S19); with 24-bit, S2 and
+// S8 (S28); and with 32-bit, S3 and S7 (S37).
+fn s_records(bytes: &[u8], base_address: u32, address_bits: Option<u32>) -> Result<String, QuasmError> {
+    let last_address = base_address as u64 + (bytes.len() as u64).saturating_sub(1);
+    let address_bits = address_bits.unwrap_or(match last_address {
+        0..=0xFFFF => 16,
+        0x10000..=0xFFFFFF => 24,
+        _ => 32
+    });
+    let (data_type, termination_type) = match address_bits {
+        16 => (1, 9),
+        24 => (2, 8),
+        32 => (3, 7),
+        _ => return Err(QuasmError::Output(format!("S-record addresses can't be {} bits, expected 16, 24 or 32", address_bits)))
+    };
+    if last_address >> address_bits != 0 {
+        return Err(QuasmError::Output(format!("address {:#X} doesn't fit in {}-bit S-record addresses", last_address, address_bits)));
+    }
+    let address_size = address_bits as usize / 8;
+    let mut output = String::new();
+    s_record(&mut output, 0, 0, 2, b"quasm");
+    let mut count = 0;
+    for (index, chunk) in bytes.chunks(16).enumerate() {
+        s_record(&mut output, data_type, base_address + index as u32 * 16, address_size, chunk);
+        count += 1;
+    }
+    if count <= 0xFFFF {
+        s_record(&mut output, 5, count, 2, &[]);
+    }
+    s_record(&mut output, termination_type, 0, address_size, &[]);
+    Ok(output)
+}
+
+fn s_record(output: &mut String, record_type: u8, address: u32, address_size: usize, data: &[u8]) {
+    let mut record = vec![(address_size + data.len() + 1) as u8];
+    record.extend_from_slice(&address.to_be_bytes()[4 - address_size..]);
+    record.extend_from_slice(data);
+    let sum = record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    record.push(!sum);
+    output.push_str(&format!("S{}", record_type));
+    for byte in record {
+        output.push_str(&format!("{:02X}", byte));
+    }
+    output.push('\n');
+}
+
+fn c_header(program: &Program, name: &str) -> String {
+    let word_size = program.profile.encoding().word_size();
+    let words = encoder::from_bytes(&program.bytes, word_size, program.endian);
+    let guard = format!("{}_H", name.to_uppercase());
+    let mut output = String::new();
+    output.push_str(&format!("#ifndef {}\n#define {}\n\n#include <stdint.h>\n\n", guard, guard));
+    output.push_str(&format!("#define {}_LENGTH {}\n\n", name.to_uppercase(), words.len()));
+    output.push_str(&format!("static const uint{}_t {}[{}_LENGTH] = {{\n", word_size * 8, name, name.to_uppercase()));
+    output.push_str(&hex_rows(&words, word_size));
+    output.push_str("};\n\n#endif\n");
+    output
+}
+
+// Labels are defined in a `labels` module, so they can't clash with the
+// array, with their names converted to constant case.
+fn rust(program: &Program, name: &str, label_constants: bool) -> String {
+    let word_size = program.profile.encoding().word_size();
+    let words = encoder::from_bytes(&program.bytes, word_size, program.endian);
+    let mut output = String::new();
+    output.push_str(&format!("pub const {}: [u{}; {}] = [\n", name.to_uppercase(), word_size * 8, words.len()));
+    output.push_str(&hex_rows(&words, word_size));
+    output.push_str("];\n");
+    if label_constants {
+        output.push_str("\npub mod labels {\n");
+        for label in &program.labels {
+            let constant: String = label.name.chars()
+                .map(|character| if character.is_ascii_alphanumeric() { character.to_ascii_uppercase() } else { '_' })
+                .collect();
+            let constant = constant.trim_matches('_');
+            let separator = if constant.starts_with(|character: char| character.is_ascii_digit()) { "_" } else { "" };
+            output.push_str(&format!("    pub const {}{}: usize = {};\n", separator, constant, label.address));
+        }
+        output.push_str("}\n");
+    }
+    output
+}
+
+fn hex(program: &Program) -> String {
+    let encoding = program.profile.encoding();
+    let words = encoder::from_bytes(&program.bytes, encoding.word_size(), program.endian);
+    let code_digits = encoding.argument_shift() as usize / 4;
+    let argument_digits = encoding.word_size() * 2 - code_digits;
+    let mut output = String::new();
+    for word in words {
+        let word = word as u64;
+        let code = word & ((1 << encoding.argument_shift()) - 1);
+        let argument = (word >> encoding.argument_shift()) & (!0u64 >> (64 - argument_digits * 4));
+        output.push_str(&format!("{:02$X}_{:03$X}\n", argument, code, argument_digits, code_digits));
+    }
+    output
+}
+
+// Reads words written in the `hex` format, returning them along with their
+// size in bytes. Blank lines are ignored.
+pub fn read_hex(text: &str) -> Result<(Vec<i64>, usize), String> {
+    let mut words = Vec::new();
+    let mut word_size = None;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let digits: String = line.chars().filter(|&character| character != '_').collect();
+        let size = digits.len() / 2;
+        let word = match u64::from_str_radix(&digits, 16) {
+            Ok(word) if (size == 4 || size == 8) && digits.len() % 2 == 0 => word,
+            _ => return Err(format!("{}: `{}` is not a 32-bit or 64-bit hexadecimal word", index + 1, line))
+        };
+        if *word_size.get_or_insert(size) != size {
+            return Err(format!("{}: `{}` is a different size to the words before it", index + 1, line));
+        }
+        words.push(encoding::sign_extend(word as i64, size as u32 * 8));
+    }
+    Ok((words, word_size.unwrap_or(4)))
+}
+
+const BASE64_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (index, &byte)| group | (byte as u32) << (16 - index * 8));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                output.push(BASE64_ALPHABET[(group >> (18 - index * 6)) as usize & 0x3F] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+// Decodes padded base64, ignoring surrounding whitespace.
+pub fn read_base64(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim().trim_end_matches('=');
+    let mut bytes = Vec::new();
+    let mut group = 0u32;
+    let mut bits = 0;
+    for character in text.bytes() {
+        let value = try!(BASE64_ALPHABET.iter()
+            .position(|&letter| letter == character)
+            .ok_or(format!("`{}` isn't a base64 character", character as char)));
+        group = (group << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+// Data words have a null opcode and argument.
+fn json(program: &Program) -> String {
+    let words: Vec<_> = program.words.iter()
+        .map(|word| {
+            let (opcode, arg) = match word.instruction {
+                Some((ref opcode, arg)) => (json_string(opcode), arg.to_string()),
+                None => ("null".to_string(), "null".to_string())
+            };
+            format!(
+                "    {{\"address\": {}, \"opcode\": {}, \"arg\": {}, \"encoded\": {}, \"file\": {}, \"source_line\": {}, \"source\": {}}}",
+                word.address, opcode, arg, word.encoded,
+                word.location.file.as_ref().map_or("null".to_string(), |file| json_string(file)),
+                word.location.line_number, json_string(&word.source))
+        })
+        .collect();
+    let symbols: Vec<_> = program.labels.iter()
+        .map(|label| format!("    {}: {}", json_string(&label.name), label.address))
+        .collect();
+    format!(
+        "{{\n  \"words\": [\n{}\n  ],\n  \"symbols\": {{\n{}\n  }},\n  \"entry\": {}\n}}\n",
+        words.join(",\n"),
+        symbols.join(",\n"),
+        program.entry.map_or("null".to_string(), |entry| entry.to_string()))
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut output = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\t' => output.push_str("\\t"),
+            '\r' => output.push_str("\\r"),
+            character if (character as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", character as u32)),
+            character => output.push(character)
+        }
+    }
+    output.push('"');
+    output
+}
+
+// The words as comma-separated hexadecimal literals, indented, with a few to
+// a line.
+fn hex_rows(words: &[i64], word_size: usize) -> String {
+    let mask = !0u64 >> (64 - word_size * 8);
+    let mut output = String::new();
+    for row in words.chunks(32 / word_size) {
+        let row: Vec<_> = row.iter()
+            .map(|&word| format!("0x{:01$X}", word as u64 & mask, word_size * 2))
+            .collect();
+        output.push_str(&format!("    {},\n", row.join(", ")));
+    }
+    output
+}
+
+// Each line is listed with its address and first word in hexadecimal, and
+// any further words it assembled to on lines of their own. A heading names
+// the file whenever it changes.
+pub fn listing(program: &Program) -> String {
+    let word_digits = program.profile.encoding().word_size() * 2;
+    let mask = !0u64 >> (64 - word_digits * 4);
+    let mut output = String::new();
+    let mut file = None;
+    for line in &program.lines {
+        if file != Some(&line.location.file) {
+            file = Some(&line.location.file);
+            output.push_str(&format!("; {}\n", line.location.file.as_ref().map_or("<input>", |file| file.as_str())));
+        }
+        let mut words = program.words[line.words.clone()].iter();
+        match words.next() {
+            Some(word) => output.push_str(&format!("{:04X}  {:03$X}  {}\n", word.address, word.encoded as u64 & mask, line.source, word_digits)),
+            None => output.push_str(&format!("{:04X}  {:3$}  {}\n", line.address, "", line.source, word_digits))
+        }
+        for word in words {
+            output.push_str(&format!("{:04X}  {:02$X}\n", word.address, word.encoded as u64 & mask, word_digits));
+        }
+    }
+    output
+}
+
+// One label per line, as `ADDRESS SECTION NAME` with the address in
+// hexadecimal, in address order.
+pub fn symbols(program: &Program) -> String {
+    let mut output = String::new();
+    for label in &program.labels {
+        output.push_str(&format!("{:08X} {} {}\n", label.address, label.section.name(), label.name));
+    }
+    output
+}
+
+// Each section with its address and size in words, followed by the functions
+// and labels in it in address order. A function is listed before the labels
+// it contains.
+pub fn map(program: &Program) -> String {
+    let mut output = String::new();
+    for section in &program.sections {
+        output.push_str(&format!("{:<24} 0x{:08X} {:>8}\n", section.name, section.address, section.size));
+        let mut functions = program.functions.iter()
+            .filter(|function| function.section == section.section)
+            .peekable();
+        for label in program.labels.iter().filter(|label| label.section == section.section) {
+            while let Some(function) = functions.next_if(|function| function.address <= label.address) {
+                output.push_str(&format!("  .func {:<16} 0x{:08X} {:>8}\n", function.name, function.address, function.size));
+            }
+            output.push_str(&format!("{:24} 0x{:08X}          {}\n", "", label.address, label.name));
+        }
+        for function in functions {
+            output.push_str(&format!("  .func {:<16} 0x{:08X} {:>8}\n", function.name, function.address, function.size));
+        }
+    }
+    output
+}
+
+// Counts of what the program is made of: the instructions, each opcode used
+// from most to least often, the labels, the furthest any branch goes in
+// words, and the size of the bytecode.
+pub fn stats(program: &Program, instruction_set: &InstructionSet) -> String {
+    let instructions: Vec<_> = program.words.iter()
+        .filter_map(|word| word.instruction.as_ref().map(|instruction| (word.address, instruction)))
+        .collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for &(_, &(ref opcode, _)) in &instructions {
+        *counts.entry(opcode.as_str()).or_insert(0) += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|&(name, count), &(other_name, other_count)| other_count.cmp(&count).then(name.cmp(other_name)));
+    let longest_branch = instructions.iter()
+        .filter(|&&(_, &(ref opcode, _))| instruction_set.opcode(opcode).map(|opcode| opcode.operand) == Some(Operand::Label))
+        .map(|&(address, &(ref opcode, argument))| ((argument + 1).abs(), address, opcode))
+        .max_by_key(|&(distance, address, _)| (distance, Reverse(address)));
+    let mut output = String::new();
+    output.push_str(&format!("instructions    {}\n", instructions.len()));
+    output.push_str(&format!("labels          {}\n", program.labels.len()));
+    match longest_branch {
+        Some((distance, address, opcode)) =>
+            output.push_str(&format!("longest branch  {} words, `{}` at {:04X}\n", distance, opcode, address)),
+        None => output.push_str("longest branch  none\n")
+    }
+    output.push_str(&format!("size            {} words, {} bytes\n", program.words.len(), program.bytes.len()));
+    for (opcode, count) in counts {
+        output.push_str(&format!("  {:<14}{}\n", opcode, count));
+    }
+    output
+}